@@ -1,4 +1,9 @@
-use std::process::Command;
+use std::{
+    env, fs,
+    path::Path,
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 #[allow(unused)]
 macro_rules! warn {
@@ -16,20 +21,103 @@ macro_rules! set_env {
 }
 
 fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    // Keep the dirty flag and build time fresh when sources change.
+    println!("cargo:rerun-if-changed=src");
     rustc_info();
+    git_info();
+    build_info();
 }
 
-fn rustc_info() {
-    let rustc_output = Command::new("rustc")
-        .args(["-vV"])
+/// Run a command, returns its trimmed stdout or `None` when it fails.
+fn run(program: &str, args: &[&str]) -> Option<String> {
+    Command::new(program)
+        .args(args)
         .output()
-        .expect("detect rustc info failed")
-        .stdout;
-    let info_str = String::from_utf8_lossy(&rustc_output);
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+fn rustc_info() {
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let Some(info_str) = run(&rustc, &["-vV"]) else {
+        warn!("detect rustc info failed");
+        set_env!("RUA_COMPILER=unknown");
+        return;
+    };
     let info_arr = info_str
         .split('\n')
         .filter(|info| !info.is_empty())
         .collect::<Vec<_>>();
+    let Some((compiler, details)) = info_arr.split_first() else {
+        warn!("rustc printed no version info");
+        set_env!("RUA_COMPILER=unknown");
+        return;
+    };
+
+    set_env!("RUA_COMPILER={}", compiler);
+    for info in details {
+        match info.split_once(": ") {
+            Some(("host", host)) => set_env!("RUA_RUSTC_HOST={}", host),
+            Some(("LLVM version", llvm)) => set_env!("RUA_LLVM_VERSION={}", llvm),
+            _ => {}
+        }
+    }
+}
+
+fn git_info() {
+    // Not a git checkout, e.g. built from a source tarball.
+    let Some(git_dir) = run("git", &["rev-parse", "--absolute-git-dir"]) else {
+        return;
+    };
+    // Rebuild when HEAD switches branch or the current branch moves. The
+    // branch ref may only live in `packed-refs`, watch that file instead.
+    println!("cargo:rerun-if-changed={git_dir}/HEAD");
+    let head = fs::read_to_string(format!("{git_dir}/HEAD")).unwrap_or_default();
+    if let Some(reference) = head.strip_prefix("ref:").map(str::trim) {
+        let path = format!("{git_dir}/{reference}");
+        if Path::new(&path).exists() {
+            println!("cargo:rerun-if-changed={path}");
+        } else {
+            println!("cargo:rerun-if-changed={git_dir}/packed-refs");
+        }
+    }
+
+    if let Some(commit) = run("git", &["rev-parse", "HEAD"]) {
+        set_env!("RUA_GIT_COMMIT={}", commit);
+    }
+    // Empty on a detached HEAD.
+    if let Some(branch) = run("git", &["branch", "--show-current"]).filter(|b| !b.is_empty()) {
+        set_env!("RUA_GIT_BRANCH={}", branch);
+    }
+    if let Some(status) = run("git", &["status", "--porcelain", "--untracked-files=no"]) {
+        set_env!("RUA_GIT_DIRTY={}", !status.is_empty());
+    }
+}
+
+fn build_info() {
+    // Reproducible builds pin the timestamp with `SOURCE_DATE_EPOCH`.
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    let timestamp = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse::<u64>().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|time| time.as_secs())
+                .unwrap_or_default()
+        });
+    let mut features = env::vars()
+        .filter_map(|(key, _)| {
+            key.strip_prefix("CARGO_FEATURE_")
+                .map(|feature| feature.to_lowercase().replace('_', "-"))
+        })
+        .collect::<Vec<_>>();
+    features.sort();
 
-    set_env!("RUA_COMPILER={}", info_arr[0]);
+    set_env!("RUA_BUILD_TIMESTAMP={}", timestamp);
+    set_env!("RUA_FEATURES={}", features.join(","));
+    set_env!("RUA_TARGET={}", env::var("TARGET").unwrap_or_default());
+    set_env!("RUA_PROFILE={}", env::var("PROFILE").unwrap_or_default());
 }
@@ -0,0 +1,214 @@
+use std::{
+    io::{self, BufRead, IsTerminal, Write},
+    path::PathBuf,
+};
+
+use anyhow::{bail, Context};
+use chrono::Utc;
+use clap::{Args, Parser, Subcommand};
+
+use crate::{
+    config::Config,
+    routes::routes,
+    utils::{
+        duration::HumanDuration,
+        jwt::{decode_jwt, encode_jwt, Claims},
+        listener::BindAddr,
+        password,
+        tls::Tls,
+    },
+    AppState,
+};
+
+/// Phthonus web server.
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// Config file, `phthonus.toml` is used when it exists.
+    #[arg(short, long, env = "PHTHONUS_CONFIG", global = true)]
+    pub config: Option<PathBuf>,
+    /// Print the effective config with secrets masked and exit.
+    #[arg(long, global = true)]
+    pub print_config: bool,
+    /// Runs `serve` when omitted.
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Run the server.
+    Serve(ServeArgs),
+    /// Hash a password read from stdin.
+    HashPassword {
+        /// Check the password against this hash instead.
+        #[arg(long, value_name = "HASH")]
+        verify: Option<String>,
+    },
+    /// Check a token signed with `jwt.secret` and print its claims.
+    VerifyToken { token: String },
+    /// Sign a token with `jwt.secret`.
+    MintToken {
+        /// Subject of the token, e.g. a username.
+        #[arg(long)]
+        sub: String,
+        /// Lifetime of the token, e.g. `1h` or `7d`.
+        #[arg(long, default_value = "7d")]
+        ttl: HumanDuration,
+    },
+    /// Validate the config and build the routes without serving.
+    CheckConfig,
+}
+
+#[derive(Debug, Default, Args)]
+pub struct ServeArgs {
+    /// Address to listen on, `host:port` or `unix:<path>`, may be repeated.
+    /// Overrides `server.bind`.
+    #[arg(long, value_name = "ADDR")]
+    pub bind: Vec<BindAddr>,
+    /// Log filter directives, overrides `log.filter` until the next reload.
+    #[arg(long, value_name = "FILTER")]
+    pub log_level: Option<String>,
+}
+
+impl ServeArgs {
+    /// Override keys of `config` with the arguments given.
+    pub fn apply(&self, config: &mut Config) -> anyhow::Result<()> {
+        if !self.bind.is_empty() {
+            config.server.bind = self.bind.clone();
+        }
+        if let Some(filter) = &self.log_level {
+            config.log.filter = filter.clone();
+        }
+        config.validate()
+    }
+}
+
+impl Command {
+    /// Run a command other than `serve`, the config must be initialized.
+    pub async fn run(self, config: &Config) -> anyhow::Result<()> {
+        match self {
+            Self::Serve(_) => unreachable!("serve is run by main"),
+            Self::HashPassword { verify } => {
+                let input = read_password()?;
+                match verify {
+                    Some(hash) => {
+                        if !password::verify(input, hash).await? {
+                            bail!("password does not match");
+                        }
+                        println!("password matches");
+                    }
+                    None => println!("{}", password::hash(input).await?),
+                }
+            }
+            Self::VerifyToken { token } => {
+                require_jwt_secret(config)?;
+                let token = decode_jwt(&token).context("invalid token")?;
+                println!("{}", serde_json::to_string_pretty(&token.claims)?);
+            }
+            Self::MintToken { sub, ttl } => {
+                require_jwt_secret(config)?;
+                let iat = Utc::now().timestamp() as usize;
+                let claims = Claims {
+                    exp: iat + ttl.0.as_secs() as usize,
+                    iat,
+                    sub,
+                };
+                println!("{}", encode_jwt(&claims)?);
+            }
+            Self::CheckConfig => {
+                let _router = routes(config, &AppState::default())?;
+                Tls::load(&config.tls)?;
+                println!("config is valid");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Tokens signed with the random fallback secret die with the process.
+fn require_jwt_secret(config: &Config) -> anyhow::Result<()> {
+    if config.jwt.secret.is_none() {
+        bail!("jwt.secret is not set, set it or PHTHONUS_JWT_SECRET");
+    }
+    Ok(())
+}
+
+/// Read the password from the first line of stdin, so it doesn't end up in
+/// the shell history.
+fn read_password() -> anyhow::Result<String> {
+    let stdin = io::stdin();
+    if stdin.is_terminal() {
+        eprint!("Password: ");
+        io::stderr().flush()?;
+    }
+    let mut line = String::new();
+    stdin
+        .lock()
+        .read_line(&mut line)
+        .context("failed to read password")?;
+    let password = line.trim_end_matches(['\r', '\n']);
+    if password.is_empty() {
+        bail!("password is empty");
+    }
+    Ok(password.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::CommandFactory;
+
+    use super::*;
+
+    #[test]
+    fn parse_commands() {
+        Cli::command().debug_assert();
+
+        let cli = Cli::parse_from(["phthonus"]);
+        assert!(cli.command.is_none());
+
+        let cli = Cli::parse_from([
+            "phthonus",
+            "serve",
+            "--bind",
+            "127.0.0.1:8080",
+            "--bind",
+            "unix:/run/phthonus.sock",
+            "--log-level",
+            "debug",
+            "-c",
+            "phthonus.toml",
+        ]);
+        assert_eq!(cli.config, Some(PathBuf::from("phthonus.toml")));
+        let Some(Command::Serve(args)) = cli.command else {
+            panic!("expect serve");
+        };
+        let mut config = Config::default();
+        args.apply(&mut config).unwrap();
+        assert_eq!(
+            config.server.bind_addrs(),
+            [
+                BindAddr::Tcp("127.0.0.1:8080".parse().unwrap()),
+                BindAddr::Unix("/run/phthonus.sock".into()),
+            ]
+        );
+        assert_eq!(config.log.filter, "debug");
+
+        let cli = Cli::parse_from(["phthonus", "mint-token", "--sub", "xfy", "--ttl", "1h"]);
+        assert!(matches!(
+            cli.command,
+            Some(Command::MintToken { ttl, .. }) if ttl.0.as_secs() == 3600
+        ));
+        assert!(Cli::try_parse_from(["phthonus", "mint-token"]).is_err());
+    }
+
+    #[test]
+    fn invalid_log_level() {
+        let args = ServeArgs {
+            log_level: Some("info,[".to_string()),
+            ..Default::default()
+        };
+        let err = args.apply(&mut Config::default()).unwrap_err();
+        assert!(err.to_string().contains("log.filter"), "{err}");
+    }
+}
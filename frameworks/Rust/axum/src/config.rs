@@ -0,0 +1,1021 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    env, fmt, fs,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::OnceLock,
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize, Serializer};
+use tracing_subscriber::EnvFilter;
+
+use crate::{
+    consts::DEFAULT_PORT,
+    middlewares::{
+        access_log::AccessLogFormat,
+        body_capture, body_limit,
+        client_ip::TrustedProxies,
+        load_shed,
+        rate_limit::{KeyStrategy, Quota},
+        timeout::DEFAULT_TIMEOUT,
+    },
+    utils::{
+        duration::HumanDuration, listener::BindAddr, log_level::DEFAULT_LOG_FILTER,
+        redact::REDACTED, size::ByteSize,
+    },
+};
+
+/// Config file read when no path is given, skipped when it does not exist.
+pub const DEFAULT_CONFIG_FILE: &str = "phthonus.toml";
+
+/// Effective config of the process, set once by [`init`].
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+/// Settings of the server.
+///
+/// Layered from lowest to highest precedence: defaults, the TOML config
+/// file, `.env` and `PHTHONUS_*` environment variables. `.env` is read into a
+/// map below the environment, the environment of the process is never
+/// changed, see [`Config::apply_env`] for the variable of each key.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub tls: TlsConfig,
+    pub http: HttpConfig,
+    pub shutdown: ShutdownConfig,
+    pub log: LogConfig,
+    pub redact: RedactConfig,
+    pub admin: AdminConfig,
+    pub jwt: JwtConfig,
+    pub metrics: MetricsConfig,
+    pub openapi: OpenApiConfig,
+    pub api: ApiConfig,
+    pub otlp: OtlpConfig,
+    pub cors: CorsConfig,
+    pub security: SecurityConfig,
+    pub body_limit: BodyLimitConfig,
+    pub compression: CompressionConfig,
+    pub rate_limit: RateLimitConfig,
+    pub concurrency: ConcurrencyConfig,
+    pub timeout: TimeoutConfig,
+    /// Config file this was loaded from, used by [`Config::reload`].
+    #[serde(skip)]
+    file: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub host: IpAddr,
+    pub port: u16,
+    /// Addresses to listen on instead of `host` and `port`, e.g.
+    /// `["[::]:4000", "unix:/run/phthonus.sock"]`.
+    pub bind: Vec<BindAddr>,
+    /// Accept only IPv6 on IPv6 addresses, `[::]` also accepts IPv4 by
+    /// default.
+    pub ipv6_only: bool,
+    /// Permissions of Unix sockets in octal, e.g. `660`.
+    pub unix_mode: Option<String>,
+    /// Owner of Unix sockets.
+    pub unix_uid: Option<u32>,
+    /// Group of Unix sockets, e.g. the group of nginx.
+    pub unix_gid: Option<u32>,
+    /// Networks of the load balancers in front of us, forwarding headers are
+    /// only honoured from them.
+    pub trusted_proxies: TrustedProxies,
+    /// Expect a PROXY protocol header from the trusted proxies.
+    pub proxy_protocol: bool,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            host: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port: DEFAULT_PORT,
+            bind: vec![],
+            ipv6_only: false,
+            unix_mode: None,
+            unix_uid: None,
+            unix_gid: None,
+            trusted_proxies: TrustedProxies::default(),
+            proxy_protocol: false,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Addresses to listen on, `bind` or `host` and `port`.
+    pub fn bind_addrs(&self) -> Vec<BindAddr> {
+        if self.bind.is_empty() {
+            vec![BindAddr::Tcp(SocketAddr::new(self.host, self.port))]
+        } else {
+            self.bind.clone()
+        }
+    }
+
+    pub fn unix_mode(&self) -> anyhow::Result<Option<u32>> {
+        self.unix_mode
+            .as_deref()
+            .map(|mode| {
+                u32::from_str_radix(mode.trim_start_matches("0o"), 8)
+                    .ok()
+                    .filter(|mode| *mode <= 0o777)
+                    .ok_or_else(|| anyhow!("invalid server.unix_mode `{mode}`, expect e.g. `660`"))
+            })
+            .transpose()
+    }
+}
+
+/// HTTPS of the TCP addresses, Unix sockets stay plaintext.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM certificate chain, HTTPS is enabled when set.
+    pub cert: Option<PathBuf>,
+    /// PEM private key of the certificate.
+    pub key: Option<PathBuf>,
+    /// PEM certificates of the CAs issuing client certificates, mutual TLS
+    /// is enabled when set.
+    pub client_ca: Option<PathBuf>,
+    /// Also accept clients without a certificate under mutual TLS.
+    pub client_auth_optional: bool,
+    /// How often the files are checked for changes, never when unset.
+    pub reload_interval: Option<HumanDuration>,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            cert: None,
+            key: None,
+            client_ca: None,
+            client_auth_optional: false,
+            reload_interval: Some(HumanDuration(Duration::from_secs(10))),
+        }
+    }
+}
+
+impl TlsConfig {
+    /// Files of the config, in the order they are loaded.
+    pub fn files(&self) -> impl Iterator<Item = &Path> {
+        [&self.cert, &self.key, &self.client_ca]
+            .into_iter()
+            .flatten()
+            .map(PathBuf::as_path)
+    }
+}
+
+/// Settings of HTTP connections, limits apply per connection.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpConfig {
+    /// Accept HTTP/2 without TLS, from clients with prior knowledge or
+    /// switching with `Upgrade: h2c`. HTTPS negotiates HTTP/2 with ALPN
+    /// regardless.
+    pub h2c: bool,
+    /// Keep HTTP/1 connections open for further requests.
+    pub keep_alive: bool,
+    /// How long a client may take to send the headers of an HTTP/1 request,
+    /// unlimited when unset.
+    pub header_read_timeout: Option<HumanDuration>,
+    /// Most headers of an HTTP/1 request, larger requests get a 431.
+    pub max_headers: usize,
+    /// Flush the responses of pipelined HTTP/1 requests together.
+    pub pipeline_flush: bool,
+    /// Most concurrent streams of an HTTP/2 connection.
+    pub max_concurrent_streams: u32,
+    /// How often idle HTTP/2 connections are pinged, never when unset.
+    pub keep_alive_interval: Option<HumanDuration>,
+    /// How long to wait for the answer of a ping before closing.
+    pub keep_alive_timeout: HumanDuration,
+    /// Close connections after this many requests, unlimited when unset.
+    pub max_requests: Option<usize>,
+    /// Close connections after this long, so clients spread over new
+    /// instances, unlimited when unset.
+    pub max_connection_age: Option<HumanDuration>,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        // Same as hyper.
+        Self {
+            h2c: true,
+            keep_alive: true,
+            header_read_timeout: Some(HumanDuration(Duration::from_secs(30))),
+            max_headers: 100,
+            pipeline_flush: false,
+            max_concurrent_streams: 200,
+            keep_alive_interval: None,
+            keep_alive_timeout: HumanDuration(Duration::from_secs(20)),
+            max_requests: None,
+            max_connection_age: None,
+        }
+    }
+}
+
+/// Graceful shutdown on `SIGTERM` or Ctrl+C.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShutdownConfig {
+    /// How long to keep accepting after `/readyz` fails, so load balancers
+    /// stop sending new connections first.
+    pub delay: HumanDuration,
+    /// How long requests in flight may take to finish before their
+    /// connections are closed.
+    pub timeout: HumanDuration,
+    /// How long each shutdown hook may take.
+    pub hook_timeout: HumanDuration,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            delay: HumanDuration(Duration::ZERO),
+            timeout: HumanDuration(Duration::from_secs(30)),
+            hook_timeout: HumanDuration(Duration::from_secs(5)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// Filter directives of the application log, e.g. `info,phthonus=debug`.
+    pub filter: String,
+    /// `stdout`, `stderr`, a file path or `off`.
+    pub access_log: String,
+    /// `common`, `combined` or a custom template.
+    pub access_log_format: String,
+    /// Log request and response bodies at debug level.
+    pub body: bool,
+    /// Largest body that is logged.
+    pub body_limit: ByteSize,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            filter: DEFAULT_LOG_FILTER.into(),
+            access_log: "off".into(),
+            access_log_format: "combined".into(),
+            body: false,
+            body_limit: ByteSize(body_capture::DEFAULT_BODY_LIMIT),
+        }
+    }
+}
+
+/// Extra denylists, merged into the built-in defaults.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RedactConfig {
+    pub headers: Vec<String>,
+    pub fields: Vec<String>,
+    pub query: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    /// Bearer token of the admin routes, they are disabled without it.
+    pub token: Option<Secret>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct JwtConfig {
+    /// Signing secret of tokens, a random one is generated on every start
+    /// without it.
+    pub secret: Option<Secret>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// Expose Prometheus metrics at `/metrics`.
+    pub enabled: bool,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self { enabled: true }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OpenApiConfig {
+    /// Serve the OpenAPI document at `/openapi.json` and its reference page
+    /// at `/docs`.
+    pub enabled: bool,
+}
+
+impl Default for OpenApiConfig {
+    fn default() -> Self {
+        Self { enabled: true }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ApiConfig {
+    /// Version of requests to versioned routes without `/v{n}`,
+    /// `Accept-Version` or a `version` parameter in `Accept`, the latest
+    /// without it.
+    pub default_version: Option<u16>,
+    /// Versions answered with `Deprecation` and `Sunset` headers, e.g.
+    /// `[[api.deprecated]]`.
+    pub deprecated: Vec<DeprecatedVersion>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeprecatedVersion {
+    pub version: u16,
+    /// When the version was deprecated, sent as `Deprecation`.
+    pub since: DateTime<Utc>,
+    /// When the version will be removed, sent as `Sunset`.
+    pub sunset: Option<DateTime<Utc>>,
+    /// Migration guide, sent as `Link` with `rel="deprecation"`.
+    pub link: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OtlpProtocol {
+    Grpc,
+    #[serde(alias = "http/protobuf")]
+    Http,
+}
+
+impl FromStr for OtlpProtocol {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "grpc" => Ok(Self::Grpc),
+            "http" | "http/protobuf" => Ok(Self::Http),
+            other => bail!("unknown OTLP protocol `{other}`, expect `grpc` or `http`"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OtlpConfig {
+    /// Collector address, traces are only exported when it is set.
+    pub endpoint: Option<String>,
+    pub protocol: OtlpProtocol,
+    /// Ratio of root traces to sample, from `0.0` to `1.0`.
+    pub sample_ratio: f64,
+    /// Filter directives of exported spans and events, `log.filter` when
+    /// not set. Unlike `log.filter` it is not changed at runtime.
+    pub filter: Option<String>,
+}
+
+impl Default for OtlpConfig {
+    fn default() -> Self {
+        Self {
+            endpoint: None,
+            protocol: OtlpProtocol::Grpc,
+            sample_ratio: 1.0,
+            filter: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConfig {
+    /// Allowed origins, or `*` for any. CORS is off when empty.
+    pub origins: Vec<String>,
+    pub methods: Vec<String>,
+    pub credentials: bool,
+    pub max_age: Option<HumanDuration>,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self {
+            origins: vec![],
+            methods: ["GET", "POST", "PUT", "PATCH", "DELETE"]
+                .map(String::from)
+                .to_vec(),
+            credentials: false,
+            max_age: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SecurityConfig {
+    /// Add the security headers below and `X-Content-Type-Options`.
+    pub headers: bool,
+    /// `Strict-Transport-Security`, omitted when empty.
+    pub hsts: String,
+    /// `Content-Security-Policy`, omitted when empty.
+    pub csp: String,
+    /// `Referrer-Policy`, omitted when empty.
+    pub referrer_policy: String,
+    /// Add `Server`, `Phthonus-Version` and `Phthonus-Revision`.
+    pub version_headers: bool,
+}
+
+impl Default for SecurityConfig {
+    fn default() -> Self {
+        Self {
+            headers: true,
+            hsts: "max-age=31536000; includeSubDomains".into(),
+            // Nothing is rendered by the API, so nothing may be loaded or framed.
+            csp: "default-src 'none'; frame-ancestors 'none'".into(),
+            referrer_policy: "no-referrer".into(),
+            version_headers: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BodyLimitConfig {
+    pub limit: ByteSize,
+    /// Limits of route templates, e.g. `/v1/user/regist`.
+    pub routes: BTreeMap<String, ByteSize>,
+}
+
+impl Default for BodyLimitConfig {
+    fn default() -> Self {
+        Self {
+            limit: ByteSize(body_limit::DEFAULT_BODY_LIMIT),
+            routes: BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CompressionConfig {
+    /// Compress responses of routes without an override.
+    pub enabled: bool,
+    /// `gzip`, `br` and `zstd`.
+    pub algorithms: Vec<String>,
+    /// `fastest`, `default`, `best` or a number.
+    pub level: String,
+    /// Responses smaller than this are sent as is.
+    pub min_size: ByteSize,
+    /// Prefixes of compressible content types.
+    pub types: Vec<String>,
+    /// Overrides of route templates, e.g. `/json`.
+    pub routes: BTreeMap<String, bool>,
+    /// Largest request body after decompression.
+    pub decompression_limit: ByteSize,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            algorithms: ["gzip", "br", "zstd"].map(String::from).to_vec(),
+            level: "default".into(),
+            min_size: ByteSize(1024),
+            types: ["application/json", "text/"].map(String::from).to_vec(),
+            routes: BTreeMap::new(),
+            decompression_limit: ByteSize(8 * 1024 * 1024),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// `<burst>/<period>`, rate limiting is off without it.
+    pub quota: Option<Quota>,
+    pub key: KeyStrategy,
+    /// `X-Api-Key` values that identify a client, other values are ignored.
+    pub api_keys: Vec<Secret>,
+    /// Quotas of route templates, e.g. `/v1/user/regist`.
+    pub routes: BTreeMap<String, Quota>,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            quota: None,
+            key: KeyStrategy::Ip,
+            api_keys: vec![],
+            routes: BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConcurrencyConfig {
+    /// Max concurrent requests, the ceiling of the adaptive limit.
+    pub limit: Option<usize>,
+    /// Limits of route templates, e.g. `/json`.
+    pub routes: BTreeMap<String, usize>,
+    /// Adapt the global limit between `adaptive_min` and `limit`.
+    pub adaptive: bool,
+    pub adaptive_min: usize,
+    /// Latency above which the adaptive limit shrinks.
+    pub adaptive_latency: HumanDuration,
+    pub retry_after: HumanDuration,
+}
+
+impl Default for ConcurrencyConfig {
+    fn default() -> Self {
+        Self {
+            limit: None,
+            routes: BTreeMap::new(),
+            adaptive: false,
+            adaptive_min: load_shed::DEFAULT_ADAPTIVE_MIN,
+            adaptive_latency: load_shed::DEFAULT_ADAPTIVE_LATENCY.into(),
+            retry_after: load_shed::DEFAULT_RETRY_AFTER.into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimeoutConfig {
+    pub default: HumanDuration,
+    /// Timeouts of route templates, e.g. `/json`, or groups, e.g. `/v1/user/*`.
+    pub routes: BTreeMap<String, HumanDuration>,
+}
+
+impl Default for TimeoutConfig {
+    fn default() -> Self {
+        // The benchmark routes answer immediately, anything slower is stuck.
+        let fast = HumanDuration(Duration::from_secs(5));
+        Self {
+            default: DEFAULT_TIMEOUT.into(),
+            routes: ["/", "/json", "/text"]
+                .into_iter()
+                .map(|route| (route.to_string(), fast))
+                .collect(),
+        }
+    }
+}
+
+/// A secret value, masked when the config is printed or logged.
+#[derive(Clone, PartialEq, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+impl Serialize for Secret {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(REDACTED)
+    }
+}
+
+/// Parse the value of an environment variable into a config value.
+trait FromEnv: Sized {
+    fn from_env(value: &str) -> anyhow::Result<Self>;
+}
+
+macro_rules! from_env_by_parse {
+    ($($ty:ty),* $(,)?) => {
+        $(impl FromEnv for $ty {
+            fn from_env(value: &str) -> anyhow::Result<Self> {
+                value.trim().parse::<$ty>().map_err(Into::into)
+            }
+        })*
+    };
+}
+
+from_env_by_parse!(
+    u16,
+    u32,
+    usize,
+    f64,
+    IpAddr,
+    PathBuf,
+    BindAddr,
+    TrustedProxies,
+    HumanDuration,
+    ByteSize,
+    OtlpProtocol,
+    Quota,
+    KeyStrategy,
+);
+
+impl FromEnv for String {
+    fn from_env(value: &str) -> anyhow::Result<Self> {
+        Ok(value.to_string())
+    }
+}
+
+impl FromEnv for bool {
+    fn from_env(value: &str) -> anyhow::Result<Self> {
+        match value.trim() {
+            "1" | "true" | "on" => Ok(true),
+            "0" | "false" | "off" => Ok(false),
+            other => bail!("invalid switch `{other}`, expect true or false"),
+        }
+    }
+}
+
+impl FromEnv for Secret {
+    fn from_env(value: &str) -> anyhow::Result<Self> {
+        Ok(Self(value.to_string()))
+    }
+}
+
+/// An empty value or `off` unsets the option.
+impl<T: FromEnv> FromEnv for Option<T> {
+    fn from_env(value: &str) -> anyhow::Result<Self> {
+        if matches!(value.trim(), "" | "off") {
+            return Ok(None);
+        }
+        T::from_env(value).map(Some)
+    }
+}
+
+/// A comma separated list.
+impl<T: FromEnv> FromEnv for Vec<T> {
+    fn from_env(value: &str) -> anyhow::Result<Self> {
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(T::from_env)
+            .collect()
+    }
+}
+
+/// A comma separated list of `<route>=<value>`.
+impl<T: FromEnv> FromEnv for BTreeMap<String, T> {
+    fn from_env(value: &str) -> anyhow::Result<Self> {
+        value
+            .split(',')
+            .filter(|item| !item.trim().is_empty())
+            .map(|item| {
+                let (key, value) = item
+                    .split_once('=')
+                    .ok_or_else(|| anyhow!("invalid item `{item}`, expect `<route>=<value>`"))?;
+                let value = T::from_env(value).with_context(|| format!("invalid item `{item}`"))?;
+                Ok((key.trim().to_string(), value))
+            })
+            .collect()
+    }
+}
+
+impl Config {
+    /// Load the config from `file`, or [`DEFAULT_CONFIG_FILE`] when it
+    /// exists, and the environment, then validate it.
+    pub fn load(file: Option<&Path>) -> anyhow::Result<Self> {
+        Self::load_with(file, |key| env::var(key).ok(), &dotenv_vars())
+    }
+
+    /// Load with the variables of `env` above the ones of `dotenv`.
+    fn load_with(
+        file: Option<&Path>,
+        env: impl Fn(&str) -> Option<String>,
+        dotenv: &HashMap<String, String>,
+    ) -> anyhow::Result<Self> {
+        let text = match file {
+            Some(file) => Some(
+                fs::read_to_string(file)
+                    .with_context(|| format!("failed to read config file {}", file.display()))?,
+            ),
+            None => fs::read_to_string(DEFAULT_CONFIG_FILE).ok(),
+        };
+        let source = file.unwrap_or(Path::new(DEFAULT_CONFIG_FILE));
+        let mut config = match text {
+            Some(text) => Self::from_toml(&text)
+                .with_context(|| format!("invalid config file {}", source.display()))?,
+            None => Self::default(),
+        };
+        config.file = file.map(Path::to_path_buf);
+        config.apply_env(|key| env(key).or_else(|| dotenv.get(key).cloned()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Load the config again from the same file and `.env`, e.g. on
+    /// `SIGHUP`.
+    pub fn reload(&self) -> anyhow::Result<Self> {
+        Self::load(self.file.as_deref())
+    }
+
+    pub fn from_toml(text: &str) -> anyhow::Result<Self> {
+        Ok(toml::from_str(text)?)
+    }
+
+    /// Override keys with the environment variables returned by `var`.
+    pub fn apply_env(&mut self, var: impl Fn(&str) -> Option<String>) -> anyhow::Result<()> {
+        macro_rules! env_keys {
+            ($($key:literal => $($field:ident).+),* $(,)?) => {
+                $(if let Some(value) = var($key) {
+                    self.$($field).+ = FromEnv::from_env(&value)
+                        .with_context(|| format!("invalid {}", $key))?;
+                })*
+            };
+        }
+
+        env_keys!(
+            "PHTHONUS_HOST" => server.host,
+            "PHTHONUS_PORT" => server.port,
+            "PHTHONUS_BIND" => server.bind,
+            "PHTHONUS_IPV6_ONLY" => server.ipv6_only,
+            "PHTHONUS_UNIX_MODE" => server.unix_mode,
+            "PHTHONUS_UNIX_UID" => server.unix_uid,
+            "PHTHONUS_UNIX_GID" => server.unix_gid,
+            "PHTHONUS_TRUSTED_PROXIES" => server.trusted_proxies,
+            "PHTHONUS_TLS_CERT" => tls.cert,
+            "PHTHONUS_TLS_KEY" => tls.key,
+            "PHTHONUS_TLS_CLIENT_CA" => tls.client_ca,
+            "PHTHONUS_TLS_CLIENT_AUTH_OPTIONAL" => tls.client_auth_optional,
+            "PHTHONUS_TLS_RELOAD_INTERVAL" => tls.reload_interval,
+            "PHTHONUS_PROXY_PROTOCOL" => server.proxy_protocol,
+            "PHTHONUS_H2C" => http.h2c,
+            "PHTHONUS_KEEP_ALIVE" => http.keep_alive,
+            "PHTHONUS_HEADER_READ_TIMEOUT" => http.header_read_timeout,
+            "PHTHONUS_MAX_HEADERS" => http.max_headers,
+            "PHTHONUS_PIPELINE_FLUSH" => http.pipeline_flush,
+            "PHTHONUS_MAX_CONCURRENT_STREAMS" => http.max_concurrent_streams,
+            "PHTHONUS_H2_KEEP_ALIVE_INTERVAL" => http.keep_alive_interval,
+            "PHTHONUS_H2_KEEP_ALIVE_TIMEOUT" => http.keep_alive_timeout,
+            "PHTHONUS_MAX_REQUESTS" => http.max_requests,
+            "PHTHONUS_MAX_CONNECTION_AGE" => http.max_connection_age,
+            "PHTHONUS_SHUTDOWN_DELAY" => shutdown.delay,
+            "PHTHONUS_SHUTDOWN_TIMEOUT" => shutdown.timeout,
+            "PHTHONUS_SHUTDOWN_HOOK_TIMEOUT" => shutdown.hook_timeout,
+            "PHTHONUS_LOG" => log.filter,
+            "PHTHONUS_ACCESS_LOG" => log.access_log,
+            "PHTHONUS_ACCESS_LOG_FORMAT" => log.access_log_format,
+            "PHTHONUS_LOG_BODY" => log.body,
+            "PHTHONUS_LOG_BODY_LIMIT" => log.body_limit,
+            "PHTHONUS_REDACT_HEADERS" => redact.headers,
+            "PHTHONUS_REDACT_FIELDS" => redact.fields,
+            "PHTHONUS_REDACT_QUERY" => redact.query,
+            "PHTHONUS_ADMIN_TOKEN" => admin.token,
+            "PHTHONUS_JWT_SECRET" => jwt.secret,
+            "PHTHONUS_METRICS" => metrics.enabled,
+            "PHTHONUS_OPENAPI" => openapi.enabled,
+            "PHTHONUS_API_DEFAULT_VERSION" => api.default_version,
+            "PHTHONUS_OTLP_ENDPOINT" => otlp.endpoint,
+            "PHTHONUS_OTLP_PROTOCOL" => otlp.protocol,
+            "PHTHONUS_OTLP_SAMPLE_RATIO" => otlp.sample_ratio,
+            "PHTHONUS_OTLP_FILTER" => otlp.filter,
+            "PHTHONUS_CORS_ORIGINS" => cors.origins,
+            "PHTHONUS_CORS_METHODS" => cors.methods,
+            "PHTHONUS_CORS_CREDENTIALS" => cors.credentials,
+            "PHTHONUS_CORS_MAX_AGE" => cors.max_age,
+            "PHTHONUS_SECURITY_HEADERS" => security.headers,
+            "PHTHONUS_HSTS" => security.hsts,
+            "PHTHONUS_CSP" => security.csp,
+            "PHTHONUS_REFERRER_POLICY" => security.referrer_policy,
+            "PHTHONUS_VERSION_HEADERS" => security.version_headers,
+            "PHTHONUS_BODY_LIMIT" => body_limit.limit,
+            "PHTHONUS_BODY_LIMIT_ROUTES" => body_limit.routes,
+            "PHTHONUS_COMPRESSION" => compression.enabled,
+            "PHTHONUS_COMPRESSION_ALGORITHMS" => compression.algorithms,
+            "PHTHONUS_COMPRESSION_LEVEL" => compression.level,
+            "PHTHONUS_COMPRESSION_MIN_SIZE" => compression.min_size,
+            "PHTHONUS_COMPRESSION_TYPES" => compression.types,
+            "PHTHONUS_COMPRESSION_ROUTES" => compression.routes,
+            "PHTHONUS_DECOMPRESSION_LIMIT" => compression.decompression_limit,
+            "PHTHONUS_RATE_LIMIT" => rate_limit.quota,
+            "PHTHONUS_RATE_LIMIT_KEY" => rate_limit.key,
+            "PHTHONUS_RATE_LIMIT_API_KEYS" => rate_limit.api_keys,
+            "PHTHONUS_RATE_LIMIT_ROUTES" => rate_limit.routes,
+            "PHTHONUS_CONCURRENCY_LIMIT" => concurrency.limit,
+            "PHTHONUS_CONCURRENCY_LIMIT_ROUTES" => concurrency.routes,
+            "PHTHONUS_ADAPTIVE_LIMIT" => concurrency.adaptive,
+            "PHTHONUS_ADAPTIVE_LIMIT_MIN" => concurrency.adaptive_min,
+            "PHTHONUS_ADAPTIVE_LATENCY" => concurrency.adaptive_latency,
+            "PHTHONUS_LOAD_SHED_RETRY_AFTER" => concurrency.retry_after,
+            "PHTHONUS_TIMEOUT" => timeout.default,
+            "PHTHONUS_TIMEOUT_ROUTES" => timeout.routes,
+        );
+        // The legacy variable of the log filter.
+        if var("PHTHONUS_LOG").is_none() {
+            if let Some(filter) = var("axum") {
+                self.log.filter = filter;
+            }
+        }
+        Ok(())
+    }
+
+    /// Check values whose type alone does not make them valid. Settings of
+    /// the middlewares are checked when they are built.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        EnvFilter::try_new(&self.log.filter)
+            .with_context(|| format!("invalid log.filter `{}`", self.log.filter))?;
+        AccessLogFormat::from_name(&self.log.access_log_format)
+            .context("invalid log.access_log_format")?;
+        if let Some(filter) = &self.otlp.filter {
+            EnvFilter::try_new(filter)
+                .with_context(|| format!("invalid otlp.filter `{filter}`"))?;
+        }
+        if !(0.0..=1.0).contains(&self.otlp.sample_ratio) {
+            bail!("otlp.sample_ratio must be between 0.0 and 1.0");
+        }
+        self.server.unix_mode()?;
+        if self.tls.cert.is_some() != self.tls.key.is_some() {
+            bail!("tls.cert and tls.key must be set together");
+        }
+        if self.tls.client_ca.is_some() && self.tls.cert.is_none() {
+            bail!("tls.client_ca requires tls.cert and tls.key");
+        }
+        if self.http.max_headers == 0 {
+            bail!("http.max_headers must be a positive number");
+        }
+        if self.http.max_concurrent_streams == 0 {
+            bail!("http.max_concurrent_streams must be a positive number");
+        }
+        if self.http.max_requests == Some(0) {
+            bail!("http.max_requests must be a positive number");
+        }
+        if self.server.proxy_protocol && self.server.trusted_proxies.is_empty() {
+            bail!("server.proxy_protocol requires server.trusted_proxies");
+        }
+        if let Some(limit) = self.concurrency.limit {
+            if limit == 0 {
+                bail!("concurrency.limit must be a positive number");
+            }
+            if self.concurrency.adaptive && self.concurrency.adaptive_min > limit {
+                bail!("concurrency.adaptive_min must not exceed concurrency.limit");
+            }
+        }
+        if self.rate_limit.key == KeyStrategy::ApiKey && self.rate_limit.api_keys.is_empty() {
+            bail!("rate_limit.key = api_key requires rate_limit.api_keys");
+        }
+        if self.concurrency.adaptive_min == 0 {
+            bail!("concurrency.adaptive_min must be a positive number");
+        }
+        if let Some((route, _)) = self
+            .concurrency
+            .routes
+            .iter()
+            .find(|(_, limit)| **limit == 0)
+        {
+            bail!("concurrency.routes `{route}` must be a positive number");
+        }
+        Ok(())
+    }
+
+    /// The config as TOML, with secrets masked.
+    pub fn to_toml(&self) -> anyhow::Result<String> {
+        Ok(toml::to_string_pretty(self)?)
+    }
+}
+
+/// Variables of `.env` in the working directory or its parents, read without
+/// touching the environment of the process.
+pub fn dotenv_vars() -> HashMap<String, String> {
+    dotenvy::dotenv_iter()
+        .map(|vars| vars.filter_map(Result::ok).collect())
+        .unwrap_or_default()
+}
+
+/// Set the effective config of the process, returns the config that was set
+/// first when called again.
+pub fn init(config: Config) -> &'static Config {
+    CONFIG.get_or_init(|| config)
+}
+
+/// The effective config, defaults until [`init`] is called, e.g. in tests.
+pub fn config() -> &'static Config {
+    CONFIG.get_or_init(Config::default)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn load(toml: &str, vars: &[(&str, &str)]) -> anyhow::Result<Config> {
+        let vars: HashMap<_, _> = vars.iter().copied().collect();
+        let mut config = Config::from_toml(toml)?;
+        config.apply_env(|key| vars.get(key).map(|value| value.to_string()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    #[test]
+    fn layered() {
+        let toml = r#"
+            [server]
+            host = "127.0.0.1"
+            port = 8080
+
+            [timeout]
+            default = "30s"
+
+            [timeout.routes]
+            "/user/*" = 60
+        "#;
+        let config = load(
+            toml,
+            &[("PHTHONUS_PORT", "9090"), ("PHTHONUS_RATE_LIMIT", "")],
+        )
+        .unwrap();
+        assert_eq!(config.server.host, IpAddr::V4(Ipv4Addr::LOCALHOST));
+        assert_eq!(config.server.port, 9090, "env overrides the file");
+        assert_eq!(config.timeout.default, Duration::from_secs(30).into());
+        assert_eq!(
+            config.timeout.routes["/user/*"],
+            Duration::from_secs(60).into()
+        );
+        assert_eq!(config.log.filter, "info", "defaults fill the rest");
+        assert!(config.rate_limit.quota.is_none());
+
+        let config = load(
+            "",
+            &[
+                ("PHTHONUS_BODY_LIMIT_ROUTES", "/user/regist=4k,/json=10mb"),
+                ("PHTHONUS_COMPRESSION_ROUTES", "/json=off"),
+                ("PHTHONUS_TRUSTED_PROXIES", "10.0.0.0/8, 127.0.0.1"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(config.body_limit.routes["/user/regist"], ByteSize(4096));
+        assert!(!config.compression.routes["/json"]);
+        assert!(config
+            .server
+            .trusted_proxies
+            .contains(IpAddr::V4(Ipv4Addr::LOCALHOST)));
+    }
+
+    #[test]
+    fn env_wins_over_dotenv() {
+        let env = |key: &str| (key == "PHTHONUS_PORT").then(|| "9090".to_string());
+        let dotenv = |port: &str| {
+            HashMap::from([
+                ("PHTHONUS_PORT".to_string(), port.to_string()),
+                ("PHTHONUS_LOG".to_string(), "debug".to_string()),
+            ])
+        };
+        let config = Config::load_with(None, env, &dotenv("7070")).unwrap();
+        assert_eq!(config.server.port, 9090);
+        assert_eq!(config.log.filter, "debug", ".env overrides the defaults");
+
+        // Reloaded after `.env` changed.
+        let config = Config::load_with(config.file.as_deref(), env, &dotenv("6060")).unwrap();
+        assert_eq!(config.server.port, 9090);
+    }
+
+    #[test]
+    fn errors_name_the_key() {
+        let err = load("", &[("PHTHONUS_PORT", "http")]).unwrap_err();
+        assert!(format!("{err:#}").contains("PHTHONUS_PORT"), "{err:#}");
+
+        let err = load("[server]\nport = \"http\"", &[]).unwrap_err();
+        assert!(format!("{err:#}").contains("port"), "{err:#}");
+
+        let err = load("[server]\nprot = 80", &[]).unwrap_err();
+        assert!(format!("{err:#}").contains("prot"), "{err:#}");
+
+        let err = load("[log]\nfilter = \"info,=\"", &[]).unwrap_err();
+        assert!(format!("{err:#}").contains("log.filter"), "{err:#}");
+
+        let err = load("", &[("PHTHONUS_PROXY_PROTOCOL", "true")]).unwrap_err();
+        assert!(format!("{err:#}").contains("server.trusted_proxies"));
+
+        let err = load("[http]\nmax_requests = 0", &[]).unwrap_err();
+        assert!(format!("{err:#}").contains("http.max_requests"), "{err:#}");
+
+        let err = load("", &[("PHTHONUS_RATE_LIMIT_KEY", "api_key")]).unwrap_err();
+        assert!(
+            format!("{err:#}").contains("rate_limit.api_keys"),
+            "{err:#}"
+        );
+    }
+
+    #[test]
+    fn mask_secrets() {
+        let config = load(
+            "[admin]\ntoken = \"hunter2\"",
+            &[("PHTHONUS_JWT_SECRET", "s3cret")],
+        )
+        .unwrap();
+        assert_eq!(config.admin.token.as_ref().unwrap().expose(), "hunter2");
+        let toml = config.to_toml().unwrap();
+        assert!(!toml.contains("hunter2") && !toml.contains("s3cret"));
+        assert!(toml.contains(REDACTED));
+
+        // The printed config can be loaded back.
+        let printed = Config::from_toml(&Config::default().to_toml().unwrap()).unwrap();
+        assert_eq!(printed.timeout.routes, Config::default().timeout.routes);
+    }
+}
@@ -1,4 +1,95 @@
+use std::fmt::Display;
+
+use chrono::{DateTime, Utc};
+
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 pub const NAME: &str = env!("CARGO_PKG_NAME");
 pub const DEFAULT_PORT: u16 = 4000;
 pub const RUA_COMPILER: &str = env!("RUA_COMPILER");
+
+/// Build metadata collected by `build.rs`.
+pub const BUILD_INFO: BuildInfo = BuildInfo {
+    name: NAME,
+    version: VERSION,
+    rustc: RUA_COMPILER,
+    rustc_host: option_env!("RUA_RUSTC_HOST"),
+    llvm_version: option_env!("RUA_LLVM_VERSION"),
+    git_commit: option_env!("RUA_GIT_COMMIT"),
+    git_branch: option_env!("RUA_GIT_BRANCH"),
+    git_dirty: option_env!("RUA_GIT_DIRTY"),
+    build_timestamp: env!("RUA_BUILD_TIMESTAMP"),
+    target: env!("RUA_TARGET"),
+    profile: env!("RUA_PROFILE"),
+    features: env!("RUA_FEATURES"),
+};
+
+/// Git fields are `None` when built outside a git checkout.
+#[derive(Debug, Clone, Copy)]
+pub struct BuildInfo {
+    pub name: &'static str,
+    pub version: &'static str,
+    /// First line of `rustc -vV`
+    pub rustc: &'static str,
+    pub rustc_host: Option<&'static str>,
+    pub llvm_version: Option<&'static str>,
+    /// Full commit hash
+    pub git_commit: Option<&'static str>,
+    /// `None` on a detached HEAD
+    pub git_branch: Option<&'static str>,
+    git_dirty: Option<&'static str>,
+    /// Unix timestamp, honours `SOURCE_DATE_EPOCH`
+    build_timestamp: &'static str,
+    pub target: &'static str,
+    pub profile: &'static str,
+    /// Enabled cargo features, separated by comma
+    features: &'static str,
+}
+
+impl BuildInfo {
+    /// Commit hash shorten to 7 characters.
+    pub fn short_commit(&self) -> Option<&'static str> {
+        self.git_commit
+            .map(|commit| commit.get(..7).unwrap_or(commit))
+    }
+
+    /// Whether the working tree had uncommitted changes.
+    pub fn git_dirty(&self) -> Option<bool> {
+        self.git_dirty.map(|dirty| dirty == "true")
+    }
+
+    pub fn build_time(&self) -> Option<DateTime<Utc>> {
+        self.build_timestamp
+            .parse::<i64>()
+            .ok()
+            .and_then(|timestamp| DateTime::from_timestamp(timestamp, 0))
+    }
+
+    pub fn features(&self) -> impl Iterator<Item = &'static str> {
+        self.features
+            .split(',')
+            .filter(|feature| !feature.is_empty())
+    }
+
+    /// Short commit with a `-dirty` suffix, e.g. `1a2b3c4-dirty`.
+    pub fn revision(&self) -> Option<String> {
+        let commit = self.short_commit()?;
+        Some(match self.git_dirty() {
+            Some(true) => format!("{commit}-dirty"),
+            _ => commit.to_string(),
+        })
+    }
+}
+
+impl Display for BuildInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.name, self.version)?;
+        if let Some(revision) = self.revision() {
+            write!(f, " ({revision}")?;
+            if let Some(branch) = self.git_branch {
+                write!(f, " {branch}")?;
+            }
+            write!(f, ")")?;
+        }
+        write!(f, " {} {} {}", self.target, self.profile, self.rustc)
+    }
+}
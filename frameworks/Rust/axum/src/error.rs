@@ -6,9 +6,10 @@ use axum::{
     response::{IntoResponse, Response},
     Json,
 };
-use serde_json::json;
+use serde::Serialize;
 use serde_repr::*;
 use tracing::error;
+use utoipa::ToSchema;
 
 #[derive(thiserror::Error, Debug)]
 pub enum AppError {
@@ -29,17 +30,36 @@ pub enum AppError {
     // 路由通常错误 错误信息直接返回用户
     #[error("{0}")]
     InvalidToken(Cow<'static, str>),
+    #[error("{0}")]
+    InvalidParameter(Cow<'static, str>),
+    #[error("{0}")]
+    Unauthorized(Cow<'static, str>),
+    #[error("{0}")]
+    TooManyRequests(Cow<'static, str>),
+    #[error("{0}")]
+    PayloadTooLarge(Cow<'static, str>),
+    #[error("{0}")]
+    PreconditionFailed(Cow<'static, str>),
+    #[error("{0}")]
+    ServiceUnavailable(Cow<'static, str>),
+    #[error("{0}")]
+    RequestTimeout(Cow<'static, str>),
 }
 
-#[derive(Serialize_repr, Deserialize_repr, PartialEq, Debug)]
+#[derive(Serialize_repr, Deserialize_repr, ToSchema, PartialEq, Debug)]
 #[repr(u16)]
 pub enum ErrorCode {
     Normal = 200,
     InternalError = 1000,
-    // NotAuthorized = 1001,
+    NotAuthorized = 1001,
     AuthorizeFailed = 1002,
     UserConflict = 1003,
     ParameterIncorrect = 1004,
+    TooManyRequests = 1005,
+    PayloadTooLarge = 1006,
+    PreconditionFailed = 1007,
+    ServiceUnavailable = 1008,
+    RequestTimeout = 1009,
 }
 
 impl Display for ErrorCode {
@@ -49,16 +69,31 @@ impl Display for ErrorCode {
         let res = match self {
             Normal => "",
             InternalError => "服务器内部错误",
-            //NotAuthorized => "未登录",
+            NotAuthorized => "未登录",
             AuthorizeFailed => "用户名或密码错误",
             UserConflict => "该用户已经存在",
             ParameterIncorrect => "请求参数错误",
+            TooManyRequests => "请求过于频繁",
+            PayloadTooLarge => "请求体过大",
+            PreconditionFailed => "资源已被修改",
+            ServiceUnavailable => "服务繁忙",
+            RequestTimeout => "请求超时",
         };
         f.write_str(res)?;
         Ok(())
     }
 }
 
+/// Body of every `AppError` response.
+#[derive(Serialize, ToSchema)]
+pub struct ErrorResponse {
+    pub code: ErrorCode,
+    /// Localized description of `code`.
+    pub message: String,
+    /// What went wrong.
+    pub error: String,
+}
+
 /// Log and return INTERNAL_SERVER_ERROR
 fn log_internal_error<T: Display>(err: T) -> (StatusCode, ErrorCode, String) {
     use ErrorCode::*;
@@ -79,6 +114,25 @@ impl IntoResponse for AppError {
         let (status_code, code, err_message) = match self {
             AppError::Any(err) => log_internal_error(err),
             AppError::Jwt(err) => log_internal_error(err),
+            // The body hit the limit of `body_limit` while being buffered.
+            AppError::AxumFormRejection(ref rejection)
+                if rejection.status() == StatusCode::PAYLOAD_TOO_LARGE =>
+            {
+                (
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    PayloadTooLarge,
+                    self.to_string(),
+                )
+            }
+            AppError::AxumJsonRejection(ref rejection)
+                if rejection.status() == StatusCode::PAYLOAD_TOO_LARGE =>
+            {
+                (
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    PayloadTooLarge,
+                    self.to_string(),
+                )
+            }
             AppError::AxumFormRejection(_) | AppError::AxumJsonRejection(_) => (
                 StatusCode::BAD_REQUEST,
                 ParameterIncorrect,
@@ -93,12 +147,35 @@ impl IntoResponse for AppError {
                 AuthorizeFailed,
                 "Invalid token".to_string(),
             ),
+            AppError::InvalidParameter(msg) => {
+                (StatusCode::BAD_REQUEST, ParameterIncorrect, msg.into())
+            }
+            AppError::Unauthorized(msg) => (StatusCode::UNAUTHORIZED, NotAuthorized, msg.into()),
+            AppError::TooManyRequests(msg) => {
+                (StatusCode::TOO_MANY_REQUESTS, TooManyRequests, msg.into())
+            }
+            AppError::PayloadTooLarge(msg) => {
+                (StatusCode::PAYLOAD_TOO_LARGE, PayloadTooLarge, msg.into())
+            }
+            AppError::PreconditionFailed(msg) => (
+                StatusCode::PRECONDITION_FAILED,
+                PreconditionFailed,
+                msg.into(),
+            ),
+            AppError::ServiceUnavailable(msg) => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                ServiceUnavailable,
+                msg.into(),
+            ),
+            AppError::RequestTimeout(msg) => {
+                (StatusCode::REQUEST_TIMEOUT, RequestTimeout, msg.into())
+            }
         };
-        let body = Json(json!({
-            "code": code,
-            "message": code.to_string(),
-            "error": err_message
-        }));
+        let body = Json(ErrorResponse {
+            message: code.to_string(),
+            code,
+            error: err_message,
+        });
         (status_code, body).into_response()
     }
 }
@@ -1,13 +1,27 @@
-use std::{env, error::Error, net::SocketAddr};
+use std::{error::Error, path::PathBuf, sync::Arc};
 
 use axum::Router;
-use consts::{DEFAULT_PORT, RUA_COMPILER};
-use dotenvy::dotenv;
+use clap::Parser;
+use cli::{Cli, Command, ServeArgs};
+use config::Config;
+use consts::BUILD_INFO;
+use middlewares::rate_limit::{MemoryStore, RateLimitStore};
 use routes::routes;
-use tokio::net::TcpListener;
 use tracing::info;
-use utils::{init_logger, shutdown_signal};
+use utils::{
+    init_logger,
+    jwt::check_keys,
+    listener::AppListener,
+    log_level::reload_on_sighup,
+    readiness::{register_check, set_shutting_down},
+    server::Server,
+    shutdown::run_hooks,
+    shutdown_signal,
+    tls::Tls,
+};
 
+mod cli;
+mod config;
 mod consts;
 mod error;
 mod middlewares;
@@ -18,31 +32,88 @@ type Result<T> = std::result::Result<T, Box<dyn Error>>;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    dotenv().ok();
-    init_logger();
+    let cli = Cli::parse();
+    // `PHTHONUS_CONFIG` may also be set in `.env`, which stays out of the
+    // environment.
+    let file = cli.config.clone().or_else(|| {
+        config::dotenv_vars()
+            .remove("PHTHONUS_CONFIG")
+            .map(PathBuf::from)
+    });
+    let mut config = Config::load(file.as_deref())?;
+    let command = cli
+        .command
+        .unwrap_or_else(|| Command::Serve(ServeArgs::default()));
+    if let Command::Serve(args) = &command {
+        args.apply(&mut config)?;
+    }
+    if cli.print_config {
+        print!("{}", config.to_toml()?);
+        return Ok(());
+    }
+    let config = config::init(config);
+    match command {
+        Command::Serve(_) => serve(config).await,
+        command => Ok(command.run(config).await?),
+    }
+}
+
+async fn serve(config: &'static Config) -> Result<()> {
+    init_logger(config)?;
 
-    info!("Phthonus {}", RUA_COMPILER);
+    info!("{}", BUILD_INFO);
     info!("Starting server");
-    let port = env::var("PHTHONUS_PORT")
-        .map(|port| port.parse::<u16>().unwrap_or(DEFAULT_PORT))
-        .unwrap_or(DEFAULT_PORT);
-    let addr = SocketAddr::from(([0, 0, 0, 0], port));
-    let listener = TcpListener::bind(addr).await?;
-    info!("listening on {}", addr);
-
-    axum::serve(listener, app())
-        .with_graceful_shutdown(shutdown_signal(shutdown))
-        .await?;
+    let listener = AppListener::bind(&config.server, Tls::load(&config.tls)?)?;
+    for addr in listener.local_addrs() {
+        info!("listening on {}", addr);
+    }
+
+    tokio::spawn(reload_on_sighup(config));
+    register_check("jwt", check_keys);
+
+    let router = app(config)?;
+    #[cfg(unix)]
+    let upgrade = utils::upgrade::upgrade_on_sigusr2(listener.try_clone_fds()?);
+    #[cfg(not(unix))]
+    let upgrade = std::future::pending::<()>();
+    #[cfg(unix)]
+    utils::upgrade::notify_ready();
+
+    let signal = async {
+        tokio::select! {
+            _ = shutdown_signal(shutdown) => {}
+            _ = upgrade => {}
+        }
+    };
+    Server::from_config(&config.http, &config.shutdown)
+        .serve(listener, router, signal)
+        .await;
+    run_hooks(config.shutdown.hook_timeout.0).await;
+    info!("Server stopped");
     Ok(())
 }
 
-#[derive(Debug, Clone)]
-pub struct AppState {}
+/// Backends shared by the routes, in-memory by default.
+#[derive(Clone)]
+pub struct AppState {
+    /// Rate limit state, replace it with a shared backend to limit across
+    /// instances.
+    pub rate_limit_store: Arc<dyn RateLimitStore>,
+}
+
+impl Default for AppState {
+    fn default() -> Self {
+        Self {
+            rate_limit_store: Arc::new(MemoryStore::default()),
+        }
+    }
+}
 
-fn app() -> Router {
-    Router::new().merge(routes())
+fn app(config: &Config) -> anyhow::Result<Router> {
+    Ok(Router::new().merge(routes(config, &AppState::default())?))
 }
 
 fn shutdown() {
-    info!("Server shuting down")
+    info!("Server shuting down");
+    set_shutting_down();
 }
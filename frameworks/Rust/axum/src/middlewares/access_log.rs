@@ -0,0 +1,610 @@
+use std::{
+    fmt::{self, Write as _},
+    fs::OpenOptions,
+    net::IpAddr,
+    pin::Pin,
+    sync::{LazyLock, Mutex},
+    task::{ready, Context as TaskContext, Poll},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Context};
+use axum::{
+    body::{Body, Bytes, HttpBody},
+    extract::Request,
+    http::{header, HeaderMap, HeaderName, StatusCode},
+    middleware::{self, Next},
+    response::Response,
+    Router,
+};
+use chrono::{DateTime, Local};
+use hyper::body::{Frame, SizeHint};
+use tracing::{info, Event, Subscriber};
+use tracing_subscriber::{
+    filter::filter_fn,
+    fmt::{format::Writer, FmtContext, FormatEvent, FormatFields},
+    layer::Filter,
+    registry::LookupSpan,
+    Layer,
+};
+
+use super::client_ip::ClientIp;
+use crate::{
+    config::{config, LogConfig},
+    utils::{
+        jwt::decode_jwt,
+        redact::{REDACTED, REDACTOR},
+    },
+};
+
+/// Tracing target of access log events. Events with this target are written
+/// to the access log sink only and never reach the application log.
+pub const ACCESS_LOG_TARGET: &str = "access_log";
+
+/// Apache Common Log Format.
+pub const COMMON_FORMAT: &str = r#"%h %l %u %t "%r" %>s %b"#;
+/// Apache Combined Log Format.
+pub const COMBINED_FORMAT: &str = r#"%h %l %u %t "%r" %>s %b "%{Referer}i" "%{User-Agent}i""#;
+
+/// Access log format, read from `log.access_log_format`.
+///
+/// Accepts `common`, `combined` or a custom template.
+static FORMAT: LazyLock<AccessLogFormat> = LazyLock::new(|| {
+    AccessLogFormat::from_name(&config().log.access_log_format)
+        .expect("format is checked by Config::validate")
+});
+
+#[derive(Debug, PartialEq)]
+enum Segment {
+    Literal(String),
+    /// `%h` client address, see [`ClientIp`]
+    RemoteAddr,
+    /// `%l` remote logname, always `-`
+    RemoteLogname,
+    /// `%u` user from the JWT `sub` claim
+    User,
+    /// `%t` time the request was received
+    Time,
+    /// `%r` first line of request
+    RequestLine,
+    /// `%s` and `%>s` response status
+    Status,
+    /// `%b` bytes of the response body sent, `-` when zero
+    BytesClf,
+    /// `%B` bytes of the response body sent
+    Bytes,
+    /// `%m` request method
+    Method,
+    /// `%U` request path
+    Path,
+    /// `%q` query string prefixed with `?`
+    Query,
+    /// `%H` request protocol
+    Protocol,
+    /// `%D` latency in microseconds
+    LatencyMicros,
+    /// `%T` latency in seconds
+    LatencySeconds,
+    /// `%{Name}i` request header
+    RequestHeader(HeaderName),
+    /// `%{Name}o` response header
+    ResponseHeader(HeaderName),
+}
+
+/// A parsed access log template.
+///
+/// Directives follow Apache `mod_log_config`: `%h %l %u %t %r %s %>s %b %B
+/// %m %U %q %H %D %T %{Header}i %{Header}o` and `%%`.
+#[derive(Debug)]
+pub struct AccessLogFormat {
+    segments: Vec<Segment>,
+}
+
+impl AccessLogFormat {
+    /// Resolve a preset name or parse a custom template.
+    pub fn from_name(name: &str) -> anyhow::Result<Self> {
+        match name {
+            "" | "combined" => Self::parse(COMBINED_FORMAT),
+            "common" => Self::parse(COMMON_FORMAT),
+            template => Self::parse(template),
+        }
+    }
+
+    pub fn parse(template: &str) -> anyhow::Result<Self> {
+        let mut segments = vec![];
+        let mut literal = String::new();
+        let mut chars = template.chars();
+
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                literal.push(c);
+                continue;
+            }
+            let mut directive = chars
+                .next()
+                .ok_or_else(|| anyhow!("unterminated directive in access log format"))?;
+            if directive == '%' {
+                literal.push('%');
+                continue;
+            }
+            if !literal.is_empty() {
+                segments.push(Segment::Literal(std::mem::take(&mut literal)));
+            }
+            if directive == '>' {
+                directive = chars.next().unwrap_or_default();
+            }
+            let segment = match directive {
+                'h' => Segment::RemoteAddr,
+                'l' => Segment::RemoteLogname,
+                'u' => Segment::User,
+                't' => Segment::Time,
+                'r' => Segment::RequestLine,
+                's' => Segment::Status,
+                'b' => Segment::BytesClf,
+                'B' => Segment::Bytes,
+                'm' => Segment::Method,
+                'U' => Segment::Path,
+                'q' => Segment::Query,
+                'H' => Segment::Protocol,
+                'D' => Segment::LatencyMicros,
+                'T' => Segment::LatencySeconds,
+                '{' => {
+                    let name = chars.by_ref().take_while(|c| *c != '}').collect::<String>();
+                    let name = HeaderName::try_from(name.as_str()).with_context(|| {
+                        format!("invalid header name `{name}` in access log format")
+                    })?;
+                    match chars.next() {
+                        Some('i') => Segment::RequestHeader(name),
+                        Some('o') => Segment::ResponseHeader(name),
+                        _ => bail!("expect `i` or `o` after `%{{{name}}}` in access log format"),
+                    }
+                }
+                other => bail!("unknown directive `%{other}` in access log format"),
+            };
+            segments.push(segment);
+        }
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+        Ok(Self { segments })
+    }
+
+    fn needs(&self, f: impl Fn(&Segment) -> bool) -> bool {
+        self.segments.iter().any(f)
+    }
+
+    fn render(&self, record: &Record) -> Result<String, fmt::Error> {
+        use Segment::*;
+
+        let mut line = String::with_capacity(128);
+        for segment in &self.segments {
+            match segment {
+                Literal(s) => line.push_str(s),
+                RemoteAddr => match record.remote_addr {
+                    Some(ip) => write!(line, "{ip}")?,
+                    None => line.push('-'),
+                },
+                RemoteLogname => line.push('-'),
+                User => match &record.user {
+                    Some(user) => escape(user, &mut line),
+                    None => line.push('-'),
+                },
+                Time => write!(line, "[{}]", record.time.format("%d/%b/%Y:%H:%M:%S %z"))?,
+                RequestLine => {
+                    write!(line, "{} ", record.method)?;
+                    escape(&record.path_and_query, &mut line);
+                    write!(line, " {}", record.protocol)?;
+                }
+                Status => write!(line, "{}", record.status.as_u16())?,
+                BytesClf => match record.sent {
+                    0 => line.push('-'),
+                    sent => write!(line, "{sent}")?,
+                },
+                Bytes => write!(line, "{}", record.sent)?,
+                Method => line.push_str(&record.method),
+                Path => escape(record.path(), &mut line),
+                Query => {
+                    if let Some((_, query)) = record.path_and_query.split_once('?') {
+                        line.push('?');
+                        escape(query, &mut line);
+                    }
+                }
+                Protocol => line.push_str(record.protocol),
+                LatencyMicros => write!(line, "{}", record.latency.as_micros())?,
+                LatencySeconds => write!(line, "{}", record.latency.as_secs())?,
+                RequestHeader(name) => write_header(&record.headers, name, &mut line),
+                ResponseHeader(name) => write_header(&record.response_headers, name, &mut line),
+            }
+        }
+        Ok(line)
+    }
+}
+
+/// Request information captured before the request is handed to the next
+/// service, completed once the response body is sent.
+struct Record {
+    remote_addr: Option<IpAddr>,
+    user: Option<String>,
+    time: DateTime<Local>,
+    method: String,
+    path_and_query: String,
+    protocol: &'static str,
+    /// Request headers referenced by the format.
+    headers: HeaderMap,
+    status: StatusCode,
+    /// Response headers referenced by the format.
+    response_headers: HeaderMap,
+    /// Bytes of the response body sent, after compression.
+    sent: u64,
+    latency: Duration,
+}
+
+impl Record {
+    fn path(&self) -> &str {
+        self.path_and_query
+            .split_once('?')
+            .map_or(self.path_and_query.as_str(), |(path, _)| path)
+    }
+}
+
+fn write_header(headers: &HeaderMap, name: &HeaderName, out: &mut String) {
+    if REDACTOR.is_sensitive_header(name.as_str()) && headers.contains_key(name) {
+        out.push_str(REDACTED);
+        return;
+    }
+    match headers.get(name).map(|value| value.as_bytes()) {
+        Some(value) if !value.is_empty() => escape(&String::from_utf8_lossy(value), out),
+        _ => out.push('-'),
+    }
+}
+
+/// Escape quotes, backslashes and control characters the same way as Apache
+/// does, so each record always stays on a single line.
+fn escape(value: &str, out: &mut String) {
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(out, "\\x{:02x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+}
+
+/// Write an access log record for each request of `router`, unless
+/// `log.access_log` is off.
+///
+/// Applied outside of compression, so `%b` is the size on the wire.
+pub fn access_log_route(router: Router, config: &LogConfig) -> Router {
+    if access_log_enabled(config) {
+        router.layer(middleware::from_fn(access_log))
+    } else {
+        router
+    }
+}
+
+/// Middleware for writing an access log record for each request.
+///
+/// The record is rendered with the format from `log.access_log_format`
+/// once the response body is sent, or dropped, and emitted as a tracing
+/// event with the `access_log` target, which is routed to the access log
+/// sink by [`access_log_layer`].
+pub async fn access_log(req: Request, next: Next) -> Response {
+    let format = &*FORMAT;
+    let start = Instant::now();
+
+    let headers = req.headers();
+    let user = if format.needs(|s| *s == Segment::User) {
+        headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .and_then(|token| decode_jwt(token).ok())
+            .map(|data| data.claims.sub)
+    } else {
+        None
+    };
+    let mut captured = HeaderMap::new();
+    for segment in &format.segments {
+        if let Segment::RequestHeader(name) = segment {
+            if let Some(value) = headers.get(name) {
+                captured.insert(name.clone(), value.clone());
+            }
+        }
+    }
+    let mut record = Record {
+        remote_addr: req.extensions().get::<ClientIp>().map(|ClientIp(ip)| *ip),
+        user,
+        time: Local::now(),
+        method: req.method().to_string(),
+        path_and_query: REDACTOR.uri(req.uri()).into_owned(),
+        protocol: protocol(&req),
+        headers: captured,
+        status: StatusCode::OK,
+        response_headers: HeaderMap::new(),
+        sent: 0,
+        latency: Duration::ZERO,
+    };
+
+    let res = next.run(req).await;
+    record.status = res.status();
+    for segment in &format.segments {
+        if let Segment::ResponseHeader(name) = segment {
+            if let Some(value) = res.headers().get(name) {
+                record.response_headers.insert(name.clone(), value.clone());
+            }
+        }
+    }
+    res.map(|body| {
+        Body::new(Counted {
+            inner: body,
+            pending: Some((start, record)),
+        })
+    })
+}
+
+/// Response body counting the bytes sent, writes the record of the request
+/// when it ends or is dropped.
+struct Counted {
+    inner: Body,
+    pending: Option<(Instant, Record)>,
+}
+
+impl Counted {
+    fn finish(&mut self) {
+        let Some((start, mut record)) = self.pending.take() else {
+            return;
+        };
+        record.latency = start.elapsed();
+        match FORMAT.render(&record) {
+            Ok(line) => info!(target: ACCESS_LOG_TARGET, "{line}"),
+            Err(err) => tracing::error!("render access log failed: {err}"),
+        }
+    }
+}
+
+impl HttpBody for Counted {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, axum::Error>>> {
+        let frame = ready!(Pin::new(&mut self.inner).poll_frame(cx));
+        match &frame {
+            Some(Ok(frame)) => {
+                let len = frame.data_ref().map_or(0, Bytes::len);
+                if let Some((_, record)) = &mut self.pending {
+                    record.sent += len as u64;
+                }
+            }
+            _ => self.finish(),
+        }
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for Counted {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+fn protocol(req: &Request) -> &'static str {
+    use axum::http::Version;
+
+    match req.version() {
+        Version::HTTP_09 => "HTTP/0.9",
+        Version::HTTP_10 => "HTTP/1.0",
+        Version::HTTP_11 => "HTTP/1.1",
+        Version::HTTP_2 => "HTTP/2.0",
+        Version::HTTP_3 => "HTTP/3.0",
+        _ => "-",
+    }
+}
+
+/// Whether the access log is enabled by `log.access_log`.
+pub fn access_log_enabled(config: &LogConfig) -> bool {
+    !config.access_log.is_empty() && config.access_log != "off"
+}
+
+/// Filter that drops access log events, used by the application log layer.
+pub fn exclude_access_log<S>() -> impl Filter<S> {
+    filter_fn(|meta| meta.target() != ACCESS_LOG_TARGET)
+}
+
+/// Build the tracing layer that writes access log records to the sink in
+/// `log.access_log`.
+///
+/// The sink can be `stdout`, `stderr` or a file path, the file is opened in
+/// append mode. Returns `None` when the access log is disabled.
+pub fn access_log_layer<S>(
+    config: &LogConfig,
+) -> anyhow::Result<Option<Box<dyn Layer<S> + Send + Sync>>>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    if !access_log_enabled(config) {
+        return Ok(None);
+    }
+    let sink = &config.access_log;
+    let layer = tracing_subscriber::fmt::layer()
+        .with_ansi(false)
+        .event_format(RawLine);
+    let only_access_log = filter_fn(|meta| meta.target() == ACCESS_LOG_TARGET);
+    let layer = match sink.as_str() {
+        "stdout" => layer
+            .with_writer(std::io::stdout)
+            .with_filter(only_access_log)
+            .boxed(),
+        "stderr" => layer
+            .with_writer(std::io::stderr)
+            .with_filter(only_access_log)
+            .boxed(),
+        path => {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("failed to open access log file {path}"))?;
+            layer
+                .with_writer(Mutex::new(file))
+                .with_filter(only_access_log)
+                .boxed()
+        }
+    };
+    Ok(Some(layer))
+}
+
+/// Event formatter that only writes the message, without timestamp, level or
+/// span context.
+struct RawLine;
+
+impl<S, N> FormatEvent<S, N> for RawLine
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        ctx.format_fields(writer.by_ref(), event)?;
+        writeln!(writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io, sync::Arc};
+
+    use axum::http::HeaderValue;
+    use chrono::TimeZone;
+    use http_body_util::BodyExt;
+    use tracing_subscriber::{fmt::MakeWriter, layer::SubscriberExt};
+
+    use super::*;
+
+    fn record() -> Record {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::USER_AGENT, HeaderValue::from_static("curl/8.0"));
+        Record {
+            remote_addr: Some(IpAddr::from([127, 0, 0, 1])),
+            user: Some("xfy".into()),
+            time: Local.with_ymd_and_hms(2000, 10, 10, 13, 55, 36).unwrap(),
+            method: "GET".into(),
+            path_and_query: "/json?a=\"1\"".into(),
+            protocol: "HTTP/1.1",
+            headers,
+            status: StatusCode::OK,
+            response_headers: HeaderMap::new(),
+            sent: 5,
+            latency: Duration::from_micros(1500),
+        }
+    }
+
+    #[test]
+    fn combined_format_works() {
+        let format = AccessLogFormat::from_name("combined").unwrap();
+        let line = format.render(&record()).unwrap();
+        let time = record().time.format("%d/%b/%Y:%H:%M:%S %z").to_string();
+        assert_eq!(
+            line,
+            format!(
+                r#"127.0.0.1 - xfy [{time}] "GET /json?a=\"1\" HTTP/1.1" 200 5 "-" "curl/8.0""#
+            )
+        );
+    }
+
+    #[test]
+    fn custom_format_works() {
+        let format = AccessLogFormat::parse("%m %U%q %s %B %Dus 100%%").unwrap();
+        let line = format.render(&record()).unwrap();
+        assert_eq!(line, r#"GET /json?a=\"1\" 200 5 1500us 100%"#);
+    }
+
+    /// Lines of the access log events.
+    #[derive(Clone, Default)]
+    struct Lines(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Lines {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl<'a> MakeWriter<'a> for Lines {
+        type Writer = Self;
+
+        fn make_writer(&'a self) -> Self::Writer {
+            self.clone()
+        }
+    }
+
+    #[tokio::test]
+    async fn count_bytes_sent() {
+        let lines = Lines::default();
+        let subscriber = tracing_subscriber::registry().with(
+            tracing_subscriber::fmt::layer()
+                .event_format(RawLine)
+                .with_writer(lines.clone()),
+        );
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let chunks = futures_util::stream::iter(["hel", "lo"].map(Ok::<_, io::Error>));
+        let body = Counted {
+            inner: Body::from_stream(chunks),
+            pending: Some((
+                Instant::now(),
+                Record {
+                    sent: 0,
+                    ..record()
+                },
+            )),
+        };
+        assert_eq!(body.collect().await.unwrap().to_bytes(), "hello");
+        let empty = Counted {
+            inner: Body::empty(),
+            pending: Some((
+                Instant::now(),
+                Record {
+                    sent: 0,
+                    ..record()
+                },
+            )),
+        };
+        drop(empty);
+
+        let lines = String::from_utf8(lines.0.lock().unwrap().clone()).unwrap();
+        let lines = lines.lines().collect::<Vec<_>>();
+        assert!(lines[0].contains(r#"HTTP/1.1" 200 5 "#), "{}", lines[0]);
+        assert!(lines[1].contains(r#"HTTP/1.1" 200 - "#), "{}", lines[1]);
+    }
+
+    #[test]
+    fn invalid_format_rejected() {
+        assert!(AccessLogFormat::parse("%z").is_err());
+        assert!(AccessLogFormat::parse("%{Referer}x").is_err());
+        assert!(AccessLogFormat::parse("trailing %").is_err());
+    }
+}
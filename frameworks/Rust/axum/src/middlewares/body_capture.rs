@@ -0,0 +1,185 @@
+use std::sync::LazyLock;
+
+use anyhow::anyhow;
+use axum::{
+    body::{to_bytes, Body, Bytes, HttpBody},
+    extract::Request,
+    http::{header, HeaderMap},
+    middleware::Next,
+    response::Response,
+};
+use tracing::{debug, enabled, Level};
+
+use crate::{
+    config::config,
+    error::{AppError, AppResult},
+    utils::redact::REDACTOR,
+};
+
+/// Default max size of captured bodies, 16 KiB.
+pub const DEFAULT_BODY_LIMIT: usize = 16 * 1024;
+
+/// Max size of captured bodies, read from `log.body_limit`.
+static BODY_LIMIT: LazyLock<usize> = LazyLock::new(|| config().log.body_limit.0);
+
+/// Middleware for logging request and response bodies at debug level.
+///
+/// Only bodies with a textual content type and a known length not larger
+/// than `log.body_limit` are buffered, others are passed through
+/// untouched. JSON bodies are pretty printed, and all bodies are redacted
+/// before they are logged.
+pub async fn capture_body(req: Request, next: Next) -> AppResult<Response> {
+    if !enabled!(Level::DEBUG) {
+        return Ok(next.run(req).await);
+    }
+
+    let (parts, body) = req.into_parts();
+    let body = match capturable(&parts.headers, &body) {
+        Ok(()) => {
+            let bytes = to_bytes(body, *BODY_LIMIT)
+                .await
+                .map_err(|err| anyhow!(err).context("failed to buffer request body"))?;
+            log_body("request", &parts.headers, &bytes);
+            Body::from(bytes)
+        }
+        Err(reason) => {
+            debug!("request body skipped: {reason}");
+            body
+        }
+    };
+    let res = next.run(Request::from_parts(parts, body)).await;
+
+    let (parts, body) = res.into_parts();
+    let body = match capturable(&parts.headers, &body) {
+        Ok(()) => {
+            let bytes = to_bytes(body, *BODY_LIMIT).await.map_err(|err| {
+                AppError::Any(anyhow!(err).context("failed to buffer response body"))
+            })?;
+            log_body("response", &parts.headers, &bytes);
+            Body::from(bytes)
+        }
+        Err(reason) => {
+            debug!("response body skipped: {reason}");
+            body
+        }
+    };
+    Ok(Response::from_parts(parts, body))
+}
+
+/// Check whether a body can be buffered without breaking it.
+fn capturable(headers: &HeaderMap, body: &Body) -> Result<(), &'static str> {
+    if body.is_end_stream() {
+        return Err("empty");
+    }
+    if headers.contains_key(header::CONTENT_ENCODING) {
+        return Err("encoded");
+    }
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    if !is_textual(content_type) {
+        return Err("binary");
+    }
+    let length = body.size_hint().exact().or_else(|| {
+        headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok())
+    });
+    match length {
+        None => Err("streaming"),
+        Some(length) if length > *BODY_LIMIT as u64 => Err("too large"),
+        Some(_) => Ok(()),
+    }
+}
+
+fn is_textual(content_type: &str) -> bool {
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    essence.starts_with("text/")
+        || essence.ends_with("/json")
+        || essence.ends_with("+json")
+        || essence.ends_with("/xml")
+        || essence.ends_with("+xml")
+        || essence == "application/x-www-form-urlencoded"
+        || essence == "application/javascript"
+}
+
+fn log_body(kind: &str, headers: &HeaderMap, bytes: &Bytes) {
+    let body = format_body(headers, bytes);
+    debug!("{kind} body:\n{body}");
+}
+
+/// Pretty print JSON bodies, redacting sensitive fields before formatting.
+fn format_body(headers: &HeaderMap, bytes: &Bytes) -> String {
+    let is_json = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| {
+            let essence = value.split(';').next().unwrap_or_default().trim();
+            essence.ends_with("/json") || essence.ends_with("+json")
+        });
+    if is_json {
+        if let Ok(mut value) = serde_json::from_slice::<serde_json::Value>(bytes) {
+            REDACTOR.json(&mut value);
+            if let Ok(pretty) = serde_json::to_string_pretty(&value) {
+                return pretty;
+            }
+        }
+    }
+    REDACTOR.text(&String::from_utf8_lossy(bytes)).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    fn headers(content_type: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+        headers
+    }
+
+    #[test]
+    fn capturable_works() {
+        let json = headers("application/json");
+        assert_eq!(capturable(&json, &Body::from("{}")), Ok(()));
+        assert_eq!(capturable(&json, &Body::empty()), Err("empty"));
+        assert_eq!(
+            capturable(&headers("image/png"), &Body::from("png")),
+            Err("binary")
+        );
+        let large = "a".repeat(DEFAULT_BODY_LIMIT + 1);
+        assert_eq!(capturable(&json, &Body::from(large)), Err("too large"));
+        let stream = Body::from_stream(futures_util::stream::iter([Ok::<_, std::io::Error>(
+            Bytes::from("{}"),
+        )]));
+        assert_eq!(capturable(&json, &stream), Err("streaming"));
+    }
+
+    #[test]
+    fn textual_content_type() {
+        assert!(is_textual("application/json; charset=utf-8"));
+        assert!(is_textual("application/problem+json"));
+        assert!(is_textual("text/plain"));
+        assert!(!is_textual("application/octet-stream"));
+        assert!(!is_textual(""));
+    }
+
+    #[test]
+    fn json_body_pretty_and_redacted() {
+        let body = Bytes::from(r#"{"username":"xfy","password":"123456"}"#);
+        let formatted = format_body(&headers("application/json"), &body);
+        assert_eq!(
+            formatted,
+            "{\n  \"password\": \"[REDACTED]\",\n  \"username\": \"xfy\"\n}"
+        );
+    }
+}
@@ -0,0 +1,148 @@
+use std::{collections::HashMap, sync::Arc};
+
+use axum::{
+    body::Body,
+    extract::{DefaultBodyLimit, MatchedPath, Request, State},
+    http::header,
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    Router,
+};
+use http_body_util::Limited;
+
+use crate::{config::BodyLimitConfig, error::AppError};
+
+/// Same as the default limit of axum extractors.
+pub const DEFAULT_BODY_LIMIT: usize = 2 * 1024 * 1024;
+
+/// Maximum request body size, globally and per route template.
+#[derive(Debug, Clone)]
+pub struct BodyLimit {
+    limit: usize,
+    routes: HashMap<String, usize>,
+}
+
+impl Default for BodyLimit {
+    fn default() -> Self {
+        Self::new(DEFAULT_BODY_LIMIT)
+    }
+}
+
+impl BodyLimit {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            routes: HashMap::new(),
+        }
+    }
+
+    /// Override the limit of a route template, e.g. `/v1/user/regist`.
+    pub fn route(mut self, route: impl Into<String>, limit: usize) -> Self {
+        self.routes.insert(route.into(), limit);
+        self
+    }
+
+    pub fn from_config(config: &BodyLimitConfig) -> Self {
+        config
+            .routes
+            .iter()
+            .fold(Self::new(config.limit.0), |limit, (route, size)| {
+                limit.route(route, size.0)
+            })
+    }
+
+    fn get(&self, route: Option<&str>) -> usize {
+        route
+            .and_then(|route| self.routes.get(route))
+            .copied()
+            .unwrap_or(self.limit)
+    }
+}
+
+/// Middleware for limiting the request body size.
+///
+/// Requests declaring a larger `Content-Length` are rejected before the body
+/// is read, streaming bodies are cut off once they exceed the limit. Both get
+/// a 413 envelope.
+pub async fn limit_body(State(limit): State<Arc<BodyLimit>>, req: Request, next: Next) -> Response {
+    let limit = limit.get(
+        req.extensions()
+            .get::<MatchedPath>()
+            .map(MatchedPath::as_str),
+    );
+    let length = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok());
+    if length.is_some_and(|length| length > limit) {
+        return AppError::PayloadTooLarge(format!("Request body exceeds {limit} bytes").into())
+            .into_response();
+    }
+    let req = req.map(|body| Body::new(Limited::new(body, limit)));
+    next.run(req).await
+}
+
+/// Enforce `limit` on every route of `router`, replacing the default limit
+/// of axum extractors.
+pub fn body_limit_route(router: Router, limit: BodyLimit) -> Router {
+    router
+        .layer(middleware::from_fn_with_state(Arc::new(limit), limit_body))
+        .layer(DefaultBodyLimit::disable())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{http::StatusCode, routing::post};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::{routes::admin::LogLevel, utils::validator::ValidatedJson};
+
+    fn app() -> Router {
+        let router = Router::new()
+            .route(
+                "/json",
+                post(|ValidatedJson(level): ValidatedJson<LogLevel>| async move { level.filter }),
+            )
+            .route(
+                "/upload",
+                post(|body: String| async move { body.len().to_string() }),
+            );
+        body_limit_route(router, BodyLimit::new(16).route("/upload", 32))
+    }
+
+    async fn send(uri: &str, body: Body, length: Option<usize>) -> Response {
+        let mut req = Request::post(uri).header(header::CONTENT_TYPE, "application/json");
+        if let Some(length) = length {
+            req = req.header(header::CONTENT_LENGTH, length);
+        }
+        app().oneshot(req.body(body).unwrap()).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn reject_large_body() {
+        let res = send("/upload", Body::from("a".repeat(20)), Some(20)).await;
+        assert_eq!(res.status(), StatusCode::OK, "route limit overrides global");
+
+        let res = send("/upload", Body::from("a".repeat(40)), Some(40)).await;
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        // Without `Content-Length` the extractor hits the limit.
+        let stream = futures_util::stream::iter([Ok::<_, std::io::Error>("a".repeat(40))]);
+        let res = send("/upload", Body::from_stream(stream), None).await;
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let stream = futures_util::stream::iter([Ok::<_, std::io::Error>(format!(
+            r#"{{"filter":"{}"}}"#,
+            "a".repeat(20)
+        ))]);
+        let res = send("/json", Body::from_stream(stream), None).await;
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["code"], 1006);
+    }
+}
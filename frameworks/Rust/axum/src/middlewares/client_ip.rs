@@ -0,0 +1,285 @@
+use std::{
+    fmt::{self, Display},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    str::FromStr,
+    sync::Arc,
+};
+
+use anyhow::Context;
+use axum::{
+    extract::{ConnectInfo, FromRequestParts, Request, State},
+    http::{header, request::Parts, HeaderMap, HeaderName},
+    middleware::{self, Next},
+    response::Response,
+    Router,
+};
+use ipnet::IpNet;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{
+    error::AppError,
+    utils::listener::{ConnectionInfo, PeerAddr},
+};
+
+/// Networks of the load balancers and reverse proxies in front of us.
+///
+/// Forwarding headers and PROXY protocol headers are only honoured when they
+/// come from one of these networks. Parsed from a comma separated list of
+/// CIDRs or addresses, e.g. `10.0.0.0/8,127.0.0.1`.
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies(Vec<IpNet>);
+
+impl TrustedProxies {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        self.0.iter().any(|net| net.contains(&ip))
+    }
+
+    /// Resolve the address of the original client.
+    ///
+    /// When `peer` is trusted, the forwarding chain is walked from right to
+    /// left and the first untrusted hop is the client. `Forwarded` takes
+    /// precedence over `X-Forwarded-For`, `X-Real-IP` is used only when
+    /// neither is present.
+    pub fn resolve(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        let peer = peer.to_canonical();
+        if !self.contains(peer) {
+            return peer;
+        }
+        self.resolve_forwarded(peer, headers)
+    }
+
+    /// Resolve the address of the original client behind the trusted
+    /// `proxy`.
+    pub fn resolve_forwarded(&self, proxy: IpAddr, headers: &HeaderMap) -> IpAddr {
+        let chain = forwarded_chain(headers);
+        if chain.is_empty() {
+            return headers
+                .get("x-real-ip")
+                .and_then(|value| value.to_str().ok())
+                .and_then(parse_node)
+                .unwrap_or(proxy);
+        }
+        let mut client = proxy;
+        for hop in chain.iter().rev() {
+            // Obfuscated or malformed hops can not be traced any further.
+            let Some(ip) = hop else {
+                break;
+            };
+            client = *ip;
+            if !self.contains(client) {
+                break;
+            }
+        }
+        client
+    }
+}
+
+impl FromStr for TrustedProxies {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(|item| {
+                item.parse::<IpNet>()
+                    .or_else(|_| item.parse::<IpAddr>().map(IpNet::from))
+                    .with_context(|| format!("invalid CIDR `{item}`"))
+            })
+            .collect::<anyhow::Result<_>>()
+            .map(Self)
+    }
+}
+
+impl Serialize for TrustedProxies {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.0.iter().map(ToString::to_string))
+    }
+}
+
+/// A list of CIDRs or addresses, e.g. `["10.0.0.0/8", "127.0.0.1"]`.
+impl<'de> Deserialize<'de> for TrustedProxies {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Vec::<String>::deserialize(deserializer)?
+            .join(",")
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+/// Hops of `Forwarded` or `X-Forwarded-For`, from the client to the last
+/// proxy. `None` marks a hop without a usable address, e.g. `for=unknown`.
+fn forwarded_chain(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let values = |name: HeaderName| {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+    };
+    let forwarded = values(header::FORWARDED)
+        .map(|element| {
+            element
+                .split(';')
+                .filter_map(|pair| pair.trim().split_once('='))
+                .find(|(key, _)| key.eq_ignore_ascii_case("for"))
+                .and_then(|(_, node)| parse_node(node))
+        })
+        .collect::<Vec<_>>();
+    if !forwarded.is_empty() {
+        return forwarded;
+    }
+    values(HeaderName::from_static("x-forwarded-for"))
+        .map(parse_node)
+        .collect()
+}
+
+/// Parse a node like `192.0.2.60`, `"192.0.2.60:4711"` or
+/// `"[2001:db8::17]:4711"`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Some(rest) = node.strip_prefix('[') {
+        return rest.split_once(']')?.0.parse().ok();
+    }
+    node.parse::<IpAddr>()
+        .or_else(|_| node.parse::<SocketAddr>().map(|addr| addr.ip()))
+        .ok()
+        .map(|ip| ip.to_canonical())
+}
+
+/// Address of the original client, resolved by [`resolve_client_ip`].
+///
+/// Use this instead of `ConnectInfo` to identify clients, the peer address
+/// is usually the load balancer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientIp(pub IpAddr);
+
+impl Display for ClientIp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl<S> FromRequestParts<S> for ClientIp
+where
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts
+            .extensions
+            .get::<ClientIp>()
+            .copied()
+            .ok_or_else(|| anyhow::anyhow!("client ip is not resolved").into())
+    }
+}
+
+/// Middleware for resolving [`ClientIp`] of each request.
+pub async fn resolve_client_ip(
+    State(proxies): State<Arc<TrustedProxies>>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    let peer = req
+        .extensions()
+        .get::<ConnectInfo<ConnectionInfo>>()
+        .map(|ConnectInfo(info)| info.peer);
+    let ip = match peer {
+        Some(PeerAddr::Tcp(addr)) => Some(proxies.resolve(addr.ip(), req.headers())),
+        // Only local processes can reach Unix sockets, e.g. nginx in front
+        // of us, so their forwarding headers are honoured.
+        Some(PeerAddr::Unix) => {
+            Some(proxies.resolve_forwarded(IpAddr::V4(Ipv4Addr::LOCALHOST), req.headers()))
+        }
+        None => None,
+    };
+    if let Some(ip) = ip {
+        req.extensions_mut().insert(ClientIp(ip));
+    }
+    next.run(req).await
+}
+
+/// Resolve [`ClientIp`] before any other middleware of `router` runs.
+pub fn client_ip_route(router: Router, proxies: TrustedProxies) -> Router {
+    router.layer(middleware::from_fn_with_state(
+        Arc::new(proxies),
+        resolve_client_ip,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn resolve_forwarding_chain() {
+        let proxies = "10.0.0.0/8, 127.0.0.1".parse::<TrustedProxies>().unwrap();
+        let xff = headers(&[("x-forwarded-for", "1.1.1.1, 2.2.2.2, 10.0.0.2")]);
+
+        // Untrusted peers can not spoof their address.
+        assert_eq!(proxies.resolve(ip("8.8.8.8"), &xff), ip("8.8.8.8"));
+        // The right most untrusted hop wins over the spoofable left ones.
+        assert_eq!(proxies.resolve(ip("10.0.0.1"), &xff), ip("2.2.2.2"));
+        assert_eq!(
+            proxies.resolve(ip("::ffff:127.0.0.1"), &xff),
+            ip("2.2.2.2"),
+            "ipv4 mapped peer"
+        );
+
+        let forwarded = headers(&[
+            ("forwarded", r#"for="[2001:db8::17]:4711";proto=https"#),
+            ("forwarded", "for=10.0.0.3"),
+            ("x-forwarded-for", "3.3.3.3"),
+        ]);
+        assert_eq!(
+            proxies.resolve(ip("10.0.0.1"), &forwarded),
+            ip("2001:db8::17")
+        );
+
+        let hidden = headers(&[("forwarded", "for=1.1.1.1, for=_hidden, for=10.0.0.3")]);
+        assert_eq!(proxies.resolve(ip("10.0.0.1"), &hidden), ip("10.0.0.3"));
+
+        assert_eq!(
+            proxies.resolve_forwarded(ip("127.0.0.1"), &xff),
+            ip("2.2.2.2"),
+            "unix socket peer"
+        );
+
+        let real_ip = headers(&[("x-real-ip", "4.4.4.4")]);
+        assert_eq!(proxies.resolve(ip("10.0.0.1"), &real_ip), ip("4.4.4.4"));
+        assert_eq!(
+            proxies.resolve(ip("10.0.0.1"), &HeaderMap::new()),
+            ip("10.0.0.1")
+        );
+    }
+
+    #[test]
+    fn parse_trusted_proxies() {
+        assert!("".parse::<TrustedProxies>().unwrap().is_empty());
+        let proxies = "192.168.0.0/16,::1".parse::<TrustedProxies>().unwrap();
+        assert!(proxies.contains(ip("192.168.1.1")));
+        assert!(proxies.contains(ip("::1")));
+        assert!(!proxies.contains(ip("10.0.0.1")));
+        assert!("10.0.0.0/33".parse::<TrustedProxies>().is_err());
+    }
+}
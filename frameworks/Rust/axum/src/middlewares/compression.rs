@@ -0,0 +1,370 @@
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::{bail, Context};
+use axum::{
+    body::Body,
+    extract::{MatchedPath, Request, State},
+    http::{header, Extensions, HeaderMap, HeaderValue, StatusCode, Version},
+    middleware::{self, Next},
+    response::Response,
+    Router,
+};
+use http_body_util::Limited;
+use tower_http::{
+    compression::{
+        predicate::{NotForContentType, SizeAbove},
+        CompressionLayer, CompressionLevel, Predicate,
+    },
+    decompression::RequestDecompressionLayer,
+};
+
+use crate::config::CompressionConfig;
+
+/// Response compression and request decompression settings.
+#[derive(Debug, Clone)]
+pub struct Compression {
+    /// Compress responses of routes without an override.
+    pub enabled: bool,
+    pub gzip: bool,
+    pub br: bool,
+    pub zstd: bool,
+    pub level: CompressionLevel,
+    /// Responses smaller than this are sent as is.
+    pub min_size: u16,
+    /// Prefixes of compressible content types, e.g. `text/`.
+    pub content_types: Vec<String>,
+    /// Largest request body after decompression.
+    pub decompression_limit: usize,
+    /// Overrides of route templates, e.g. `/json`.
+    pub routes: HashMap<String, bool>,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self::from_config(&CompressionConfig::default()).expect("default config is valid")
+    }
+}
+
+impl Compression {
+    pub fn from_config(config: &CompressionConfig) -> anyhow::Result<Self> {
+        for algorithm in &config.algorithms {
+            if !matches!(algorithm.as_str(), "gzip" | "br" | "zstd") {
+                bail!("unknown compression.algorithms `{algorithm}`, expect gzip, br or zstd");
+            }
+        }
+        let has = |name: &str| config.algorithms.iter().any(|algorithm| algorithm == name);
+        let level = match config.level.as_str() {
+            "fastest" => CompressionLevel::Fastest,
+            "default" => CompressionLevel::Default,
+            "best" => CompressionLevel::Best,
+            precise => CompressionLevel::Precise(precise.parse().with_context(|| {
+                format!("invalid compression.level `{precise}`, expect fastest, default, best or a number")
+            })?),
+        };
+        Ok(Self {
+            enabled: config.enabled,
+            gzip: has("gzip"),
+            br: has("br"),
+            zstd: has("zstd"),
+            level,
+            min_size: u16::try_from(config.min_size.0)
+                .context("compression.min_size must be less than 64k")?,
+            content_types: config.types.clone(),
+            decompression_limit: config.decompression_limit.0,
+            routes: config.routes.clone().into_iter().collect(),
+        })
+    }
+
+    fn is_compressible(&self, headers: &HeaderMap) -> bool {
+        let Some(content_type) = headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+        else {
+            return false;
+        };
+        self.content_types
+            .iter()
+            .any(|prefix| content_type.starts_with(prefix.as_str()))
+    }
+}
+
+/// Whether the response of a route is compressed, inserted into response
+/// extensions of routes with an override.
+#[derive(Debug, Clone, Copy)]
+struct CompressRoute(bool);
+
+/// Marks requests whose body was sent with a `Content-Encoding`.
+#[derive(Debug, Clone, Copy)]
+struct Encoded;
+
+/// Middleware for applying the per route compression override.
+async fn compress_route(
+    State(compression): State<Arc<Compression>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let enabled = req
+        .extensions()
+        .get::<MatchedPath>()
+        .and_then(|route| compression.routes.get(route.as_str()))
+        .copied();
+    let mut res = next.run(req).await;
+    if let Some(enabled) = enabled {
+        res.extensions_mut().insert(CompressRoute(enabled));
+    }
+    res
+}
+
+async fn mark_encoded(mut req: Request, next: Next) -> Response {
+    if req.headers().contains_key(header::CONTENT_ENCODING) {
+        req.extensions_mut().insert(Encoded);
+    }
+    next.run(req).await
+}
+
+/// Middleware for capping the size of decompressed request bodies, so a small
+/// compressed payload can not expand without bound.
+async fn limit_decompressed(State(limit): State<usize>, req: Request, next: Next) -> Response {
+    if req.extensions().get::<Encoded>().is_none() {
+        return next.run(req).await;
+    }
+    next.run(req.map(|body| Body::new(Limited::new(body, limit))))
+        .await
+}
+
+/// Codings a response may be compressed with.
+const CODINGS: [&str; 3] = ["gzip", "br", "zstd"];
+
+/// Middleware for keeping strong `ETag`s distinct per representation.
+///
+/// The tag is computed before compression, so a compressed response gets the
+/// coding appended, e.g. `"abc-gzip"`. The suffix is stripped from
+/// `If-None-Match` and `If-Match` again, so the routes only see their own
+/// tags, and put back on the `304` answering it.
+async fn etag_coding(mut req: Request, next: Next) -> Response {
+    let mut stripped = None;
+    for name in [header::IF_NONE_MATCH, header::IF_MATCH] {
+        let Some(value) = req.headers().get(&name).and_then(|v| v.to_str().ok()) else {
+            continue;
+        };
+        let mut value = value.to_string();
+        for coding in CODINGS {
+            let suffix = format!("-{coding}\"");
+            if value.contains(&suffix) {
+                value = value.replace(&suffix, "\"");
+                stripped = Some(coding);
+            }
+        }
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            req.headers_mut().insert(name, value);
+        }
+    }
+
+    let mut res = next.run(req).await;
+    let coding = match res.headers().get(header::CONTENT_ENCODING) {
+        Some(coding) => coding
+            .to_str()
+            .ok()
+            .filter(|coding| CODINGS.contains(coding)),
+        None if res.status() == StatusCode::NOT_MODIFIED => stripped,
+        None => None,
+    };
+    let etag = res
+        .headers()
+        .get(header::ETAG)
+        .and_then(|etag| etag.to_str().ok())
+        // Weak tags already allow any representation.
+        .filter(|etag| etag.starts_with('"'));
+    if let (Some(coding), Some(etag)) = (coding, etag) {
+        let etag = format!("{}-{coding}\"", etag.trim_end_matches('"'));
+        if let Ok(etag) = HeaderValue::from_str(&etag) {
+            res.headers_mut().insert(header::ETAG, etag);
+        }
+    }
+    res
+}
+
+/// Compress responses and decompress requests of `router`.
+///
+/// Responses are compressed with the algorithm negotiated by
+/// `Accept-Encoding` when they are larger than `min_size` and have one of
+/// `content_types`. Like the default predicate of tower-http, gRPC, images
+/// and server-sent events are never compressed. Requests with an unsupported
+/// `Content-Encoding` get a 415. Strong `ETag`s of compressed responses name
+/// the coding, see [`etag_coding`].
+pub fn compression_route(router: Router, compression: Compression) -> Router {
+    let compression = Arc::new(compression);
+    let predicate = {
+        let compression = compression.clone();
+        move |_: StatusCode, _: Version, headers: &HeaderMap, extensions: &Extensions| {
+            extensions
+                .get::<CompressRoute>()
+                .map_or(compression.enabled, |CompressRoute(enabled)| *enabled)
+                && compression.is_compressible(headers)
+        }
+    };
+    let layer = CompressionLayer::new()
+        .gzip(compression.gzip)
+        .br(compression.br)
+        .zstd(compression.zstd)
+        .no_deflate()
+        .quality(compression.level)
+        .compress_when(
+            SizeAbove::new(compression.min_size)
+                .and(NotForContentType::GRPC)
+                .and(NotForContentType::IMAGES)
+                .and(NotForContentType::SSE)
+                .and(predicate),
+        );
+    let decompression = RequestDecompressionLayer::new()
+        .gzip(compression.gzip)
+        .br(compression.br)
+        .zstd(compression.zstd)
+        .no_deflate();
+
+    let router = router.layer(middleware::from_fn_with_state(
+        compression.decompression_limit,
+        limit_decompressed,
+    ));
+    let router = router
+        .layer(decompression)
+        .layer(middleware::from_fn(mark_encoded));
+    let router = if compression.routes.is_empty() {
+        router
+    } else {
+        router.layer(middleware::from_fn_with_state(
+            compression.clone(),
+            compress_route,
+        ))
+    };
+    router.layer(layer).layer(middleware::from_fn(etag_coding))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+
+    use axum::routing::{get, post};
+    use flate2::{read::GzDecoder, write::GzEncoder};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::{
+        middlewares::body_limit::{body_limit_route, BodyLimit},
+        routes::{admin::LogLevel, test_app::TestApp},
+        utils::validator::ValidatedJson,
+    };
+
+    fn app() -> Router {
+        let large = || async { axum::Json(vec!["phthonus"; 512]) };
+        let router = Router::new()
+            .route("/json", get(large))
+            .route("/plain", get(large))
+            .route("/small", get(|| async { axum::Json("small") }))
+            .route(
+                "/events",
+                get(|| async {
+                    let events = "data: phthonus\n\n".repeat(512);
+                    ([(header::CONTENT_TYPE, "text/event-stream")], events)
+                }),
+            )
+            .route(
+                "/upload",
+                post(|ValidatedJson(level): ValidatedJson<LogLevel>| async move { level.filter }),
+            );
+        let router = body_limit_route(router, BodyLimit::default());
+        let mut compression = Compression {
+            decompression_limit: 1024,
+            ..Default::default()
+        };
+        compression.routes.insert("/plain".into(), false);
+        compression_route(router, compression)
+    }
+
+    async fn get_encoding(uri: &str) -> Option<String> {
+        let req = Request::get(uri)
+            .header(header::ACCEPT_ENCODING, "gzip")
+            .body(Body::empty())
+            .unwrap();
+        let res = app().oneshot(req).await.unwrap();
+        let encoding = res
+            .headers()
+            .get(header::CONTENT_ENCODING)
+            .map(|value| value.to_str().unwrap().to_string());
+        if encoding.is_some() {
+            let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let mut json = String::new();
+            GzDecoder::new(&body[..]).read_to_string(&mut json).unwrap();
+            assert!(json.starts_with(r#"["phthonus""#));
+        }
+        encoding
+    }
+
+    #[tokio::test]
+    async fn compress_response() {
+        assert_eq!(get_encoding("/json").await.as_deref(), Some("gzip"));
+        assert_eq!(get_encoding("/plain").await, None, "route override");
+        assert_eq!(get_encoding("/small").await, None, "below min size");
+        assert_eq!(get_encoding("/events").await, None, "server-sent events");
+    }
+
+    #[tokio::test]
+    async fn decompress_request_with_limit() {
+        let gzip = |json: String| {
+            let mut encoder = GzEncoder::new(vec![], flate2::Compression::default());
+            encoder.write_all(json.as_bytes()).unwrap();
+            encoder.finish().unwrap()
+        };
+        let upload = |body: Vec<u8>| {
+            let req = Request::post("/upload")
+                .header(header::CONTENT_TYPE, "application/json")
+                .header(header::CONTENT_ENCODING, "gzip")
+                .body(Body::from(body))
+                .unwrap();
+            app().oneshot(req)
+        };
+
+        let res = upload(gzip(r#"{"filter":"debug"}"#.into())).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // A few dozen bytes on the wire, far larger once decompressed.
+        let bomb = gzip(format!(r#"{{"filter":"{}"}}"#, "a".repeat(64 * 1024)));
+        assert!(bomb.len() < 1024);
+        let res = upload(bomb).await.unwrap();
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn etag_names_coding() {
+        let app = TestApp::from_toml("[compression]\nmin_size = \"0\"");
+        let identity = app.get("/version").send().await;
+        identity.assert_ok();
+        let identity = identity.headers[header::ETAG].clone();
+
+        let gzip = app
+            .get("/version")
+            .header(header::ACCEPT_ENCODING, "gzip")
+            .send()
+            .await;
+        assert_eq!(gzip.headers[header::CONTENT_ENCODING], "gzip");
+        let etag = gzip.headers[header::ETAG].to_str().unwrap();
+        assert_eq!(
+            etag,
+            format!(
+                "{}-gzip\"",
+                identity.to_str().unwrap().trim_end_matches('"')
+            )
+        );
+
+        let res = app
+            .get("/version")
+            .header(header::ACCEPT_ENCODING, "gzip")
+            .header(header::IF_NONE_MATCH, etag)
+            .send()
+            .await;
+        assert_eq!(res.status, StatusCode::NOT_MODIFIED);
+        assert_eq!(res.headers[header::ETAG], etag);
+    }
+}
@@ -0,0 +1,136 @@
+use axum::{
+    body::Body,
+    extract::Request,
+    http::{header, Method, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use axum_extra::headers::{ETag, HeaderMapExt, IfModifiedSince, IfNoneMatch, LastModified};
+
+/// Headers kept on a `304 Not Modified`.
+const NOT_MODIFIED_HEADERS: [header::HeaderName; 5] = [
+    header::CACHE_CONTROL,
+    header::ETAG,
+    header::EXPIRES,
+    header::LAST_MODIFIED,
+    header::VARY,
+];
+
+/// Middleware for answering conditional `GET` and `HEAD` requests.
+///
+/// A `200` carrying an `ETag` or `Last-Modified` is turned into an empty
+/// `304` when it matches `If-None-Match`, or `If-Modified-Since` when the
+/// former is absent.
+pub async fn conditional_get(req: Request, next: Next) -> Response {
+    if !matches!(*req.method(), Method::GET | Method::HEAD) {
+        return next.run(req).await;
+    }
+    let if_none_match = req.headers().typed_get::<IfNoneMatch>();
+    let if_modified_since = req.headers().typed_get::<IfModifiedSince>();
+    if if_none_match.is_none() && if_modified_since.is_none() {
+        return next.run(req).await;
+    }
+
+    let res = next.run(req).await;
+    if res.status() != StatusCode::OK {
+        return res;
+    }
+    let headers = res.headers();
+    let not_modified = match (if_none_match, headers.typed_get::<ETag>()) {
+        (Some(if_none_match), Some(etag)) => !if_none_match.precondition_passes(&etag),
+        (Some(_), None) => false,
+        (None, _) => match (if_modified_since, headers.typed_get::<LastModified>()) {
+            (Some(since), Some(modified)) => !since.is_modified(modified.into()),
+            _ => false,
+        },
+    };
+    if !not_modified {
+        return res;
+    }
+
+    let mut not_modified = Response::new(Body::empty());
+    *not_modified.status_mut() = StatusCode::NOT_MODIFIED;
+    for name in NOT_MODIFIED_HEADERS {
+        for value in res.headers().get_all(&name) {
+            not_modified.headers_mut().append(&name, value.clone());
+        }
+    }
+    not_modified
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use axum::{http::HeaderValue, routing::get, Router};
+    use axum_extra::headers::{HeaderMap, IfMatch};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::{
+        consts::BUILD_INFO,
+        routes::health::version,
+        utils::etag::{strong_etag, Precondition},
+    };
+
+    async fn send(method: Method, headers: HeaderMap) -> Response {
+        let put =
+            |precondition: Precondition| async move { precondition.check(&strong_etag(b"info")) };
+        let app = Router::new()
+            .route("/", get(version).put(put))
+            .layer(axum::middleware::from_fn(conditional_get));
+        let mut req = Request::builder()
+            .method(method)
+            .uri("/")
+            .body(Body::empty())
+            .unwrap();
+        *req.headers_mut() = headers;
+        app.oneshot(req).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn not_modified() {
+        let res = send(Method::GET, HeaderMap::new()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let etag = res.headers()[header::ETAG].clone();
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::IF_NONE_MATCH, etag.clone());
+        let res = send(Method::GET, headers).await;
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(res.headers()[header::ETAG], etag);
+        assert!(!res.headers().contains_key(header::CONTENT_TYPE));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::IF_NONE_MATCH, HeaderValue::from_static("\"stale\""));
+        assert_eq!(send(Method::GET, headers).await.status(), StatusCode::OK);
+
+        let built = SystemTime::from(BUILD_INFO.build_time().unwrap());
+        let mut headers = HeaderMap::new();
+        headers.typed_insert(IfModifiedSince::from(built + Duration::from_secs(60)));
+        assert_eq!(
+            send(Method::GET, headers).await.status(),
+            StatusCode::NOT_MODIFIED
+        );
+        let mut headers = HeaderMap::new();
+        headers.typed_insert(IfModifiedSince::from(built - Duration::from_secs(60)));
+        assert_eq!(send(Method::GET, headers).await.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn precondition_failed() {
+        let mut headers = HeaderMap::new();
+        headers.typed_insert(IfMatch::from(strong_etag(b"info")));
+        assert_eq!(send(Method::PUT, headers).await.status(), StatusCode::OK);
+
+        let mut headers = HeaderMap::new();
+        headers.typed_insert(IfMatch::from(strong_etag(b"debug")));
+        let res = send(Method::PUT, headers).await;
+        assert_eq!(res.status(), StatusCode::PRECONDITION_FAILED);
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["code"], 1007);
+    }
+}
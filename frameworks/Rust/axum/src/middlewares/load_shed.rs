@@ -0,0 +1,321 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::{header, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use metrics::{counter, gauge};
+
+use super::{
+    metrics::{CONCURRENCY_LIMIT, REQUESTS_SHED_TOTAL},
+    UNLIMITED_ROUTES,
+};
+use crate::{config::ConcurrencyConfig, error::AppError};
+
+const DEFAULT_ADAPTIVE_MAX: usize = 1000;
+pub const DEFAULT_ADAPTIVE_MIN: usize = 10;
+pub const DEFAULT_ADAPTIVE_LATENCY: Duration = Duration::from_millis(500);
+pub const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(1);
+/// Multiplier of the limit when overload is detected.
+const BACKOFF: f64 = 0.9;
+
+/// Additive increase, multiplicative decrease of a concurrency limit.
+///
+/// Every request finishing within `latency` grows the limit by `1 / limit`,
+/// so about one per window of requests. A slow or failed request shrinks it
+/// by [`BACKOFF`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Aimd {
+    pub min: usize,
+    pub max: usize,
+    /// Latency above which the server is considered overloaded.
+    pub latency: Duration,
+}
+
+impl Aimd {
+    fn next(&self, limit: f64, latency: Duration, overloaded: bool) -> f64 {
+        let limit = if overloaded || latency > self.latency {
+            limit * BACKOFF
+        } else {
+            limit + 1.0 / limit
+        };
+        limit.clamp(self.min as f64, self.max as f64)
+    }
+}
+
+/// Concurrency limit of a scope, either fixed or adapted by [`Aimd`].
+#[derive(Debug)]
+pub struct Limiter {
+    scope: String,
+    in_flight: AtomicUsize,
+    limit: Mutex<f64>,
+    aimd: Option<Aimd>,
+}
+
+impl Limiter {
+    pub fn fixed(scope: impl Into<String>, limit: usize) -> Self {
+        Self::new(scope.into(), limit as f64, None)
+    }
+
+    /// Start from the maximum, the limit only shrinks once overloaded.
+    pub fn adaptive(scope: impl Into<String>, aimd: Aimd) -> Self {
+        Self::new(scope.into(), aimd.max as f64, Some(aimd))
+    }
+
+    fn new(scope: String, limit: f64, aimd: Option<Aimd>) -> Self {
+        gauge!(CONCURRENCY_LIMIT, "scope" => scope.clone()).set(limit.floor());
+        Self {
+            scope,
+            in_flight: AtomicUsize::new(0),
+            limit: Mutex::new(limit),
+            aimd,
+        }
+    }
+
+    pub fn limit(&self) -> usize {
+        *self.limit.lock().unwrap_or_else(|err| err.into_inner()) as usize
+    }
+
+    /// Take a slot, or `None` when the scope is at its limit.
+    fn try_acquire(self: &Arc<Self>) -> Option<Permit> {
+        let in_flight = self.in_flight.fetch_add(1, Ordering::AcqRel);
+        if in_flight >= self.limit() {
+            self.in_flight.fetch_sub(1, Ordering::AcqRel);
+            counter!(REQUESTS_SHED_TOTAL, "scope" => self.scope.clone()).increment(1);
+            return None;
+        }
+        Some(Permit {
+            limiter: self.clone(),
+            start: Instant::now(),
+            overloaded: true,
+        })
+    }
+
+    fn release(&self, latency: Duration, overloaded: bool) {
+        self.in_flight.fetch_sub(1, Ordering::AcqRel);
+        let Some(aimd) = self.aimd else {
+            return;
+        };
+        let mut limit = self.limit.lock().unwrap_or_else(|err| err.into_inner());
+        let next = aimd.next(*limit, latency, overloaded);
+        if next.floor() != limit.floor() {
+            gauge!(CONCURRENCY_LIMIT, "scope" => self.scope.clone()).set(next.floor());
+        }
+        *limit = next;
+    }
+}
+
+/// A slot of a [`Limiter`], released on drop. Requests dropped before they
+/// complete, e.g. cancelled by the client, count as overloaded.
+struct Permit {
+    limiter: Arc<Limiter>,
+    start: Instant,
+    overloaded: bool,
+}
+
+impl Permit {
+    fn complete(mut self, status: StatusCode) {
+        self.overloaded = matches!(
+            status,
+            StatusCode::REQUEST_TIMEOUT
+                | StatusCode::SERVICE_UNAVAILABLE
+                | StatusCode::GATEWAY_TIMEOUT
+        );
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.limiter.release(self.start.elapsed(), self.overloaded);
+    }
+}
+
+/// Global and per route concurrency limits.
+#[derive(Debug)]
+pub struct LoadShedder {
+    global: Option<Arc<Limiter>>,
+    /// Limits of route templates, e.g. `/json`.
+    routes: HashMap<String, Arc<Limiter>>,
+    retry_after: Duration,
+}
+
+impl LoadShedder {
+    pub fn new(global: Option<Limiter>) -> Self {
+        Self {
+            global: global.map(Arc::new),
+            routes: HashMap::new(),
+            retry_after: DEFAULT_RETRY_AFTER,
+        }
+    }
+
+    pub fn route(mut self, route: impl Into<String>, limit: usize) -> Self {
+        let route = route.into();
+        let limiter = Limiter::fixed(route.clone(), limit);
+        self.routes.insert(route, Arc::new(limiter));
+        self
+    }
+
+    pub fn retry_after(mut self, retry_after: Duration) -> Self {
+        self.retry_after = retry_after;
+        self
+    }
+
+    /// Build the limits from `concurrency`, returns `None` when no limit is
+    /// set.
+    ///
+    /// With `concurrency.adaptive` the global limit adapts between
+    /// `concurrency.adaptive_min` and `concurrency.limit`.
+    pub fn from_config(config: &ConcurrencyConfig) -> Option<Self> {
+        let global = if config.adaptive {
+            let aimd = Aimd {
+                min: config.adaptive_min,
+                max: config.limit.unwrap_or(DEFAULT_ADAPTIVE_MAX),
+                latency: config.adaptive_latency.0,
+            };
+            Some(Limiter::adaptive("*", aimd))
+        } else {
+            config.limit.map(|limit| Limiter::fixed("*", limit))
+        };
+        if global.is_none() && config.routes.is_empty() {
+            return None;
+        }
+        let mut shedder = Self::new(global).retry_after(config.retry_after.0);
+        for (route, limit) in &config.routes {
+            shedder = shedder.route(route, *limit);
+        }
+        Some(shedder)
+    }
+
+    fn reject(&self) -> Response {
+        let mut res = AppError::ServiceUnavailable("Server is overloaded".into()).into_response();
+        let secs = self.retry_after.as_secs() + u64::from(self.retry_after.subsec_nanos() > 0);
+        res.headers_mut()
+            .insert(header::RETRY_AFTER, HeaderValue::from(secs));
+        res
+    }
+}
+
+/// Middleware for rejecting requests beyond the concurrency limits with a
+/// 503 and `Retry-After`, instead of queueing them until they time out.
+///
+/// [`UNLIMITED_ROUTES`] only count against their own route limits.
+pub async fn load_shed(
+    State(shedder): State<Arc<LoadShedder>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let matched = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(MatchedPath::as_str);
+    let route = matched.and_then(|route| shedder.routes.get(route));
+    let route = match route.map(Limiter::try_acquire) {
+        Some(None) => return shedder.reject(),
+        permit => permit.flatten(),
+    };
+    let global = shedder
+        .global
+        .as_ref()
+        .filter(|_| !matched.is_some_and(|route| UNLIMITED_ROUTES.contains(&route)));
+    let global = match global.map(Limiter::try_acquire) {
+        Some(None) => return shedder.reject(),
+        permit => permit.flatten(),
+    };
+
+    let res = next.run(req).await;
+    for permit in [route, global].into_iter().flatten() {
+        permit.complete(res.status());
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, middleware, routing::get, Router};
+    use tokio::sync::Notify;
+    use tower::ServiceExt;
+
+    use super::*;
+
+    #[test]
+    fn aimd_works() {
+        let aimd = Aimd {
+            min: 2,
+            max: 10,
+            latency: Duration::from_millis(100),
+        };
+        let fast = Duration::from_millis(10);
+        assert_eq!(aimd.next(4.0, fast, false), 4.25);
+        assert_eq!(aimd.next(10.0, fast, false), 10.0, "capped at max");
+        assert_eq!(aimd.next(10.0, Duration::from_secs(1), false), 9.0);
+        assert_eq!(aimd.next(10.0, fast, true), 9.0);
+        assert_eq!(aimd.next(2.0, fast, true), 2.0, "capped at min");
+    }
+
+    #[tokio::test]
+    async fn shed_excess_requests() {
+        let notify = Arc::new(Notify::new());
+        let release = notify.clone();
+        let shedder = Arc::new(LoadShedder::new(None).route("/slow", 1));
+        let app = Router::new()
+            .route(
+                "/slow",
+                get(move || async move { release.notified().await }),
+            )
+            .layer(middleware::from_fn_with_state(shedder.clone(), load_shed));
+        let request = || Request::get("/slow").body(Body::empty()).unwrap();
+
+        let pending = tokio::spawn(app.clone().oneshot(request()));
+        while shedder.routes["/slow"].in_flight.load(Ordering::Acquire) == 0 {
+            tokio::task::yield_now().await;
+        }
+        let res = app.clone().oneshot(request()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(res.headers()[header::RETRY_AFTER], "1");
+
+        notify.notify_one();
+        assert_eq!(pending.await.unwrap().unwrap().status(), StatusCode::OK);
+        assert_eq!(shedder.routes["/slow"].in_flight.load(Ordering::Acquire), 0);
+    }
+
+    #[tokio::test]
+    async fn probes_bypass_global_limit() {
+        let notify = Arc::new(Notify::new());
+        let release = notify.clone();
+        let shedder = Arc::new(LoadShedder::new(Some(Limiter::fixed("*", 1))));
+        let app = Router::new()
+            .route(
+                "/slow",
+                get(move || async move { release.notified().await }),
+            )
+            .route("/healthz", get(|| async {}))
+            .layer(middleware::from_fn_with_state(shedder.clone(), load_shed));
+        let request = |uri| Request::get(uri).body(Body::empty()).unwrap();
+
+        let pending = tokio::spawn(app.clone().oneshot(request("/slow")));
+        let global = shedder.global.as_ref().unwrap();
+        while global.in_flight.load(Ordering::Acquire) == 0 {
+            tokio::task::yield_now().await;
+        }
+        let res = app.clone().oneshot(request("/slow")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let res = app.clone().oneshot(request("/healthz")).await.unwrap();
+        assert_eq!(
+            res.status(),
+            StatusCode::OK,
+            "probes answer while saturated"
+        );
+
+        notify.notify_one();
+        assert_eq!(pending.await.unwrap().unwrap().status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,136 @@
+use std::{sync::LazyLock, time::Instant};
+
+use axum::{
+    extract::{MatchedPath, Request},
+    http::Method,
+    middleware::{self, Next},
+    response::Response,
+    Router,
+};
+use metrics::{
+    counter, describe_counter, describe_gauge, describe_histogram, gauge, histogram, Unit,
+};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+
+use crate::config::MetricsConfig;
+
+pub const REQUESTS_TOTAL: &str = "http_requests_total";
+pub const REQUEST_DURATION_SECONDS: &str = "http_request_duration_seconds";
+pub const REQUESTS_IN_FLIGHT: &str = "http_requests_in_flight";
+pub const CONCURRENCY_LIMIT: &str = "http_concurrency_limit";
+pub const REQUESTS_SHED_TOTAL: &str = "http_requests_shed_total";
+pub const REQUESTS_TIMED_OUT_TOTAL: &str = "http_requests_timed_out_total";
+
+/// Latency buckets from 100μs to 10s.
+const LATENCY_BUCKETS: &[f64] = &[
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+    5.0, 10.0,
+];
+
+/// The global Prometheus recorder, installed on first use.
+pub static PROMETHEUS: LazyLock<PrometheusHandle> = LazyLock::new(|| {
+    let handle = PrometheusBuilder::new()
+        .set_buckets_for_metric(
+            Matcher::Full(REQUEST_DURATION_SECONDS.to_string()),
+            LATENCY_BUCKETS,
+        )
+        .expect("latency buckets are not empty")
+        .install_recorder()
+        .expect("failed to install Prometheus recorder");
+    describe_counter!(REQUESTS_TOTAL, "Total number of HTTP requests.");
+    describe_histogram!(
+        REQUEST_DURATION_SECONDS,
+        Unit::Seconds,
+        "HTTP request latency."
+    );
+    describe_gauge!(
+        REQUESTS_IN_FLIGHT,
+        "Number of HTTP requests being processed."
+    );
+    describe_gauge!(
+        CONCURRENCY_LIMIT,
+        "Current concurrency limit of the load shedder."
+    );
+    describe_counter!(
+        REQUESTS_SHED_TOTAL,
+        "Total number of HTTP requests rejected by the load shedder."
+    );
+    describe_counter!(
+        REQUESTS_TIMED_OUT_TOTAL,
+        "Total number of HTTP requests cancelled by their route timeout."
+    );
+    handle
+});
+
+/// Record request metrics for every route of `router`, including the
+/// fallback, unless `metrics.enabled` is `false`.
+pub fn metrics_route(router: Router, config: &MetricsConfig) -> Router {
+    if config.enabled {
+        // Install the recorder before the first request is recorded.
+        LazyLock::force(&PROMETHEUS);
+        router.layer(middleware::from_fn(track_metrics))
+    } else {
+        router
+    }
+}
+
+/// Middleware for recording request count, latency and in-flight requests.
+///
+/// Requests are labelled by the matched route template instead of the raw
+/// uri, so path parameters and unknown routes don't blow up cardinality.
+pub async fn track_metrics(req: Request, next: Next) -> Response {
+    let start = Instant::now();
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or("unmatched".to_string(), |path| path.as_str().to_string());
+    let method = method_label(req.method());
+
+    let in_flight = gauge!(REQUESTS_IN_FLIGHT);
+    in_flight.increment(1);
+    // Decrement on drop, the future may be cancelled by the timeout layer.
+    let _guard = InFlightGuard(in_flight);
+
+    let res = next.run(req).await;
+
+    let labels = [
+        ("route", route),
+        ("method", method.to_string()),
+        ("status", status_class(res.status().as_u16()).to_string()),
+    ];
+    counter!(REQUESTS_TOTAL, &labels).increment(1);
+    histogram!(REQUEST_DURATION_SECONDS, &labels).record(start.elapsed().as_secs_f64());
+    res
+}
+
+struct InFlightGuard(metrics::Gauge);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.decrement(1);
+    }
+}
+
+/// Keep method label bounded, unknown methods are grouped into `OTHER`.
+fn method_label(method: &Method) -> &'static str {
+    match *method {
+        Method::GET => "GET",
+        Method::POST => "POST",
+        Method::PUT => "PUT",
+        Method::DELETE => "DELETE",
+        Method::PATCH => "PATCH",
+        Method::HEAD => "HEAD",
+        Method::OPTIONS => "OPTIONS",
+        _ => "OTHER",
+    }
+}
+
+fn status_class(status: u16) -> &'static str {
+    match status {
+        100..=199 => "1xx",
+        200..=299 => "2xx",
+        300..=399 => "3xx",
+        400..=499 => "4xx",
+        _ => "5xx",
+    }
+}
@@ -1,29 +1,58 @@
-use std::{fmt::Display, time::Duration};
+use std::{fmt::Display, sync::LazyLock, time::Duration};
 
 use axum::{
     body::Bytes,
-    extract::Request,
+    extract::{ConnectInfo, Request},
     http::{HeaderMap, HeaderValue},
-    middleware::Next,
+    middleware::{self, Next},
     response::{IntoResponse, Response},
     Router,
 };
+use body_capture::capture_body;
+use client_ip::ClientIp;
 use tower_http::classify::ServerErrorsFailureClass;
 use tower_http::trace::TraceLayer;
-use tracing::{error, info, info_span, Span};
+use tracing::{debug, error, info, info_span, Span};
 
 use crate::{
-    consts::{NAME, VERSION},
+    config::LogConfig,
+    consts::{BUILD_INFO, NAME, VERSION},
     error::AppResult,
+    utils::{listener::ConnectionInfo, redact::REDACTOR, tls::ClientCert},
 };
 
+pub mod access_log;
+pub mod body_capture;
+pub mod body_limit;
+pub mod client_ip;
+pub mod compression;
+pub mod conditional;
+pub mod load_shed;
+pub mod metrics;
+pub mod rate_limit;
+pub mod security;
+pub mod timeout;
+pub mod versioning;
+
+/// Probe and scrape routes, exempt from the global concurrency limit and
+/// rate limit, so they keep answering while the server is saturated.
+pub const UNLIMITED_ROUTES: [&str; 3] = ["/healthz", "/readyz", "/metrics"];
+
+/// Git revision of [`BUILD_INFO`] as a header value.
+static REVISION: LazyLock<Option<HeaderValue>> = LazyLock::new(|| {
+    BUILD_INFO
+        .revision()
+        .and_then(|revision| HeaderValue::from_str(&revision).ok())
+});
+
 /// Middleware for adding version information to each response's headers.
 ///
 /// This middleware takes an incoming `Request` and a `Next` handler, which represents the
 /// subsequent middleware or route in the chain. It then asynchronously runs the next handler,
-/// obtaining the response. After receiving the response, it appends two headers:
+/// obtaining the response. After receiving the response, it appends three headers:
 /// - "Server": The name of the server extracted from the Cargo package name.
-/// - "S-Version": The version of the server extracted from the Cargo package version.
+/// - "Phthonus-Version": The version of the server extracted from the Cargo package version.
+/// - "Phthonus-Revision": The git commit the server was built from, when known.
 pub async fn add_version(
     req: Request<axum::body::Body>,
     next: Next,
@@ -32,6 +61,9 @@ pub async fn add_version(
     let headers = res.headers_mut();
     headers.append("Server", HeaderValue::from_static(NAME));
     headers.append("Phthonus-Version", HeaderValue::from_static(VERSION));
+    if let Some(revision) = &*REVISION {
+        headers.append("Phthonus-Revision", revision.clone());
+    }
     Ok(res)
 }
 
@@ -39,7 +71,11 @@ pub async fn add_version(
 ///
 /// This middleware will calculate each request latency
 /// and add request's information to each info_span.
-pub fn logging_route(router: Router) -> Router {
+/// Sensitive query parameters are masked before they are recorded, and the
+/// client address comes from [`ClientIp`]. Requests cancelled by
+/// [`timeout::timeout`] get `timed_out` recorded on their span.
+/// Bodies are logged at debug level when `log.body` is set.
+pub fn logging_route(router: Router, config: &LogConfig) -> Router {
     let make_span = |req: &Request<_>| {
         let unknown = &HeaderValue::from_static("Unknown");
         let empty = &HeaderValue::from_static("");
@@ -50,12 +86,39 @@ pub fn logging_route(router: Router) -> Router {
             .to_str()
             .unwrap_or("Unknown");
         let host = headers.get("Host").unwrap_or(empty).to_str().unwrap_or("");
-        info_span!("HTTP", method = ?req.method(), host, uri = ?req.uri(), ua)
+        let uri = REDACTOR.uri(req.uri());
+        let client_ip = req
+            .extensions()
+            .get::<ClientIp>()
+            .map_or_else(|| "-".to_string(), ToString::to_string);
+        let span = info_span!(
+            "HTTP",
+            method = ?req.method(),
+            host,
+            uri = %uri,
+            ua,
+            client_ip,
+            timed_out = tracing::field::Empty,
+            client_cert = tracing::field::Empty,
+        );
+        let client_cert = req
+            .extensions()
+            .get::<ConnectInfo<ConnectionInfo>>()
+            .and_then(|ConnectInfo(info)| info.client_cert.as_ref())
+            .and_then(ClientCert::common_name);
+        if let Some(client_cert) = client_cert {
+            span.record("client_cert", client_cert);
+        }
+        #[cfg(feature = "otel")]
+        crate::utils::otel::set_remote_parent(&span, headers);
+        span
     };
 
     let trace_layer = TraceLayer::new_for_http()
         .make_span_with(make_span)
-        .on_request(|_req: &Request<_>, _span: &Span| {})
+        .on_request(|req: &Request<_>, _span: &Span| {
+            debug!(headers = ?REDACTOR.headers(req.headers()), "request");
+        })
         .on_response(|res: &Response, latency: Duration, _span: &Span| {
             info!("{}", format_latency(latency, res.status()));
         })
@@ -67,9 +130,25 @@ pub fn logging_route(router: Router) -> Router {
             },
         );
 
+    let router = if config.body {
+        router.layer(middleware::from_fn(capture_body))
+    } else {
+        router
+    };
+    #[cfg(feature = "otel")]
+    let router = router.layer(middleware::from_fn(propagate_trace));
     router.layer(trace_layer)
 }
 
+/// Middleware for writing the W3C `traceparent` of the request span into
+/// the response headers, so clients can look up the exported trace.
+#[cfg(feature = "otel")]
+pub async fn propagate_trace(req: Request, next: Next) -> Response {
+    let mut res = next.run(req).await;
+    crate::utils::otel::inject_context(&Span::current(), res.headers_mut());
+    res
+}
+
 /// Format request latency and status message
 /// return a string
 fn format_latency(latency: Duration, status: impl Display) -> String {
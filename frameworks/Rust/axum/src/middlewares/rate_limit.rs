@@ -0,0 +1,520 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    future::Future,
+    pin::Pin,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Context};
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::{header, HeaderMap, HeaderValue},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use tracing::warn;
+
+use super::{client_ip::ClientIp, UNLIMITED_ROUTES};
+use crate::{
+    config::{RateLimitConfig, Secret},
+    error::AppError,
+    utils::{
+        duration::{parse_duration, HumanDuration},
+        jwt::decode_jwt,
+    },
+};
+
+/// Header carrying the API key of a client.
+pub const API_KEY_HEADER: &str = "x-api-key";
+
+/// Allow `burst` requests per `period`, refilled evenly over the period.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quota {
+    pub burst: u32,
+    pub period: Duration,
+}
+
+impl Quota {
+    /// Time to refill a single request, the GCRA emission interval.
+    fn emission_interval(&self) -> Duration {
+        self.period / self.burst
+    }
+}
+
+impl fmt::Display for Quota {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.burst, HumanDuration(self.period))
+    }
+}
+
+impl Serialize for Quota {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Quota {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+impl FromStr for Quota {
+    type Err = anyhow::Error;
+
+    /// Parse `<burst>/<period>`, e.g. `100/1s` or `5/m`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (burst, period) = s
+            .split_once('/')
+            .ok_or_else(|| anyhow!("invalid quota `{s}`, expect `<burst>/<period>`"))?;
+        let burst = burst
+            .trim()
+            .parse::<u32>()
+            .with_context(|| format!("invalid quota burst in `{s}`"))?;
+        let period = parse_duration(period)?;
+        if burst == 0 || period.is_zero() {
+            bail!("quota `{s}` must allow at least one request");
+        }
+        Ok(Self { burst, period })
+    }
+}
+
+/// Outcome of a rate limit check.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Decision {
+    pub allowed: bool,
+    pub limit: u32,
+    pub remaining: u32,
+    /// Time until the quota is fully refilled.
+    pub reset: Duration,
+    /// Time until the next request is allowed, zero when allowed.
+    pub retry_after: Duration,
+}
+
+/// Generic cell rate algorithm.
+///
+/// `tat` is the theoretical arrival time of the key, as an offset from an
+/// arbitrary epoch shared with `now`. Returns the decision and the new `tat`
+/// to store.
+pub fn gcra(tat: Option<Duration>, now: Duration, quota: &Quota) -> (Decision, Duration) {
+    let interval = quota.emission_interval();
+    // Latest arrival the bucket still has room for, `tat - tolerance` can
+    // underflow close to the epoch, so compare against `now + tolerance`.
+    let horizon = now + quota.period;
+    let tat = tat.unwrap_or(now).max(now);
+    let new_tat = tat + interval;
+
+    if new_tat > horizon {
+        let decision = Decision {
+            allowed: false,
+            limit: quota.burst,
+            remaining: 0,
+            reset: tat - now,
+            retry_after: new_tat - horizon,
+        };
+        return (decision, tat);
+    }
+    let remaining = ((horizon - new_tat).as_nanos() / interval.as_nanos().max(1)) as u32;
+    let decision = Decision {
+        allowed: true,
+        limit: quota.burst,
+        remaining: remaining.min(quota.burst - 1),
+        reset: new_tat - now,
+        retry_after: Duration::ZERO,
+    };
+    (decision, new_tat)
+}
+
+pub type StoreFuture<'a> = Pin<Box<dyn Future<Output = anyhow::Result<Decision>> + Send + 'a>>;
+
+/// Storage of rate limit state.
+///
+/// The in-memory store only limits a single instance, implement this trait
+/// on a shared backend such as Redis to limit across instances. A shared
+/// backend must apply [`gcra`] atomically.
+pub trait RateLimitStore: Send + Sync + 'static {
+    fn check<'a>(&'a self, key: &'a str, quota: &'a Quota) -> StoreFuture<'a>;
+}
+
+/// In-process store, the default backend.
+///
+/// Keys whose quota is fully refilled are dropped at most once per
+/// `prune_period`, so a check is O(1) apart from that sweep. Beyond
+/// `max_keys` new keys are not tracked and the limiter fails open for them.
+#[derive(Debug)]
+pub struct MemoryStore {
+    epoch: Instant,
+    max_keys: usize,
+    prune_period: Duration,
+    state: Mutex<MemoryState>,
+}
+
+#[derive(Debug, Default)]
+struct MemoryState {
+    tats: HashMap<String, Duration>,
+    last_prune: Duration,
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        Self::new(Self::MAX_KEYS, Self::PRUNE_PERIOD)
+    }
+}
+
+impl MemoryStore {
+    const MAX_KEYS: usize = 100_000;
+    const PRUNE_PERIOD: Duration = Duration::from_secs(10);
+
+    pub fn new(max_keys: usize, prune_period: Duration) -> Self {
+        Self {
+            epoch: Instant::now(),
+            max_keys,
+            prune_period,
+            state: Mutex::default(),
+        }
+    }
+}
+
+impl RateLimitStore for MemoryStore {
+    fn check<'a>(&'a self, key: &'a str, quota: &'a Quota) -> StoreFuture<'a> {
+        Box::pin(async move {
+            let now = self.epoch.elapsed();
+            let mut state = self
+                .state
+                .lock()
+                .map_err(|_| anyhow!("rate limit store lock poisoned"))?;
+            if now - state.last_prune >= self.prune_period {
+                state.tats.retain(|_, tat| *tat > now);
+                state.last_prune = now;
+            }
+            let tat = state.tats.get(key).copied();
+            if tat.is_none() && state.tats.len() >= self.max_keys {
+                bail!("rate limit store is full, {} keys", self.max_keys);
+            }
+            let (decision, tat) = gcra(tat, now, quota);
+            state.tats.insert(key.to_string(), tat);
+            Ok(decision)
+        })
+    }
+}
+
+/// What identifies a client.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyStrategy {
+    /// Client ip address, see [`ClientIp`].
+    Ip,
+    /// `sub` of a valid bearer JWT, falls back to the ip.
+    Sub,
+    /// `X-Api-Key` header listed in `rate_limit.api_keys`, falls back to the
+    /// ip.
+    ApiKey,
+    /// Listed API key, then `sub`, then ip.
+    Auto,
+}
+
+impl FromStr for KeyStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ip" => Ok(Self::Ip),
+            "sub" => Ok(Self::Sub),
+            "api_key" => Ok(Self::ApiKey),
+            "auto" => Ok(Self::Auto),
+            other => bail!("unknown rate limit key `{other}`, expect ip, sub, api_key or auto"),
+        }
+    }
+}
+
+pub struct RateLimiter {
+    store: Arc<dyn RateLimitStore>,
+    strategy: KeyStrategy,
+    /// Known API keys, unknown keys would let a client pick a fresh bucket
+    /// for every request.
+    api_keys: Vec<Secret>,
+    quota: Quota,
+    /// Quotas of route templates, e.g. `/v1/user/regist`.
+    routes: HashMap<String, Quota>,
+}
+
+impl RateLimiter {
+    pub fn new(quota: Quota) -> Self {
+        Self {
+            store: Arc::new(MemoryStore::default()),
+            strategy: KeyStrategy::Ip,
+            api_keys: vec![],
+            quota,
+            routes: HashMap::new(),
+        }
+    }
+
+    /// Use a shared backend instead of the in-memory store.
+    pub fn store(mut self, store: Arc<dyn RateLimitStore>) -> Self {
+        self.store = store;
+        self
+    }
+
+    pub fn strategy(mut self, strategy: KeyStrategy) -> Self {
+        self.strategy = strategy;
+        self
+    }
+
+    pub fn api_keys(mut self, api_keys: Vec<Secret>) -> Self {
+        self.api_keys = api_keys;
+        self
+    }
+
+    /// Override the quota of a route template.
+    pub fn route(mut self, route: impl Into<String>, quota: Quota) -> Self {
+        self.routes.insert(route.into(), quota);
+        self
+    }
+
+    /// Build the limiter from `rate_limit`, returns `None` when rate limiting
+    /// is disabled.
+    pub fn from_config(config: &RateLimitConfig) -> Option<Self> {
+        let mut limiter = Self::new(config.quota?)
+            .strategy(config.key)
+            .api_keys(config.api_keys.clone());
+        for (route, quota) in &config.routes {
+            limiter = limiter.route(route, *quota);
+        }
+        Some(limiter)
+    }
+
+    fn key(&self, req: &Request) -> String {
+        let headers = req.headers();
+        // Keyed by position, so the store never sees the key itself.
+        let api_key = || {
+            let key = headers.get(API_KEY_HEADER)?.as_bytes();
+            self.api_keys
+                .iter()
+                .position(|known| known.expose().as_bytes() == key)
+                .map(|index| format!("key:{index}"))
+        };
+        let sub = || {
+            headers
+                .get(header::AUTHORIZATION)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.strip_prefix("Bearer "))
+                .and_then(|token| decode_jwt(token).ok())
+                .map(|data| format!("sub:{}", data.claims.sub))
+        };
+        let ip = || {
+            req.extensions()
+                .get::<ClientIp>()
+                .map_or("ip:unknown".to_string(), |ip| format!("ip:{ip}"))
+        };
+        match self.strategy {
+            KeyStrategy::Ip => ip(),
+            KeyStrategy::Sub => sub().unwrap_or_else(ip),
+            KeyStrategy::ApiKey => api_key().unwrap_or_else(ip),
+            KeyStrategy::Auto => api_key().or_else(sub).unwrap_or_else(ip),
+        }
+    }
+}
+
+/// Middleware for limiting request rate of each client.
+///
+/// Every response carries `RateLimit-Limit`, `RateLimit-Remaining` and
+/// `RateLimit-Reset` headers, rejected requests get a 429 with `Retry-After`.
+/// The limiter fails open when the store is unavailable. [`UNLIMITED_ROUTES`]
+/// are only limited by their own route quotas.
+pub async fn rate_limit(
+    State(limiter): State<Arc<RateLimiter>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(MatchedPath::as_str);
+    let (quota, scope) = match route.and_then(|route| limiter.routes.get_key_value(route)) {
+        Some((route, quota)) => (quota, route.as_str()),
+        None if route.is_some_and(|route| UNLIMITED_ROUTES.contains(&route)) => {
+            return next.run(req).await;
+        }
+        None => (&limiter.quota, "*"),
+    };
+    let key = format!("{scope}|{}", limiter.key(&req));
+
+    let decision = match limiter.store.check(&key, quota).await {
+        Ok(decision) => decision,
+        Err(err) => {
+            warn!("rate limit store failed: {err:#}");
+            return next.run(req).await;
+        }
+    };
+    let mut res = if decision.allowed {
+        next.run(req).await
+    } else {
+        let mut res = AppError::TooManyRequests("Too many requests".into()).into_response();
+        res.headers_mut()
+            .insert(header::RETRY_AFTER, ceil_secs(decision.retry_after));
+        res
+    };
+    set_headers(res.headers_mut(), &decision);
+    res
+}
+
+fn set_headers(headers: &mut HeaderMap, decision: &Decision) {
+    headers.insert("ratelimit-limit", HeaderValue::from(decision.limit));
+    headers.insert("ratelimit-remaining", HeaderValue::from(decision.remaining));
+    headers.insert("ratelimit-reset", ceil_secs(decision.reset));
+}
+
+/// Headers count in whole seconds, round up so clients never retry early.
+fn ceil_secs(duration: Duration) -> HeaderValue {
+    let secs = duration.as_secs() + u64::from(duration.subsec_nanos() > 0);
+    HeaderValue::from(secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{HeaderName, StatusCode};
+
+    use super::*;
+    use crate::{
+        error::ErrorCode,
+        routes::test_app::TestApp,
+        utils::jwt::{encode_jwt, Claims},
+        AppState,
+    };
+
+    #[test]
+    fn parse_quota() {
+        let quota = "10/1m".parse::<Quota>().unwrap();
+        assert_eq!(
+            quota,
+            Quota {
+                burst: 10,
+                period: Duration::from_secs(60)
+            }
+        );
+        assert_eq!(quota.emission_interval(), Duration::from_secs(6));
+        assert!("0/1s".parse::<Quota>().is_err());
+        assert!("10".parse::<Quota>().is_err());
+    }
+
+    #[test]
+    fn gcra_allows_burst_then_limits() {
+        let quota = "3/3s".parse::<Quota>().unwrap();
+        let now = Duration::from_secs(1);
+        let mut tat = None;
+
+        for remaining in [2, 1, 0] {
+            let (decision, new_tat) = gcra(tat, now, &quota);
+            assert!(decision.allowed);
+            assert_eq!(decision.remaining, remaining);
+            tat = Some(new_tat);
+        }
+
+        let (decision, new_tat) = gcra(tat, now, &quota);
+        assert!(!decision.allowed);
+        assert_eq!(decision.retry_after, Duration::from_secs(1));
+        assert_eq!(decision.reset, Duration::from_secs(3));
+        assert_eq!(Some(new_tat), tat, "rejected requests don't consume quota");
+
+        // One request is refilled after one emission interval.
+        let later = now + Duration::from_secs(1);
+        let (decision, new_tat) = gcra(tat, later, &quota);
+        assert!(decision.allowed);
+        assert_eq!(decision.remaining, 0);
+        let (decision, _) = gcra(Some(new_tat), later, &quota);
+        assert!(!decision.allowed);
+    }
+
+    /// Records the keys and rejects every request.
+    #[derive(Default)]
+    struct RecordingStore(Mutex<Vec<String>>);
+
+    impl RateLimitStore for RecordingStore {
+        fn check<'a>(&'a self, key: &'a str, quota: &'a Quota) -> StoreFuture<'a> {
+            self.0.lock().unwrap().push(key.to_string());
+            let (mut decision, _) = gcra(None, Duration::ZERO, quota);
+            decision.allowed = false;
+            Box::pin(async move { Ok(decision) })
+        }
+    }
+
+    #[tokio::test]
+    async fn key_on_known_api_keys_only() {
+        let store = Arc::new(RecordingStore::default());
+        let state = AppState {
+            rate_limit_store: store.clone(),
+        };
+        let app = TestApp::with_state(
+            "[rate_limit]\nquota = \"10/1s\"\nkey = \"auto\"\napi_keys = [\"known\"]",
+            state,
+        );
+        for api_key in ["random-1", "random-2", "known"] {
+            app.get("/json")
+                .header(HeaderName::from_static(API_KEY_HEADER), api_key)
+                .send()
+                .await
+                .assert_error(StatusCode::TOO_MANY_REQUESTS, ErrorCode::TooManyRequests);
+        }
+        let token = encode_jwt(&Claims {
+            exp: usize::MAX,
+            iat: 0,
+            sub: "xfy".to_string(),
+        })
+        .unwrap();
+        app.get("/json").bearer(&token).send().await;
+        app.get("/json").bearer("forged").send().await;
+        assert_eq!(
+            *store.0.lock().unwrap(),
+            [
+                "*|ip:127.0.0.1",
+                "*|ip:127.0.0.1",
+                "*|key:0",
+                "*|sub:xfy",
+                "*|ip:127.0.0.1"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn memory_store_keeps_keys_apart() {
+        let store = MemoryStore::default();
+        let quota = "1/1m".parse::<Quota>().unwrap();
+        assert!(store.check("a", &quota).await.unwrap().allowed);
+        assert!(!store.check("a", &quota).await.unwrap().allowed);
+        assert!(store.check("b", &quota).await.unwrap().allowed);
+    }
+
+    #[tokio::test]
+    async fn memory_store_caps_keys() {
+        let store = MemoryStore::new(1, Duration::ZERO);
+        let quota = "1/10ms".parse::<Quota>().unwrap();
+        assert!(store.check("a", &quota).await.unwrap().allowed);
+        assert!(store.check("b", &quota).await.is_err(), "store is full");
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(
+            store.check("b", &quota).await.unwrap().allowed,
+            "refilled keys are pruned"
+        );
+    }
+
+    #[tokio::test]
+    async fn probes_are_not_limited() {
+        let app = TestApp::from_toml("[rate_limit]\nquota = \"1/1m\"");
+        app.get("/json").send().await.assert_ok();
+        app.get("/json")
+            .send()
+            .await
+            .assert_error(StatusCode::TOO_MANY_REQUESTS, ErrorCode::TooManyRequests);
+        for _ in 0..3 {
+            let res = app.get("/healthz").send().await;
+            assert_eq!(res.status, StatusCode::OK);
+            assert!(!res.headers.contains_key("ratelimit-limit"));
+        }
+    }
+}
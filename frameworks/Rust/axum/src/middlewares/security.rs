@@ -0,0 +1,120 @@
+use anyhow::{bail, Context};
+use axum::{
+    http::{header, HeaderValue, Method},
+    Router,
+};
+use tower_http::{
+    cors::{AllowHeaders, AllowOrigin, CorsLayer},
+    set_header::SetResponseHeaderLayer,
+};
+
+use crate::config::{CorsConfig, SecurityConfig};
+
+/// Build the CORS layer from `cors`, returns `None` when no origin is
+/// allowed.
+///
+/// Origins are a list, or `*` for any origin. `*` can not be listed with
+/// other origins or combined with credentials.
+pub fn cors_layer(config: &CorsConfig) -> anyhow::Result<Option<CorsLayer>> {
+    if config.origins.is_empty() {
+        return Ok(None);
+    }
+    if config.origins.len() > 1 && config.origins.iter().any(|origin| origin == "*") {
+        bail!("cors.origins can not list `*` with other origins");
+    }
+    let allow_origin = if config.origins == ["*"] {
+        if config.credentials {
+            bail!("cors.credentials can not be used with any origin");
+        }
+        AllowOrigin::any()
+    } else {
+        let origins = config
+            .origins
+            .iter()
+            .map(|origin| {
+                HeaderValue::from_str(origin)
+                    .with_context(|| format!("invalid cors.origins `{origin}`"))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        AllowOrigin::list(origins)
+    };
+    let methods = config
+        .methods
+        .iter()
+        .map(|method| {
+            Method::from_bytes(method.to_ascii_uppercase().as_bytes())
+                .with_context(|| format!("invalid cors.methods `{method}`"))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let mut cors = CorsLayer::new()
+        .allow_origin(allow_origin)
+        .allow_methods(methods)
+        .allow_headers(AllowHeaders::mirror_request())
+        .allow_credentials(config.credentials);
+    if let Some(max_age) = config.max_age {
+        cors = cors.max_age(max_age.0);
+    }
+    Ok(Some(cors))
+}
+
+/// Add the security headers to every response of `router`, unless
+/// `security.headers` is `false`.
+///
+/// An empty `security.hsts`, `security.csp` or `security.referrer_policy`
+/// omits the header. Headers set by a handler are kept.
+pub fn security_headers_route(router: Router, config: &SecurityConfig) -> anyhow::Result<Router> {
+    if !config.headers {
+        return Ok(router);
+    }
+    let headers = [
+        (
+            header::STRICT_TRANSPORT_SECURITY,
+            "security.hsts",
+            &config.hsts,
+        ),
+        (header::CONTENT_SECURITY_POLICY, "security.csp", &config.csp),
+        (
+            header::REFERRER_POLICY,
+            "security.referrer_policy",
+            &config.referrer_policy,
+        ),
+    ];
+    let mut router = router.layer(SetResponseHeaderLayer::if_not_present(
+        header::X_CONTENT_TYPE_OPTIONS,
+        HeaderValue::from_static("nosniff"),
+    ));
+    for (name, key, value) in headers {
+        if value.is_empty() {
+            continue;
+        }
+        let value =
+            HeaderValue::from_str(value).with_context(|| format!("invalid {key} `{value}`"))?;
+        router = router.layer(SetResponseHeaderLayer::if_not_present(name, value));
+    }
+    Ok(router)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn origins(origins: &[&str]) -> CorsConfig {
+        CorsConfig {
+            origins: origins.iter().map(|origin| origin.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn any_origin_stands_alone() {
+        assert!(cors_layer(&origins(&[])).unwrap().is_none());
+        assert!(cors_layer(&origins(&["*"])).unwrap().is_some());
+        assert!(cors_layer(&origins(&["https://a.example"]))
+            .unwrap()
+            .is_some());
+
+        let err = cors_layer(&origins(&["*", "https://a.example"])).unwrap_err();
+        assert!(format!("{err:#}").contains("cors.origins"), "{err:#}");
+    }
+}
@@ -0,0 +1,139 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use axum::{
+    extract::{MatchedPath, Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use metrics::counter;
+use tracing::{warn, Span};
+
+use super::metrics::REQUESTS_TIMED_OUT_TOTAL;
+use crate::{config::TimeoutConfig, error::AppError};
+
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Request timeouts, globally and per route template or route group.
+#[derive(Debug, Clone)]
+pub struct Timeouts {
+    timeout: Duration,
+    /// Timeouts of route templates, e.g. `/json`, or groups, e.g. `/v1/user/*`.
+    routes: HashMap<String, Duration>,
+}
+
+impl Timeouts {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            routes: HashMap::new(),
+        }
+    }
+
+    /// Override the timeout of a route template, e.g. `/json`, or of every
+    /// route under a group, e.g. `/v1/user/*`.
+    pub fn route(mut self, route: impl Into<String>, timeout: Duration) -> Self {
+        self.routes.insert(route.into(), timeout);
+        self
+    }
+
+    pub fn from_config(config: &TimeoutConfig) -> Self {
+        config
+            .routes
+            .iter()
+            .fold(Self::new(config.default.0), |timeouts, (route, timeout)| {
+                timeouts.route(route, timeout.0)
+            })
+    }
+
+    /// Timeout of the route, the route itself wins over the longest matching
+    /// group.
+    fn get(&self, route: Option<&str>) -> Duration {
+        let Some(route) = route else {
+            return self.timeout;
+        };
+        if let Some(timeout) = self.routes.get(route) {
+            return *timeout;
+        }
+        self.routes
+            .iter()
+            .filter_map(|(group, timeout)| {
+                let prefix = group.strip_suffix('*')?;
+                route
+                    .starts_with(prefix)
+                    .then_some((prefix.len(), *timeout))
+            })
+            .max_by_key(|(len, _)| *len)
+            .map_or(self.timeout, |(_, timeout)| timeout)
+    }
+}
+
+/// Middleware for cancelling handlers that run past the timeout of their
+/// route.
+///
+/// The request gets a 408 envelope, and the request span is marked with
+/// `timed_out` so the cancellation shows up in logs and traces.
+pub async fn timeout(State(timeouts): State<Arc<Timeouts>>, req: Request, next: Next) -> Response {
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|route| route.as_str().to_string());
+    let timeout = timeouts.get(route.as_deref());
+    match tokio::time::timeout(timeout, next.run(req)).await {
+        Ok(res) => res,
+        Err(_) => {
+            let route = route.unwrap_or_else(|| "unmatched".to_string());
+            Span::current().record("timed_out", true);
+            warn!(route, ?timeout, "request timed out");
+            counter!(REQUESTS_TIMED_OUT_TOTAL, "route" => route).increment(1);
+            AppError::RequestTimeout(format!("Request timed out after {timeout:?}").into())
+                .into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, http::StatusCode, middleware, routing::get, Router};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    #[test]
+    fn route_overrides_group() {
+        let timeouts = Timeouts::new(Duration::from_secs(15))
+            .route("/user/*", Duration::from_secs(30))
+            .route("/user/admin/*", Duration::from_secs(60))
+            .route("/user/login", Duration::from_secs(5));
+        assert_eq!(timeouts.get(None), Duration::from_secs(15));
+        assert_eq!(timeouts.get(Some("/json")), Duration::from_secs(15));
+        assert_eq!(timeouts.get(Some("/user/login")), Duration::from_secs(5));
+        assert_eq!(timeouts.get(Some("/user/regist")), Duration::from_secs(30));
+        assert_eq!(
+            timeouts.get(Some("/user/admin/{id}")),
+            Duration::from_secs(60)
+        );
+    }
+
+    #[tokio::test]
+    async fn timeout_with_envelope() {
+        let timeouts =
+            Timeouts::new(Duration::from_secs(15)).route("/slow", Duration::from_millis(10));
+        let app = Router::new()
+            .route("/slow", get(|| tokio::time::sleep(Duration::from_secs(15))))
+            .route("/fast", get(|| async {}))
+            .layer(middleware::from_fn_with_state(Arc::new(timeouts), timeout));
+        let send = |uri: &str| {
+            app.clone()
+                .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+        };
+
+        assert_eq!(send("/fast").await.unwrap().status(), StatusCode::OK);
+        let res = send("/slow").await.unwrap();
+        assert_eq!(res.status(), StatusCode::REQUEST_TIMEOUT);
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["code"], 1009);
+    }
+}
@@ -0,0 +1,391 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::{self, Display},
+    sync::Arc,
+};
+
+use anyhow::{anyhow, bail, Context};
+use axum::{
+    extract::{FromRequestParts, Request, State},
+    http::{header, request::Parts, HeaderMap, HeaderName, HeaderValue, Uri},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    Router,
+};
+use tower::ServiceBuilder;
+
+use crate::{config::ApiConfig, error::AppError};
+
+pub const ACCEPT_VERSION: HeaderName = HeaderName::from_static("accept-version");
+const DEPRECATION: HeaderName = HeaderName::from_static("deprecation");
+const SUNSET: HeaderName = HeaderName::from_static("sunset");
+
+/// Request for a version without a route table, left unrouted by
+/// [`negotiate_version`] and answered by the fallback of the router.
+#[derive(Debug, Clone)]
+pub struct UnsupportedVersion(String);
+
+impl IntoResponse for UnsupportedVersion {
+    fn into_response(self) -> Response {
+        AppError::InvalidParameter(self.0.into()).into_response()
+    }
+}
+
+/// Version of the API a request was routed to, only on versioned routes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ApiVersion(pub u16);
+
+impl Display for ApiVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "v{}", self.0)
+    }
+}
+
+impl<S> FromRequestParts<S> for ApiVersion
+where
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts
+            .extensions
+            .get::<ApiVersion>()
+            .copied()
+            .ok_or_else(|| anyhow!("ApiVersion extracted on an unversioned route").into())
+    }
+}
+
+/// Route tables of every API version.
+///
+/// A table nested at `/user` for version 1 is served at `/v1/user`, and at
+/// `/user` when the request negotiates version 1, see [`versioning_route`].
+/// Per-route settings like `timeout.routes` match the `/v1/user/...`
+/// templates either way.
+#[derive(Default)]
+pub struct ApiVersions {
+    tables: BTreeMap<ApiVersion, Router>,
+    prefixes: BTreeSet<String>,
+}
+
+impl ApiVersions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add `router` at `path` to the table of `version`.
+    pub fn nest(mut self, version: u16, path: &str, router: Router) -> Self {
+        let version = ApiVersion(version);
+        let table = self.tables.remove(&version).unwrap_or_default();
+        self.tables.insert(version, table.nest(path, router));
+        self.prefixes.insert(path.to_string());
+        self
+    }
+
+    /// Nest the tables in `router` under their version prefix.
+    pub fn mount(self, router: Router) -> Router {
+        self.tables
+            .into_iter()
+            .fold(router, |router, (version, table)| {
+                router.nest(&format!("/{version}"), table)
+            })
+    }
+}
+
+/// How requests to versioned routes without a version prefix are routed.
+#[derive(Debug)]
+pub struct Versioning {
+    versions: BTreeSet<ApiVersion>,
+    prefixes: Vec<String>,
+    default: ApiVersion,
+    /// Headers of deprecated versions.
+    deprecated: BTreeMap<ApiVersion, HeaderMap>,
+}
+
+impl Versioning {
+    /// Negotiate between the versions of `versions`.
+    ///
+    /// Errors when `api.default_version` or a deprecated version has no
+    /// route table.
+    pub fn from_config(config: &ApiConfig, versions: &ApiVersions) -> anyhow::Result<Self> {
+        let known = versions.tables.keys().copied().collect::<BTreeSet<_>>();
+        let supported = |version| {
+            if known.contains(&ApiVersion(version)) {
+                Ok(ApiVersion(version))
+            } else {
+                bail!("unknown API version {version}")
+            }
+        };
+        let default = match config.default_version {
+            Some(version) => supported(version).context("invalid api.default_version")?,
+            None => *known.last().context("no API version")?,
+        };
+        let mut deprecated = BTreeMap::new();
+        for deprecation in &config.deprecated {
+            let version = supported(deprecation.version).context("invalid api.deprecated")?;
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                DEPRECATION,
+                HeaderValue::from_str(&format!("@{}", deprecation.since.timestamp()))?,
+            );
+            if let Some(sunset) = deprecation.sunset {
+                let date = sunset.format("%a, %d %b %Y %H:%M:%S GMT").to_string();
+                headers.insert(SUNSET, HeaderValue::from_str(&date)?);
+            }
+            if let Some(link) = &deprecation.link {
+                let link = HeaderValue::from_str(&format!(r#"<{link}>; rel="deprecation""#))
+                    .with_context(|| format!("invalid api.deprecated link `{link}`"))?;
+                headers.insert(header::LINK, link);
+            }
+            deprecated.insert(version, headers);
+        }
+        Ok(Self {
+            versions: known,
+            prefixes: versions.prefixes.iter().cloned().collect(),
+            default,
+            deprecated,
+        })
+    }
+
+    /// Version of a `/v{n}/...` path.
+    fn prefixed(&self, path: &str) -> Option<ApiVersion> {
+        let (version, _) = path.strip_prefix("/v")?.split_once('/')?;
+        let version = ApiVersion(version.parse().ok()?);
+        self.versions.contains(&version).then_some(version)
+    }
+
+    fn is_versioned(&self, path: &str) -> bool {
+        self.prefixes.iter().any(|prefix| {
+            path.strip_prefix(prefix.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        })
+    }
+
+    /// Version of `Accept-Version`, or the `version` parameter of `Accept`,
+    /// e.g. `application/json; version=2`, the default without either.
+    fn negotiate(&self, headers: &HeaderMap) -> Result<ApiVersion, UnsupportedVersion> {
+        let requested = headers
+            .get(ACCEPT_VERSION)
+            .and_then(|value| value.to_str().ok())
+            .or_else(|| accept_version(headers));
+        let Some(requested) = requested else {
+            return Ok(self.default);
+        };
+        requested
+            .trim()
+            .trim_start_matches(['v', 'V'])
+            .parse()
+            .ok()
+            .map(ApiVersion)
+            .filter(|version| self.versions.contains(version))
+            .ok_or_else(|| {
+                let supported = self
+                    .versions
+                    .iter()
+                    .map(ApiVersion::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                UnsupportedVersion(format!(
+                    "unsupported API version `{requested}`, expected one of {supported}"
+                ))
+            })
+    }
+}
+
+/// The `version` parameter of the first media range of `Accept` with one.
+fn accept_version(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .flat_map(|range| range.split(';').skip(1))
+        .find_map(|param| {
+            let (name, value) = param.split_once('=')?;
+            name.trim()
+                .eq_ignore_ascii_case("version")
+                .then(|| value.trim().trim_matches('"'))
+        })
+}
+
+/// `uri` with its path under the prefix of `version`.
+fn with_version(uri: &Uri, version: ApiVersion) -> Option<Uri> {
+    let path_and_query = uri.path_and_query().map_or("/", |pq| pq.as_str());
+    let mut parts = uri.clone().into_parts();
+    parts.path_and_query = Some(format!("/{version}{path_and_query}").parse().ok()?);
+    Uri::from_parts(parts).ok()
+}
+
+pub async fn negotiate_version(
+    State(versioning): State<Arc<Versioning>>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    let path = req.uri().path();
+    let (version, negotiated) = if let Some(version) = versioning.prefixed(path) {
+        (version, false)
+    } else if versioning.is_versioned(path) {
+        let version = match versioning.negotiate(req.headers()) {
+            Ok(version) => version,
+            Err(unsupported) => {
+                req.extensions_mut().insert(unsupported);
+                return next.run(req).await;
+            }
+        };
+        if let Some(uri) = with_version(req.uri(), version) {
+            *req.uri_mut() = uri;
+        }
+        (version, true)
+    } else {
+        return next.run(req).await;
+    };
+    req.extensions_mut().insert(version);
+
+    let mut res = next.run(req).await;
+    let headers = res.headers_mut();
+    if negotiated {
+        headers.append(header::VARY, HeaderValue::from_static("accept-version"));
+        headers.append(header::VARY, HeaderValue::from_static("accept"));
+    }
+    if let Some(deprecated) = versioning.deprecated.get(&version) {
+        headers.extend(deprecated.clone());
+    }
+    res
+}
+
+/// Route requests to versioned routes without a version prefix to the
+/// table of the negotiated version, and mark responses of deprecated
+/// versions with `Deprecation`, `Sunset` and `Link`.
+///
+/// The path is rewritten before routing, so this wraps the whole `router`.
+/// A request for an unsupported version is passed on unrouted with an
+/// [`UnsupportedVersion`], for the fallback of `router` to answer inside
+/// its logging and CORS layers.
+pub fn versioning_route(router: Router, versioning: Versioning) -> Router {
+    let service = ServiceBuilder::new()
+        .layer(middleware::from_fn_with_state(
+            Arc::new(versioning),
+            negotiate_version,
+        ))
+        .service(router);
+    Router::new().fallback_service(service)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, http::StatusCode, routing::get, Extension};
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::config::Config;
+
+    fn versioned(toml: &str) -> anyhow::Result<Router> {
+        let table = || {
+            Router::new().route(
+                "/",
+                get(|version: ApiVersion| async move { version.to_string() }),
+            )
+        };
+        let versions = ApiVersions::new()
+            .nest(1, "/items", table())
+            .nest(2, "/items", table())
+            .nest(2, "/orders", table());
+        let versioning = Versioning::from_config(&Config::from_toml(toml)?.api, &versions)?;
+        let router = versions
+            .mount(Router::new().route("/json", get(|| async { "json" })))
+            .fallback(
+                |unsupported: Option<Extension<UnsupportedVersion>>| async move {
+                    match unsupported {
+                        Some(Extension(unsupported)) => unsupported.into_response(),
+                        None => StatusCode::NOT_FOUND.into_response(),
+                    }
+                },
+            );
+        Ok(versioning_route(router, versioning))
+    }
+
+    async fn send(
+        app: &Router,
+        req: axum::http::request::Builder,
+    ) -> (StatusCode, HeaderMap, String) {
+        let res = app
+            .clone()
+            .oneshot(req.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let (parts, body) = res.into_parts();
+        let body = body.collect().await.unwrap().to_bytes();
+        (
+            parts.status,
+            parts.headers,
+            String::from_utf8(body.to_vec()).unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn route_by_version() {
+        let app = versioned("").unwrap();
+        let get = |uri: &str| Request::get(uri);
+        assert_eq!(send(&app, get("/v1/items")).await.2, "v1");
+        assert_eq!(send(&app, get("/v2/items")).await.2, "v2");
+        assert_eq!(send(&app, get("/v2/orders")).await.2, "v2");
+        assert_eq!(send(&app, get("/items")).await.2, "v2", "latest by default");
+        assert_eq!(send(&app, get("/json")).await.2, "json");
+
+        let (_, headers, body) = send(&app, get("/items").header(ACCEPT_VERSION, "1")).await;
+        assert_eq!(body, "v1");
+        let vary = headers.get_all(header::VARY).iter().collect::<Vec<_>>();
+        assert_eq!(vary, ["accept-version", "accept"]);
+        let accept = "text/html, application/json; q=0.9; version=\"1\"";
+        assert_eq!(
+            send(&app, get("/items").header(header::ACCEPT, accept))
+                .await
+                .2,
+            "v1"
+        );
+
+        let (status, _, body) = send(&app, get("/items").header(ACCEPT_VERSION, "v3")).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body.contains("expected one of v1, v2"), "{body}");
+        // Only the tables of a version are under its prefix.
+        assert_eq!(send(&app, get("/v3/items")).await.0, StatusCode::NOT_FOUND);
+        assert_eq!(send(&app, get("/v1/orders")).await.0, StatusCode::NOT_FOUND);
+        assert_eq!(send(&app, get("/v1/json")).await.0, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn deprecation_headers() {
+        let app = versioned(
+            r#"
+            [api]
+            default_version = 1
+
+            [[api.deprecated]]
+            version = 1
+            since = "2026-01-01T00:00:00Z"
+            sunset = "2026-07-01T00:00:00Z"
+            link = "https://example.com/migrate-to-v2"
+            "#,
+        )
+        .unwrap();
+        for uri in ["/items", "/v1/items"] {
+            let (_, headers, body) = send(&app, Request::get(uri)).await;
+            assert_eq!(body, "v1");
+            assert_eq!(headers[DEPRECATION], "@1767225600");
+            assert_eq!(headers[SUNSET], "Wed, 01 Jul 2026 00:00:00 GMT");
+            assert_eq!(
+                headers[header::LINK],
+                r#"<https://example.com/migrate-to-v2>; rel="deprecation""#
+            );
+        }
+        let (_, headers, _) = send(&app, Request::get("/v2/items")).await;
+        assert!(headers.get(DEPRECATION).is_none());
+
+        let err = versioned("[api]\ndefault_version = 3").unwrap_err();
+        assert!(
+            format!("{err:#}").contains("api.default_version"),
+            "{err:#}"
+        );
+    }
+}
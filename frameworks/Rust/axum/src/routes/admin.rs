@@ -0,0 +1,171 @@
+use axum::{routing::get, Extension, Router};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+use utoipa::ToSchema;
+use validator::Validate;
+
+use crate::{
+    config::AdminConfig,
+    error::ErrorResponse,
+    middlewares::client_ip::ClientIp,
+    utils::{
+        admin::{Admin, AdminToken},
+        etag::{strong_etag, Precondition},
+        log_level::{log_filter, set_log_filter},
+        validator::ValidatedJson,
+    },
+};
+
+use super::{RouteResponse, RouteResult};
+
+#[derive(Serialize, Deserialize, Validate, ToSchema, Default)]
+pub struct LogLevel {
+    /// `EnvFilter` directives, e.g. `info,phthonus::routes=debug`
+    #[validate(length(min = 1, message = "Can not be empty"))]
+    #[schema(min_length = 1)]
+    pub filter: String,
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/log-level",
+    tag = "admin",
+    security(("admin" = [])),
+    responses(
+        (status = 200, body = RouteResponse<LogLevel>, headers(("ETag" = String))),
+        (status = 401, body = ErrorResponse),
+    )
+)]
+pub async fn get_log_level(_: Admin) -> RouteResult<LogLevel> {
+    let filter = log_filter()?;
+    let etag = strong_etag(filter.as_bytes());
+    let res = RouteResponse {
+        data: LogLevel { filter },
+        ..Default::default()
+    }
+    .etag(etag);
+    Ok(res)
+}
+
+/// Replace the log filter. Send the `ETag` of `GET` as `If-Match` to avoid
+/// overwriting a concurrent change.
+#[utoipa::path(
+    put,
+    path = "/admin/log-level",
+    tag = "admin",
+    security(("admin" = [])),
+    params(("If-Match" = Option<String>, Header, description = "`ETag` of `GET`")),
+    request_body = LogLevel,
+    responses(
+        (status = 200, body = RouteResponse<LogLevel>, headers(("ETag" = String))),
+        (status = 400, description = "Invalid filter", body = ErrorResponse),
+        (status = 401, body = ErrorResponse),
+        (status = 412, description = "Changed since `If-Match`", body = ErrorResponse),
+    )
+)]
+pub async fn put_log_level(
+    _: Admin,
+    client_ip: ClientIp,
+    precondition: Precondition,
+    ValidatedJson(param): ValidatedJson<LogLevel>,
+) -> RouteResult<LogLevel> {
+    precondition.check(&strong_etag(log_filter()?.as_bytes()))?;
+    set_log_filter(&param.filter)?;
+    info!(%client_ip, filter = param.filter, "audit: log filter changed by admin");
+    get_log_level(Admin).await
+}
+
+pub fn admin_routes(config: &AdminConfig) -> Router {
+    Router::new()
+        .route("/log-level", get(get_log_level).put(put_log_level))
+        .layer(Extension(AdminToken::from_config(config)))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{header, StatusCode};
+
+    use super::*;
+    use crate::{error::ErrorCode, routes::test_app::TestApp};
+
+    const LOG_LEVEL: &str = "/admin/log-level";
+
+    #[tokio::test]
+    async fn reject_unauthorized() {
+        let app = TestApp::new();
+        let err = app
+            .get(LOG_LEVEL)
+            .send()
+            .await
+            .assert_error(StatusCode::UNAUTHORIZED, ErrorCode::NotAuthorized);
+        assert_eq!(err, "Extract the token failed");
+        let err = app
+            .put(LOG_LEVEL)
+            .bearer("wrong")
+            .send()
+            .await
+            .assert_error(StatusCode::UNAUTHORIZED, ErrorCode::NotAuthorized);
+        assert_eq!(err, "Invalid admin token");
+
+        // Disabled without `admin.token`.
+        let err = TestApp::from_toml("")
+            .get(LOG_LEVEL)
+            .admin()
+            .send()
+            .await
+            .assert_error(StatusCode::UNAUTHORIZED, ErrorCode::NotAuthorized);
+        assert_eq!(err, "Admin token is not configured");
+    }
+
+    #[tokio::test]
+    async fn change_log_level() {
+        let app = TestApp::new();
+        let res = app.get(LOG_LEVEL).admin().send().await;
+        let etag = res.headers[header::ETAG].clone();
+        let filter = res.assert_ok()["filter"].as_str().unwrap().to_string();
+
+        let level = |filter: &str| LogLevel {
+            filter: filter.to_string(),
+        };
+        app.put(LOG_LEVEL)
+            .admin()
+            .json(&level(""))
+            .send()
+            .await
+            .assert_error(StatusCode::BAD_REQUEST, ErrorCode::ParameterIncorrect);
+        app.put(LOG_LEVEL)
+            .admin()
+            .json(&level("info,=="))
+            .send()
+            .await
+            .assert_error(StatusCode::BAD_REQUEST, ErrorCode::ParameterIncorrect);
+
+        let data = app
+            .put(LOG_LEVEL)
+            .admin()
+            .header(header::IF_MATCH, etag.clone())
+            .json(&level("debug"))
+            .send()
+            .await
+            .assert_ok();
+        assert_eq!(data["filter"], "debug");
+
+        // The filter changed since the `ETag` was read.
+        app.put(LOG_LEVEL)
+            .admin()
+            .header(header::IF_MATCH, etag)
+            .json(&level(&filter))
+            .send()
+            .await
+            .assert_error(
+                StatusCode::PRECONDITION_FAILED,
+                ErrorCode::PreconditionFailed,
+            );
+        app.put(LOG_LEVEL)
+            .admin()
+            .json(&level(&filter))
+            .send()
+            .await
+            .assert_ok();
+    }
+}
@@ -0,0 +1,195 @@
+use std::collections::BTreeMap;
+
+use axum::{http::StatusCode, response::IntoResponse};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{
+    consts::BUILD_INFO,
+    error::ErrorCode,
+    utils::readiness::{is_shutting_down, run_checks},
+};
+
+use super::{RouteResponse, RouteResult};
+
+/// Liveness probe, ok as long as the process can serve requests.
+#[utoipa::path(
+    get,
+    path = "/healthz",
+    tag = "operations",
+    responses((status = 200, body = String, content_type = "text/plain", example = "ok"))
+)]
+pub async fn healthz() -> &'static str {
+    "ok"
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Default)]
+pub struct Readiness {
+    pub ready: bool,
+    pub checks: BTreeMap<String, String>,
+}
+
+/// Readiness probe, fails when any registered dependency is unavailable or
+/// the server is shutting down.
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    tag = "operations",
+    responses(
+        (status = 200, description = "Ready", body = RouteResponse<Readiness>),
+        (status = 503, description = "Not ready, `checks` names the failures", body = RouteResponse<Readiness>),
+    )
+)]
+pub async fn readyz() -> impl IntoResponse {
+    let mut checks = BTreeMap::new();
+    let mut ready = true;
+    if is_shutting_down() {
+        ready = false;
+        checks.insert("shutdown".to_string(), "shutting down".to_string());
+    }
+    for (name, result) in run_checks().await {
+        let status = match result {
+            Ok(()) => "ok".to_string(),
+            Err(err) => {
+                ready = false;
+                err.into_owned()
+            }
+        };
+        checks.insert(name.into_owned(), status);
+    }
+
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    let res = RouteResponse {
+        code: if ready {
+            ErrorCode::Normal
+        } else {
+            ErrorCode::ServiceUnavailable
+        },
+        data: Readiness { ready, checks },
+        ..Default::default()
+    };
+    (status, res)
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Default)]
+pub struct Version {
+    pub name: String,
+    pub version: String,
+    pub rustc: String,
+    pub rustc_host: Option<String>,
+    pub llvm_version: Option<String>,
+    pub git_commit: Option<String>,
+    pub git_branch: Option<String>,
+    pub git_dirty: Option<bool>,
+    pub build_time: Option<String>,
+    pub target: String,
+    pub profile: String,
+    pub features: Vec<String>,
+}
+
+/// Build information of the running binary, never changes until restart so
+/// clients can poll it with `If-None-Match`.
+#[utoipa::path(
+    get,
+    path = "/version",
+    tag = "operations",
+    params(("If-None-Match" = Option<String>, Header, description = "`ETag` of a previous response")),
+    responses(
+        (status = 200, body = RouteResponse<Version>, headers(("ETag" = String), ("Last-Modified" = String))),
+        (status = 304, description = "Unchanged since `If-None-Match`"),
+    )
+)]
+pub async fn version() -> RouteResult<Version> {
+    let info = BUILD_INFO;
+    let data = Version {
+        name: info.name.to_string(),
+        version: info.version.to_string(),
+        rustc: info.rustc.to_string(),
+        rustc_host: info.rustc_host.map(String::from),
+        llvm_version: info.llvm_version.map(String::from),
+        git_commit: info.git_commit.map(String::from),
+        git_branch: info.git_branch.map(String::from),
+        git_dirty: info.git_dirty(),
+        build_time: info.build_time().map(|time| time.to_rfc3339()),
+        target: info.target.to_string(),
+        profile: info.profile.to_string(),
+        features: info.features().map(String::from).collect(),
+    };
+    let mut res = RouteResponse {
+        data,
+        ..Default::default()
+    }
+    .with_etag();
+    if let Some(time) = info.build_time() {
+        res = res.last_modified(time.into());
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use axum::http::{header, StatusCode};
+    use serde_json::Value;
+
+    use crate::{
+        error::ErrorCode,
+        routes::test_app::TestApp,
+        utils::{jwt::check_keys, readiness::register_check},
+    };
+
+    /// The only test of `/readyz`, checks are global.
+    #[tokio::test]
+    async fn probes() {
+        static FAILING: AtomicBool = AtomicBool::new(false);
+
+        let app = TestApp::new();
+        let res = app.get("/healthz").send().await;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(res.text(), "ok");
+
+        register_check("jwt", check_keys);
+        register_check("store", || async {
+            if FAILING.load(Ordering::SeqCst) {
+                Err("connection refused".into())
+            } else {
+                Ok(())
+            }
+        });
+        let data = app.get("/readyz").send().await.assert_ok();
+        assert_eq!(data["ready"], true);
+        assert_eq!(data["checks"]["jwt"], "ok");
+
+        FAILING.store(true, Ordering::SeqCst);
+        let res = app.get("/readyz").send().await;
+        assert_eq!(res.status, StatusCode::SERVICE_UNAVAILABLE);
+        let body: Value = res.json();
+        assert_eq!(body["code"], ErrorCode::ServiceUnavailable as u16);
+        assert_eq!(body["data"]["ready"], false);
+        assert_eq!(body["data"]["checks"]["store"], "connection refused");
+        FAILING.store(false, Ordering::SeqCst);
+    }
+
+    #[tokio::test]
+    async fn version_not_modified() {
+        let app = TestApp::new();
+        let res = app.get("/version").send().await;
+        let etag = res.headers[header::ETAG].clone();
+        let data = res.assert_ok();
+        assert_eq!(data["name"], env!("CARGO_PKG_NAME"));
+        assert_eq!(data["version"], env!("CARGO_PKG_VERSION"));
+
+        let res = app
+            .get("/version")
+            .header(header::IF_NONE_MATCH, etag)
+            .send()
+            .await;
+        assert_eq!(res.status, StatusCode::NOT_MODIFIED);
+        assert!(res.body.is_empty());
+    }
+}
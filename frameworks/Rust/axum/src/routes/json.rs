@@ -1,14 +1,21 @@
 use std::borrow::Cow;
 
 use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
 
 use super::{RouteResponse, RouteResult};
 
-#[derive(Serialize, Deserialize, Default)]
+#[derive(Serialize, Deserialize, ToSchema, Default)]
 pub struct JsonData {
     pub name: Cow<'static, str>,
 }
 
+#[utoipa::path(
+    method(get, post),
+    path = "/json",
+    tag = "benchmark",
+    responses((status = 200, body = RouteResponse<JsonData>))
+)]
 pub async fn json() -> RouteResult<JsonData> {
     let data = JsonData { name: "xfy".into() };
     let res = RouteResponse {
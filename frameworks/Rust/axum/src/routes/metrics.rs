@@ -0,0 +1,45 @@
+use axum::{http::header, response::IntoResponse};
+
+use crate::{middlewares::metrics::PROMETHEUS, utils::process::ProcessStats};
+
+/// Prometheus scrape endpoint.
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    tag = "operations",
+    responses((status = 200, body = String, content_type = "text/plain; version=0.0.4"))
+)]
+pub async fn metrics() -> impl IntoResponse {
+    if let Some(stats) = ProcessStats::read() {
+        stats.record();
+    }
+    PROMETHEUS.run_upkeep();
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        PROMETHEUS.render(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{header, StatusCode};
+
+    use crate::routes::test_app::TestApp;
+
+    #[tokio::test]
+    async fn scrape() {
+        let app = TestApp::new();
+        app.get("/json").send().await.assert_ok();
+        let res = app.get("/metrics").send().await;
+        assert_eq!(res.status, StatusCode::OK);
+        assert!(res.headers[header::CONTENT_TYPE]
+            .to_str()
+            .unwrap()
+            .starts_with("text/plain"));
+        assert!(res.text().contains("http_requests_total"), "{}", res.text());
+
+        let app = TestApp::from_toml("[metrics]\nenabled = false");
+        let res = app.get("/metrics").send().await;
+        assert_eq!(res.status, StatusCode::NOT_FOUND);
+    }
+}
@@ -1,28 +1,60 @@
-use std::{borrow::Cow, time::Duration};
+use std::{
+    borrow::Cow,
+    sync::{Arc, LazyLock},
+    time::SystemTime,
+};
 
+use admin::admin_routes;
+use anyhow::bail;
 use axum::{
-    http::{StatusCode, Uri},
+    http::{header, HeaderValue, StatusCode, Uri},
     middleware,
     response::{IntoResponse, Response},
     routing::get,
-    Json, Router,
+    Extension, Json, Router,
 };
+use axum_extra::headers::{ETag, HeaderMapExt, LastModified};
+use openapi::{api_doc, openapi_routes};
 use serde::Serialize;
 use tower::ServiceBuilder;
-use tower_http::timeout::TimeoutLayer;
 use tracing::info;
 use user::user_routes;
+use utoipa::ToSchema;
 
 use crate::{
-    error::{AppResult, ErrorCode},
-    middlewares::{add_version, logging_route},
+    config::Config,
+    error::{AppError, AppResult, ErrorCode},
+    middlewares::{
+        access_log::access_log_route,
+        add_version,
+        body_limit::{body_limit_route, BodyLimit},
+        client_ip::client_ip_route,
+        compression::{compression_route, Compression},
+        conditional::conditional_get,
+        load_shed::{load_shed, LoadShedder},
+        logging_route,
+        metrics::{metrics_route, PROMETHEUS},
+        rate_limit::{rate_limit, RateLimiter},
+        security::{cors_layer, security_headers_route},
+        timeout::{timeout, Timeouts},
+        versioning::{versioning_route, ApiVersions, UnsupportedVersion, Versioning},
+    },
+    utils::etag::strong_etag,
+    AppState,
 };
 
+pub mod admin;
+pub mod health;
 pub mod json;
+pub mod metrics;
+pub mod openapi;
+#[cfg(test)]
+pub mod test_app;
 pub mod text;
 pub mod user;
 
-#[derive(Debug, Serialize)]
+/// Body of every successful JSON response.
+#[derive(Debug, Serialize, ToSchema)]
 pub struct RouteResponse<T>
 where
     T: Serialize,
@@ -31,6 +63,40 @@ where
     #[serde(skip_serializing_if = "Option::is_none")]
     message: Option<Cow<'static, str>>,
     data: T,
+    /// Sent as `ETag`, see [`conditional_get`].
+    #[serde(skip)]
+    entity_tag: Option<EntityTag>,
+    #[serde(skip)]
+    last_modified: Option<SystemTime>,
+}
+#[derive(Debug)]
+enum EntityTag {
+    /// Hash of the serialized body.
+    Body,
+    /// Version supplied by the handler.
+    Version(ETag),
+}
+impl<T> RouteResponse<T>
+where
+    T: Serialize,
+{
+    /// Send a strong `ETag` computed from the serialized body.
+    pub fn with_etag(mut self) -> Self {
+        self.entity_tag = Some(EntityTag::Body);
+        self
+    }
+
+    /// Send `etag` as `ETag`, e.g. the revision of a record, which is cheaper
+    /// than hashing the body.
+    pub fn etag(mut self, etag: ETag) -> Self {
+        self.entity_tag = Some(EntityTag::Version(etag));
+        self
+    }
+
+    pub fn last_modified(mut self, time: SystemTime) -> Self {
+        self.last_modified = Some(time);
+        self
+    }
 }
 impl<T> Default for RouteResponse<T>
 where
@@ -41,6 +107,8 @@ where
             code: ErrorCode::Normal,
             message: None,
             data: T::default(),
+            entity_tag: None,
+            last_modified: None,
         }
     }
 }
@@ -48,33 +116,285 @@ impl<T> IntoResponse for RouteResponse<T>
 where
     T: Serialize + Default,
 {
-    fn into_response(self) -> Response {
-        (StatusCode::OK, Json(self)).into_response()
+    fn into_response(mut self) -> Response {
+        let (entity_tag, last_modified) = (self.entity_tag.take(), self.last_modified);
+        let mut res = match entity_tag {
+            None => (StatusCode::OK, Json(self)).into_response(),
+            Some(entity_tag) => {
+                let body = match serde_json::to_vec(&self) {
+                    Ok(body) => body,
+                    Err(err) => return AppError::Any(err.into()).into_response(),
+                };
+                let etag = match entity_tag {
+                    EntityTag::Body => strong_etag(&body),
+                    EntityTag::Version(etag) => etag,
+                };
+                let mut res = (
+                    StatusCode::OK,
+                    [(
+                        header::CONTENT_TYPE,
+                        HeaderValue::from_static("application/json"),
+                    )],
+                    body,
+                )
+                    .into_response();
+                res.headers_mut().typed_insert(etag);
+                res
+            }
+        };
+        if let Some(time) = last_modified {
+            res.headers_mut().typed_insert(LastModified::from(time));
+        }
+        res
     }
 }
 pub type RouteResult<T> = AppResult<RouteResponse<T>>;
 
-pub fn routes() -> Router {
-    let router = Router::new()
+pub fn routes(config: &Config, state: &AppState) -> anyhow::Result<Router> {
+    let mut router = Router::new()
         .route("/", get(hello).post(hello))
         .route("/json", get(json::json).post(json::json))
         .route("/text", get(text::text).post(text::text))
-        .nest("/user", user_routes())
-        .layer(
-            ServiceBuilder::new()
-                .layer(middleware::from_fn(add_version))
-                .layer(TimeoutLayer::new(Duration::from_secs(15))),
-        )
-        .fallback(fallback);
-    logging_route(router)
+        .route("/healthz", get(health::healthz))
+        .route("/readyz", get(health::readyz))
+        .route("/version", get(health::version))
+        .nest("/admin", admin_routes(&config.admin));
+    let versions = ApiVersions::new().nest(1, "/user", user_routes());
+    let versioning = Versioning::from_config(&config.api, &versions)?;
+    router = versions.mount(router);
+    if config.metrics.enabled {
+        // Install the recorder before middlewares set their initial gauges.
+        LazyLock::force(&PROMETHEUS);
+        router = router.route("/metrics", get(metrics::metrics));
+    }
+    let doc = api_doc(config);
+    let mut templates = doc
+        .paths
+        .paths
+        .keys()
+        .map(String::as_str)
+        .collect::<Vec<_>>();
+    if config.openapi.enabled {
+        templates.extend(["/openapi.json", "/docs"]);
+    }
+    check_route_keys(config, &templates)?;
+    if config.openapi.enabled {
+        router = router.merge(openapi_routes(doc)?);
+    }
+    let router = body_limit_route(router, BodyLimit::from_config(&config.body_limit));
+    let timeouts = Timeouts::from_config(&config.timeout);
+    let router = router.layer(
+        ServiceBuilder::new()
+            .option_layer(
+                config
+                    .security
+                    .version_headers
+                    .then(|| middleware::from_fn(add_version)),
+            )
+            .layer(middleware::from_fn(conditional_get))
+            .layer(middleware::from_fn_with_state(Arc::new(timeouts), timeout)),
+    );
+    let router = security_headers_route(router, &config.security)?;
+    let router = match LoadShedder::from_config(&config.concurrency) {
+        Some(shedder) => router.layer(middleware::from_fn_with_state(Arc::new(shedder), load_shed)),
+        None => router,
+    };
+    let router = match RateLimiter::from_config(&config.rate_limit) {
+        Some(limiter) => router.layer(middleware::from_fn_with_state(
+            Arc::new(limiter.store(state.rate_limit_store.clone())),
+            rate_limit,
+        )),
+        None => router,
+    };
+    // Before CORS, so the rejections of `versioning_route` get its headers.
+    let router = router.fallback(fallback);
+    let router = match cors_layer(&config.cors)? {
+        Some(cors) => router.layer(cors),
+        None => router,
+    };
+    let router = logging_route(metrics_route(router, &config.metrics), &config.log);
+    // Outside of logging, so bodies are logged uncompressed.
+    let router = compression_route(router, Compression::from_config(&config.compression)?);
+    let router = access_log_route(router, &config.log);
+    let router = client_ip_route(router, config.server.trusted_proxies.clone());
+    Ok(versioning_route(router, versioning))
+}
+
+/// Fail on keys of per-route settings that match none of `templates`,
+/// e.g. `/user/regist` instead of `/v1/user/regist`.
+fn check_route_keys(config: &Config, templates: &[&str]) -> anyhow::Result<()> {
+    let keys = (config
+        .body_limit
+        .routes
+        .keys()
+        .map(|key| ("body_limit.routes", key)))
+    .chain(
+        config
+            .compression
+            .routes
+            .keys()
+            .map(|key| ("compression.routes", key)),
+    )
+    .chain(
+        config
+            .concurrency
+            .routes
+            .keys()
+            .map(|key| ("concurrency.routes", key)),
+    )
+    .chain(
+        config
+            .rate_limit
+            .routes
+            .keys()
+            .map(|key| ("rate_limit.routes", key)),
+    )
+    .chain(
+        config
+            .timeout
+            .routes
+            .keys()
+            .map(|key| ("timeout.routes", key)),
+    );
+    for (setting, key) in keys {
+        let matched = match key.strip_suffix('*') {
+            // Only timeouts apply to route groups.
+            Some(prefix) if setting == "timeout.routes" => {
+                templates.iter().any(|route| route.starts_with(prefix))
+            }
+            _ => templates.contains(&key.as_str()),
+        };
+        if !matched {
+            bail!("{setting} `{key}` matches no route");
+        }
+    }
+    Ok(())
 }
 
 /// hello world
+#[utoipa::path(
+    method(get, post),
+    path = "/",
+    tag = "benchmark",
+    responses((status = 200, body = String, content_type = "text/plain"))
+)]
 pub async fn hello() -> String {
     format!("hello {}", env!("CARGO_PKG_NAME"))
 }
 
-pub async fn fallback(uri: Uri) -> impl IntoResponse {
+pub async fn fallback(unsupported: Option<Extension<UnsupportedVersion>>, uri: Uri) -> Response {
+    if let Some(Extension(unsupported)) = unsupported {
+        return unsupported.into_response();
+    }
     info!("route {} not found", uri);
-    (StatusCode::NOT_FOUND, "Not found")
+    (StatusCode::NOT_FOUND, "Not found").into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{header, Method, StatusCode};
+
+    use super::{routes, test_app::TestApp};
+    use crate::{
+        config::Config, error::ErrorCode, middlewares::versioning::ACCEPT_VERSION, AppState,
+    };
+
+    #[tokio::test]
+    async fn benchmark_routes() {
+        let app = TestApp::new();
+        for method in [Method::GET, Method::POST] {
+            let res = app.request(method.clone(), "/").send().await;
+            assert_eq!(res.status, StatusCode::OK);
+            assert_eq!(res.text(), "hello phthonus");
+
+            let data = app
+                .request(method.clone(), "/json")
+                .send()
+                .await
+                .assert_ok();
+            assert_eq!(data["name"], "xfy");
+
+            let res = app.request(method, "/text").send().await;
+            assert_eq!(res.text(), "xfy");
+        }
+    }
+
+    #[tokio::test]
+    async fn not_found() {
+        let res = TestApp::new().get("/missing").send().await;
+        assert_eq!(res.status, StatusCode::NOT_FOUND);
+        assert_eq!(res.text(), "Not found");
+    }
+
+    #[tokio::test]
+    async fn unsupported_version_through_middlewares() {
+        let app = TestApp::from_toml("[cors]\norigins = [\"https://a.example\"]");
+        let res = app
+            .post("/user/regist")
+            .header(ACCEPT_VERSION, "9")
+            .header(header::ORIGIN, "https://a.example")
+            .send()
+            .await;
+        let error = res.assert_error(StatusCode::BAD_REQUEST, ErrorCode::ParameterIncorrect);
+        assert!(error.contains("expected one of v1"), "{error}");
+        assert_eq!(
+            res.headers[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://a.example"
+        );
+
+        let metrics = app.get("/metrics").send().await;
+        assert!(
+            metrics
+                .text()
+                .lines()
+                .any(|line| line.starts_with("http_requests_total{")
+                    && line.contains(r#"route="unmatched""#)
+                    && line.contains(r#"method="POST""#)
+                    && line.contains(r#"status="4xx""#)),
+            "{}",
+            metrics.text()
+        );
+    }
+
+    #[test]
+    fn unknown_route_keys() {
+        let build = |toml: &str| routes(&Config::from_toml(toml)?, &AppState::default());
+        assert!(build("[rate_limit.routes]\n\"/v1/user/regist\" = \"5/1m\"").is_ok());
+        assert!(build("[timeout.routes]\n\"/v1/user/*\" = \"30s\"").is_ok());
+        for (toml, setting) in [
+            (
+                "[body_limit.routes]\n\"/user/regist\" = \"4k\"",
+                "body_limit.routes",
+            ),
+            (
+                "[rate_limit.routes]\n\"/user/regist\" = \"5/1m\"",
+                "rate_limit.routes",
+            ),
+            ("[timeout.routes]\n\"/user/*\" = \"30s\"", "timeout.routes"),
+        ] {
+            let err = build(toml).unwrap_err();
+            assert!(format!("{err:#}").contains(setting), "{err:#}");
+        }
+    }
+
+    #[tokio::test]
+    async fn rate_limited() {
+        let app = TestApp::from_toml("[rate_limit]\nquota = \"1/1m\"");
+        app.get("/json").send().await.assert_ok();
+        app.get("/json")
+            .send()
+            .await
+            .assert_error(StatusCode::TOO_MANY_REQUESTS, ErrorCode::TooManyRequests);
+    }
+
+    #[tokio::test]
+    async fn serve_on_listener() {
+        let app = TestApp::new();
+        let server = app.spawn();
+        let data = server.get("/json").send().await.assert_ok();
+        assert_eq!(data["name"], "xfy");
+        let res = server.get("/missing").send().await;
+        assert_eq!(res.status, StatusCode::NOT_FOUND);
+        server.stop().await;
+    }
 }
@@ -0,0 +1,208 @@
+use axum::{body::Bytes, http::header, response::Html, routing::get, Router};
+use utoipa::{
+    openapi::{
+        security::{HttpAuthScheme, HttpBuilder, SecurityScheme},
+        OpenApi as Document,
+    },
+    Modify, OpenApi,
+};
+use utoipa_scalar::Scalar;
+
+use crate::{
+    config::Config,
+    error::{ErrorCode, ErrorResponse},
+};
+
+use super::{admin, health, json, metrics, text, user};
+
+/// The reference page loads Scalar from jsDelivr, which the default
+/// `security.csp` blocks.
+const DOCS_CSP: &str = "default-src 'none'; script-src https://cdn.jsdelivr.net; \
+    style-src 'unsafe-inline' https://cdn.jsdelivr.net; font-src https://fonts.scalar.com data:; \
+    img-src 'self' data:; connect-src 'self'; frame-ancestors 'none'";
+
+#[derive(OpenApi)]
+#[openapi(
+    info(description = "Benchmark server of phthonus built on axum.\n\n\
+        Versioned routes are served at `/v{n}/...`, and without the prefix for the version \
+        requested by `Accept-Version` or the `version` parameter of `Accept`, the latest \
+        by default. Deprecated versions are answered with `Deprecation` and `Sunset`."),
+    paths(
+        super::hello,
+        json::json,
+        text::text,
+        health::healthz,
+        health::readyz,
+        health::version,
+        metrics::metrics,
+        user::registry,
+        admin::get_log_level,
+        admin::put_log_level,
+    ),
+    components(schemas(ErrorResponse, ErrorCode)),
+    modifiers(&NoLicense, &AdminToken, &PostOperationIds),
+    tags(
+        (name = "benchmark", description = "Fixed responses for benchmarks"),
+        (name = "operations", description = "Probes, build information and metrics"),
+        (name = "user"),
+        (name = "admin", description = "Runtime administration, requires `admin.token`"),
+    )
+)]
+pub struct ApiDoc;
+
+/// utoipa fills in the license of Cargo.toml, which has none.
+struct NoLicense;
+
+impl Modify for NoLicense {
+    fn modify(&self, openapi: &mut Document) {
+        openapi.info.license = None;
+    }
+}
+
+/// Bearer token of the admin routes.
+struct AdminToken;
+
+impl Modify for AdminToken {
+    fn modify(&self, openapi: &mut Document) {
+        let scheme = HttpBuilder::new()
+            .scheme(HttpAuthScheme::Bearer)
+            .description(Some("`admin.token`"))
+            .build();
+        openapi
+            .components
+            .get_or_insert_with(Default::default)
+            .add_security_scheme("admin", SecurityScheme::Http(scheme));
+    }
+}
+
+/// Handlers of both `GET` and `POST` get the same `operationId`, which must
+/// be unique, suffix the one of `POST`.
+struct PostOperationIds;
+
+impl Modify for PostOperationIds {
+    fn modify(&self, openapi: &mut Document) {
+        for item in openapi.paths.paths.values_mut() {
+            let (Some(get), Some(post)) = (&item.get, &mut item.post) else {
+                continue;
+            };
+            if get.operation_id == post.operation_id {
+                post.operation_id = post.operation_id.take().map(|id| format!("{id}_post"));
+            }
+        }
+    }
+}
+
+/// Document of the routes enabled by `config`.
+pub fn api_doc(config: &Config) -> Document {
+    let mut doc = ApiDoc::openapi();
+    if !config.metrics.enabled {
+        doc.paths.paths.remove("/metrics");
+    }
+    doc
+}
+
+/// Serve `doc` at `/openapi.json` and its reference page at `/docs`.
+pub fn openapi_routes(doc: Document) -> anyhow::Result<Router> {
+    let json = Bytes::from(doc.to_json()?);
+    let html = Bytes::from(Scalar::new(doc).to_html());
+    let router = Router::new()
+        .route(
+            "/openapi.json",
+            get(|| async move { ([(header::CONTENT_TYPE, "application/json")], json) }),
+        )
+        .route(
+            "/docs",
+            get(|| async move { ([(header::CONTENT_SECURITY_POLICY, DOCS_CSP)], Html(html)) }),
+        );
+    Ok(router)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use axum::http::StatusCode;
+    use serde_json::{json, Value};
+    use validator::Validate;
+
+    use super::*;
+    use crate::{
+        routes::{test_app::TestApp, user::UserResigtry},
+        utils::validator::EMAIL_REGEX,
+    };
+
+    const SNAPSHOT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/openapi.json");
+
+    /// Review changes of the document, then accept them with
+    /// `UPDATE_SNAPSHOTS=1 cargo test`.
+    #[test]
+    fn document_snapshot() {
+        let doc = ApiDoc::openapi().to_pretty_json().unwrap() + "\n";
+        if env::var_os("UPDATE_SNAPSHOTS").is_some() {
+            fs::write(SNAPSHOT, &doc).unwrap();
+            return;
+        }
+        let snapshot = fs::read_to_string(SNAPSHOT).unwrap_or_default();
+        assert!(
+            snapshot == doc,
+            "openapi.json is out of date, run `UPDATE_SNAPSHOTS=1 cargo test`"
+        );
+    }
+
+    #[test]
+    fn email_pattern_is_validated() {
+        let doc = serde_json::to_value(ApiDoc::openapi()).unwrap();
+        let email = &doc["components"]["schemas"]["UserResigtry"]["properties"]["email"];
+        assert_eq!(email["pattern"], EMAIL_REGEX.as_str());
+    }
+
+    /// The documented lengths are the bounds `validate` accepts.
+    #[test]
+    fn lengths_are_validated() {
+        let doc = serde_json::to_value(ApiDoc::openapi()).unwrap();
+        let properties = &doc["components"]["schemas"]["UserResigtry"]["properties"];
+        let valid = json!({
+            "username": "xfy",
+            "email": "xfy@example.com",
+            "password": "secret-password",
+        });
+        let accepts = |field: &str, len: u64| {
+            let mut user = valid.clone();
+            user[field] = "a".repeat(len as usize).into();
+            let user: UserResigtry = serde_json::from_value(user).unwrap();
+            user.validate().is_ok()
+        };
+        for (field, schema) in properties.as_object().unwrap() {
+            if let Some(min) = schema["minLength"].as_u64() {
+                assert!(accepts(field, min), "{field} of {min}");
+                assert!(!accepts(field, min - 1), "{field} of {}", min - 1);
+            }
+            if let Some(max) = schema["maxLength"].as_u64() {
+                assert!(accepts(field, max), "{field} of {max}");
+                assert!(!accepts(field, max + 1), "{field} of {}", max + 1);
+            }
+        }
+        assert!(properties["password"]["maxLength"].is_u64());
+    }
+
+    #[tokio::test]
+    async fn serve_document() {
+        let app = TestApp::new();
+        let doc: Value = app.get("/openapi.json").send().await.json();
+        assert_eq!(doc["openapi"], "3.1.0");
+        assert!(doc["paths"]["/metrics"].is_object());
+
+        let res = app.get("/docs").send().await;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(res.headers[header::CONTENT_SECURITY_POLICY], DOCS_CSP);
+        assert!(res.text().contains("/user/regist"));
+
+        let app = TestApp::from_toml("[metrics]\nenabled = false");
+        let doc: Value = app.get("/openapi.json").send().await.json();
+        assert!(doc["paths"]["/metrics"].is_null());
+
+        let app = TestApp::from_toml("[openapi]\nenabled = false");
+        let res = app.get("/openapi.json").send().await;
+        assert_eq!(res.status, StatusCode::NOT_FOUND);
+    }
+}
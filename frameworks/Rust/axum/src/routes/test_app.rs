@@ -0,0 +1,262 @@
+//! Harness for testing the full router, see [`TestApp`].
+
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    sync::OnceLock,
+};
+
+use axum::{
+    body::{Body, Bytes},
+    extract::ConnectInfo,
+    http::{header, request, HeaderMap, HeaderName, HeaderValue, Method, Request, StatusCode},
+    Router,
+};
+use http_body_util::BodyExt;
+use hyper::{body::Body as _, client::conn};
+use hyper_util::rt::TokioIo;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use tokio::{net::TcpStream, sync::oneshot, task::JoinHandle};
+use tower::ServiceExt;
+use tracing_subscriber::{reload, EnvFilter, Registry};
+
+use super::routes;
+use crate::{
+    config::Config,
+    error::ErrorCode,
+    utils::{
+        listener::{AppListener, BindAddr, ConnectionInfo, PeerAddr},
+        log_level::reloadable_filter,
+        server::Server,
+    },
+    AppState,
+};
+
+/// `admin.token` of [`TestApp::new`].
+pub const ADMIN_TOKEN: &str = "test-admin-token";
+
+/// Peer of requests sent without a listener.
+const PEER: SocketAddr = SocketAddr::new(std::net::IpAddr::V4(Ipv4Addr::LOCALHOST), 40000);
+
+/// Keeps the reloadable log filter alive, tests don't install a subscriber.
+static LOG_FILTER: OnceLock<reload::Layer<EnvFilter, Registry>> = OnceLock::new();
+
+/// The router of `main`, with every middleware, built from a test config.
+///
+/// Requests are sent to the router directly with `oneshot`, or through a
+/// real listener after [`TestApp::spawn`].
+pub struct TestApp {
+    config: Config,
+    router: Router,
+}
+
+impl TestApp {
+    /// App with the default config and [`ADMIN_TOKEN`].
+    pub fn new() -> Self {
+        Self::from_toml(&format!("[admin]\ntoken = \"{ADMIN_TOKEN}\""))
+    }
+
+    /// App with the config file `toml`, defaults fill the rest.
+    pub fn from_toml(toml: &str) -> Self {
+        Self::with_state(toml, AppState::default())
+    }
+
+    /// App with the config file `toml` and the backends of `state`.
+    pub fn with_state(toml: &str, state: AppState) -> Self {
+        LOG_FILTER.get_or_init(|| reloadable_filter(EnvFilter::new("info")));
+        let config = Config::from_toml(toml).expect("invalid test config");
+        config.validate().expect("invalid test config");
+        let router = routes(&config, &state).expect("failed to build router");
+        Self { config, router }
+    }
+
+    pub fn get(&self, uri: &str) -> TestRequest {
+        self.request(Method::GET, uri)
+    }
+
+    pub fn post(&self, uri: &str) -> TestRequest {
+        self.request(Method::POST, uri)
+    }
+
+    pub fn put(&self, uri: &str) -> TestRequest {
+        self.request(Method::PUT, uri)
+    }
+
+    pub fn request(&self, method: Method, uri: &str) -> TestRequest {
+        TestRequest::new(Target::Router(self.router.clone()), method, uri)
+    }
+
+    /// Serve the app on an ephemeral port of `127.0.0.1`, with the HTTP
+    /// settings of the config.
+    pub fn spawn(&self) -> TestServer {
+        let mut server = self.config.server.clone();
+        server.bind = vec!["127.0.0.1:0".parse().unwrap()];
+        let listener = AppListener::bind(&server, None).expect("failed to bind");
+        let BindAddr::Tcp(addr) = listener.local_addrs()[0] else {
+            panic!("expect tcp address");
+        };
+        let (stop, rx) = oneshot::channel();
+        let serving = tokio::spawn(
+            Server::from_config(&self.config.http, &self.config.shutdown).serve(
+                listener,
+                self.router.clone(),
+                async {
+                    rx.await.ok();
+                },
+            ),
+        );
+        TestServer {
+            addr,
+            stop,
+            serving,
+        }
+    }
+}
+
+/// [`TestApp`] served on a real listener.
+pub struct TestServer {
+    pub addr: SocketAddr,
+    stop: oneshot::Sender<()>,
+    serving: JoinHandle<()>,
+}
+
+impl TestServer {
+    pub fn get(&self, uri: &str) -> TestRequest {
+        self.request(Method::GET, uri)
+    }
+
+    /// Request sent on a new HTTP/1.1 connection.
+    pub fn request(&self, method: Method, uri: &str) -> TestRequest {
+        TestRequest::new(Target::Listener(self.addr), method, uri)
+    }
+
+    /// Shut down gracefully and wait until the server stopped.
+    pub async fn stop(self) {
+        self.stop.send(()).ok();
+        self.serving.await.unwrap();
+    }
+}
+
+enum Target {
+    Router(Router),
+    Listener(SocketAddr),
+}
+
+/// Request built by [`TestApp`] or [`TestServer`].
+pub struct TestRequest {
+    target: Target,
+    builder: request::Builder,
+    body: Body,
+}
+
+impl TestRequest {
+    fn new(target: Target, method: Method, uri: &str) -> Self {
+        Self {
+            target,
+            builder: Request::builder().method(method).uri(uri),
+            body: Body::empty(),
+        }
+    }
+
+    pub fn header<V>(mut self, name: HeaderName, value: V) -> Self
+    where
+        V: TryInto<HeaderValue>,
+        V::Error: Into<axum::http::Error>,
+    {
+        self.builder = self.builder.header(name, value);
+        self
+    }
+
+    /// Authenticate with `Authorization: Bearer <token>`.
+    pub fn bearer(self, token: &str) -> Self {
+        self.header(header::AUTHORIZATION, format!("Bearer {token}"))
+    }
+
+    /// Authenticate as admin with [`ADMIN_TOKEN`].
+    pub fn admin(self) -> Self {
+        self.bearer(ADMIN_TOKEN)
+    }
+
+    /// Send `value` as a JSON body.
+    pub fn json(mut self, value: &impl Serialize) -> Self {
+        self.body = Body::from(serde_json::to_vec(value).unwrap());
+        self.header(header::CONTENT_TYPE, "application/json")
+    }
+
+    pub fn body(mut self, body: impl Into<Body>) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    pub async fn send(self) -> TestResponse {
+        let mut req = self.builder.body(self.body).expect("invalid test request");
+        let res = match self.target {
+            Target::Router(router) => {
+                // Sent by hyper on the wire, the body limit relies on it.
+                if let Some(length) = req.body().size_hint().exact().filter(|&len| len > 0) {
+                    req.headers_mut()
+                        .entry(header::CONTENT_LENGTH)
+                        .or_insert(length.into());
+                }
+                req.extensions_mut().insert(ConnectInfo(ConnectionInfo {
+                    peer: PeerAddr::Tcp(PEER),
+                    client_cert: None,
+                }));
+                router.oneshot(req).await.unwrap()
+            }
+            Target::Listener(addr) => {
+                let stream = TcpStream::connect(addr).await.unwrap();
+                let (mut sender, conn) =
+                    conn::http1::handshake(TokioIo::new(stream)).await.unwrap();
+                tokio::spawn(conn);
+                sender.send_request(req).await.unwrap().map(Body::new)
+            }
+        };
+        let (parts, body) = res.into_parts();
+        TestResponse {
+            status: parts.status,
+            headers: parts.headers,
+            body: body.collect().await.unwrap().to_bytes(),
+        }
+    }
+}
+
+/// Buffered response with assertions on the envelope of `RouteResponse`
+/// and `AppError`.
+#[derive(Debug)]
+pub struct TestResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+impl TestResponse {
+    pub fn text(&self) -> &str {
+        std::str::from_utf8(&self.body).expect("body is not utf-8")
+    }
+
+    pub fn json<T: DeserializeOwned>(&self) -> T {
+        serde_json::from_slice(&self.body)
+            .unwrap_or_else(|err| panic!("invalid json body `{}`: {err}", self.text()))
+    }
+
+    /// Assert a successful envelope, returns its `data`.
+    pub fn assert_ok(&self) -> Value {
+        assert_eq!(self.status, StatusCode::OK, "{}", self.text());
+        let mut envelope: Value = self.json();
+        assert_eq!(envelope["code"], ErrorCode::Normal as u16, "{envelope}");
+        envelope["data"].take()
+    }
+
+    /// Assert an error envelope of `status` and `code`, returns its `error`.
+    pub fn assert_error(&self, status: StatusCode, code: ErrorCode) -> String {
+        assert_eq!(self.status, status, "{}", self.text());
+        let envelope: Value = self.json();
+        assert_eq!(envelope["code"], code as u16, "{envelope}");
+        assert!(envelope["message"].is_string(), "{envelope}");
+        envelope["error"]
+            .as_str()
+            .unwrap_or_else(|| panic!("no error in {envelope}"))
+            .to_string()
+    }
+}
@@ -1,5 +1,11 @@
 use axum::response::IntoResponse;
 
+#[utoipa::path(
+    method(get, post),
+    path = "/text",
+    tag = "benchmark",
+    responses((status = 200, body = String, content_type = "text/plain"))
+)]
 pub async fn text() -> impl IntoResponse {
     "xfy"
 }
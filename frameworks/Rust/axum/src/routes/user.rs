@@ -2,29 +2,48 @@ use crate::utils::{
     jwt::{self, Claims},
     password::hash,
 };
-use axum::{routing::post, Json, Router};
+use axum::{routing::post, Router};
 use chrono::Utc;
 use serde::{Deserialize, Serialize};
+use utoipa::{
+    openapi::{schema::Type, Object, ObjectBuilder},
+    ToSchema,
+};
 use validator::Validate;
 
-use crate::utils::validator::EMAIL_REGEX;
+use crate::{
+    error::ErrorResponse,
+    utils::validator::{ValidatedJson, EMAIL_PATTERN, EMAIL_REGEX},
+};
 
 use super::{RouteResponse, RouteResult};
 
-#[derive(Serialize, Deserialize, Validate)]
+#[derive(Serialize, Deserialize, Validate, ToSchema)]
 pub struct UserResigtry {
     #[validate(length(min = 1, message = "Can not be empty"))]
+    #[schema(min_length = 1)]
     pub username: String,
     #[validate(regex(
         path = *EMAIL_REGEX,
         message = "邮箱格式不正确"
     ))]
+    #[schema(schema_with = email_schema)]
     pub email: String,
-    #[validate(length(min = 6, max = 100, message = "Can not be empty"))]
+    #[validate(length(min = 6, max = 100, message = "Must be 6 to 100 characters"))]
+    #[schema(min_length = 6, max_length = 100)]
     pub password: String,
 }
 
-#[derive(Serialize, Deserialize, Default)]
+/// `pattern` of utoipa only takes a literal, build the schema from the
+/// pattern `EMAIL_REGEX` is compiled from instead.
+fn email_schema() -> Object {
+    ObjectBuilder::new()
+        .schema_type(Type::String)
+        .pattern(Some(EMAIL_PATTERN))
+        .build()
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Default)]
 pub struct UserResigtryRes {
     pub username: String,
     pub email: String,
@@ -32,7 +51,20 @@ pub struct UserResigtryRes {
     pub token: String,
 }
 
-pub async fn registry(Json(user_param): Json<UserResigtry>) -> RouteResult<UserResigtryRes> {
+#[utoipa::path(
+    post,
+    path = "/v1/user/regist",
+    tag = "user",
+    request_body = UserResigtry,
+    responses(
+        (status = 200, description = "Registered, `password` is the hash", body = RouteResponse<UserResigtryRes>),
+        (status = 400, description = "Not JSON, or a field is missing or invalid", body = ErrorResponse),
+        (status = 413, body = ErrorResponse),
+    )
+)]
+pub async fn registry(
+    ValidatedJson(user_param): ValidatedJson<UserResigtry>,
+) -> RouteResult<UserResigtryRes> {
     let UserResigtry {
         email,
         password,
@@ -66,3 +98,81 @@ pub async fn registry(Json(user_param): Json<UserResigtry>) -> RouteResult<UserR
 pub fn user_routes() -> Router {
     Router::new().route("/regist", post(registry))
 }
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{header, StatusCode};
+    use serde_json::json;
+
+    use crate::{
+        error::ErrorCode,
+        routes::test_app::TestApp,
+        utils::{jwt::decode_jwt, password::verify},
+    };
+
+    #[tokio::test]
+    async fn regist() {
+        let user = json!({
+            "username": "xfy",
+            "email": "xfy@example.com",
+            "password": "secret-password",
+        });
+        let data = TestApp::new()
+            .post("/user/regist")
+            .json(&user)
+            .send()
+            .await
+            .assert_ok();
+        assert_eq!(data["username"], "xfy");
+        assert_eq!(data["email"], "xfy@example.com");
+        let hash = data["password"].as_str().unwrap().to_string();
+        assert!(verify("secret-password".into(), hash).await.unwrap());
+        let token = decode_jwt(data["token"].as_str().unwrap()).unwrap();
+        assert_eq!(token.claims.sub, "xfy");
+    }
+
+    #[tokio::test]
+    async fn reject_invalid_body() {
+        let app = TestApp::new();
+        let error = app
+            .post("/user/regist")
+            .json(&json!({ "username": "xfy" }))
+            .send()
+            .await
+            .assert_error(StatusCode::BAD_REQUEST, ErrorCode::ParameterIncorrect);
+        assert!(error.contains("missing field `email`"), "{error}");
+
+        let error = app
+            .post("/user/regist")
+            .json(&json!({
+                "username": "xfy",
+                "email": "xfy@example.com",
+                "password": "short",
+            }))
+            .send()
+            .await
+            .assert_error(StatusCode::BAD_REQUEST, ErrorCode::ParameterIncorrect);
+        assert!(error.contains("password"), "{error}");
+
+        app.post("/user/regist")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body("{")
+            .send()
+            .await
+            .assert_error(StatusCode::BAD_REQUEST, ErrorCode::ParameterIncorrect);
+
+        app.post("/user/regist")
+            .body("{}")
+            .send()
+            .await
+            .assert_error(StatusCode::BAD_REQUEST, ErrorCode::ParameterIncorrect);
+
+        let app = TestApp::from_toml("[body_limit.routes]\n\"/v1/user/regist\" = 16");
+        let res = app
+            .post("/user/regist")
+            .json(&json!({ "username": "a".repeat(32) }))
+            .send()
+            .await;
+        res.assert_error(StatusCode::PAYLOAD_TOO_LARGE, ErrorCode::PayloadTooLarge);
+    }
+}
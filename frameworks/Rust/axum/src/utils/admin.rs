@@ -0,0 +1,69 @@
+use axum::{extract::FromRequestParts, http::request::Parts, RequestPartsExt};
+use axum_extra::{
+    headers::{authorization::Bearer, Authorization},
+    TypedHeader,
+};
+
+use tracing::warn;
+
+use crate::{
+    config::{AdminConfig, Secret},
+    error::AppError,
+    middlewares::client_ip::ClientIp,
+};
+
+/// Extractor for admin routes.
+///
+/// The request must carry `Authorization: Bearer <token>` where the token
+/// equals the [`AdminToken`] of the route. Admin routes are always rejected
+/// when `admin.token` is not set.
+pub struct Admin;
+
+/// Token expected by [`Admin`], added as an extension by the admin routes.
+#[derive(Clone)]
+pub struct AdminToken(Option<Secret>);
+
+impl AdminToken {
+    pub fn from_config(config: &AdminConfig) -> Self {
+        Self(
+            config
+                .token
+                .clone()
+                .filter(|token| !token.expose().is_empty()),
+        )
+    }
+}
+
+impl<S> FromRequestParts<S> for Admin
+where
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let expected = parts
+            .extensions
+            .get::<AdminToken>()
+            .and_then(|AdminToken(token)| token.clone())
+            .ok_or_else(|| AppError::Unauthorized("Admin token is not configured".into()))?;
+        let TypedHeader(Authorization(bearer)) = parts
+            .extract::<TypedHeader<Authorization<Bearer>>>()
+            .await
+            .map_err(|_| AppError::Unauthorized("Extract the token failed".into()))?;
+
+        if constant_time_eq(bearer.token().as_bytes(), expected.expose().as_bytes()) {
+            Ok(Admin)
+        } else {
+            match parts.extensions.get::<ClientIp>() {
+                Some(client_ip) => warn!(%client_ip, "audit: invalid admin token"),
+                None => warn!("audit: invalid admin token"),
+            }
+            Err(AppError::Unauthorized("Invalid admin token".into()))
+        }
+    }
+}
+
+/// Compare two byte slices without short-circuiting on the first mismatch.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
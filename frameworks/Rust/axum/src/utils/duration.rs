@@ -0,0 +1,134 @@
+use std::{fmt, str::FromStr, time::Duration};
+
+use anyhow::{anyhow, bail};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+/// Parse a human readable duration such as `500ms`, `15s`, `1m` or `1h`.
+///
+/// A bare unit is treated as one of it, e.g. `s` is one second. A bare number
+/// is treated as seconds.
+pub fn parse_duration(input: &str) -> anyhow::Result<Duration> {
+    let input = input.trim();
+    if input.is_empty() {
+        bail!("empty duration");
+    }
+    let split = input
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(input.len());
+    let (value, unit) = input.split_at(split);
+    let value = if value.is_empty() {
+        1
+    } else {
+        value
+            .parse::<u64>()
+            .map_err(|_| anyhow!("invalid duration `{input}`"))?
+    };
+    let duration = match unit.trim() {
+        "ms" => Duration::from_millis(value),
+        "" | "s" => Duration::from_secs(value),
+        "m" => Duration::from_secs(value * 60),
+        "h" => Duration::from_secs(value * 60 * 60),
+        "d" => Duration::from_secs(value * 60 * 60 * 24),
+        _ => bail!("invalid duration unit in `{input}`, expect one of ms, s, m, h, d"),
+    };
+    Ok(duration)
+}
+
+/// A [`Duration`] written as `500ms`, `15s` or `1m` in config files, a bare
+/// number is seconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HumanDuration(pub Duration);
+
+impl From<Duration> for HumanDuration {
+    fn from(duration: Duration) -> Self {
+        Self(duration)
+    }
+}
+
+impl FromStr for HumanDuration {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_duration(s).map(Self)
+    }
+}
+
+/// Formatted in the largest unit that divides the duration.
+impl fmt::Display for HumanDuration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let millis = self.0.as_millis();
+        let units = [
+            (86_400_000, "d"),
+            (3_600_000, "h"),
+            (60_000, "m"),
+            (1000, "s"),
+        ];
+        match units
+            .iter()
+            .find(|(scale, _)| millis > 0 && millis.is_multiple_of(*scale))
+        {
+            Some((scale, unit)) => write!(f, "{}{unit}", millis / scale),
+            None => write!(f, "{millis}ms"),
+        }
+    }
+}
+
+impl Serialize for HumanDuration {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for HumanDuration {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl de::Visitor<'_> for Visitor {
+            type Value = HumanDuration;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("a duration such as `500ms`, `15s` or a number of seconds")
+            }
+
+            fn visit_u64<E: de::Error>(self, value: u64) -> Result<Self::Value, E> {
+                Ok(HumanDuration(Duration::from_secs(value)))
+            }
+
+            fn visit_i64<E: de::Error>(self, value: i64) -> Result<Self::Value, E> {
+                let value = u64::try_from(value)
+                    .map_err(|_| E::invalid_value(de::Unexpected::Signed(value), &self))?;
+                self.visit_u64(value)
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<Self::Value, E> {
+                value.parse().map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_any(Visitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_duration_works() {
+        assert_eq!(parse_duration("500ms").unwrap(), Duration::from_millis(500));
+        assert_eq!(parse_duration("15").unwrap(), Duration::from_secs(15));
+        assert_eq!(parse_duration("s").unwrap(), Duration::from_secs(1));
+        assert_eq!(parse_duration("2m").unwrap(), Duration::from_secs(120));
+        assert_eq!(parse_duration("1h").unwrap(), Duration::from_secs(3600));
+        assert!(parse_duration("1w").is_err());
+        assert!(parse_duration("").is_err());
+    }
+
+    #[test]
+    fn human_duration_round_trip() {
+        for text in ["500ms", "15s", "2m", "1h", "1d", "1500ms"] {
+            assert_eq!(text.parse::<HumanDuration>().unwrap().to_string(), text);
+        }
+        assert_eq!(HumanDuration(Duration::ZERO).to_string(), "0ms");
+    }
+}
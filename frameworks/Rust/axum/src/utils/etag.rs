@@ -0,0 +1,68 @@
+use axum::{extract::FromRequestParts, http::request::Parts};
+use axum_extra::headers::{ETag, HeaderMapExt, IfMatch};
+use blake2::{Blake2s256, Digest};
+
+use crate::error::AppError;
+
+/// Strong entity tag of `bytes`, stable across processes and builds.
+pub fn strong_etag(bytes: &[u8]) -> ETag {
+    let hash = Blake2s256::digest(bytes);
+    let hex = hash[..16]
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect::<String>();
+    format!("\"{hex}\"")
+        .parse()
+        .expect("hex digest is a valid entity tag")
+}
+
+/// Extractor for `If-Match`, used by update routes for optimistic
+/// concurrency.
+///
+/// Requests without `If-Match` always pass.
+#[derive(Debug, Clone)]
+pub struct Precondition(pub Option<IfMatch>);
+
+impl Precondition {
+    /// Reject with 412 when the client's version is not `current`.
+    pub fn check(&self, current: &ETag) -> Result<(), AppError> {
+        match &self.0 {
+            Some(if_match) if !if_match.precondition_passes(current) => Err(
+                AppError::PreconditionFailed("Resource has been modified".into()),
+            ),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<S> FromRequestParts<S> for Precondition
+where
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(Self(parts.headers.typed_get::<IfMatch>()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn precondition_works() {
+        let current = strong_etag(b"info");
+        assert_eq!(current, strong_etag(b"info"));
+        assert_ne!(current, strong_etag(b"debug"));
+
+        assert!(Precondition(None).check(&current).is_ok());
+        assert!(Precondition(Some(IfMatch::any())).check(&current).is_ok());
+        assert!(Precondition(Some(IfMatch::from(current.clone())))
+            .check(&current)
+            .is_ok());
+        assert!(Precondition(Some(IfMatch::from(strong_etag(b"debug"))))
+            .check(&current)
+            .is_err());
+    }
+}
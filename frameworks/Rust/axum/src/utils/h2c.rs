@@ -0,0 +1,335 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use axum::{
+    body::{Body, Bytes},
+    http::{
+        header::{CONNECTION, HOST, TE, UPGRADE},
+        HeaderName, HeaderValue, Request, Response, StatusCode, Version,
+    },
+};
+use hyper::upgrade::OnUpgrade;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Client connection preface of HTTP/2.
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+/// Default `SETTINGS_MAX_FRAME_SIZE`, the server has not announced its own
+/// yet when the upgraded request is replayed.
+const MAX_FRAME_SIZE: usize = 16_384;
+
+const HEADERS: u8 = 0x1;
+const SETTINGS: u8 = 0x4;
+const CONTINUATION: u8 = 0x9;
+const END_STREAM: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+
+static HTTP2_SETTINGS: HeaderName = HeaderName::from_static("http2-settings");
+
+/// Headers with a meaning on the HTTP/1 connection only, RFC 9113 section
+/// 8.2.2.
+static CONNECTION_HEADERS: [HeaderName; 5] = [
+    CONNECTION,
+    HeaderName::from_static("keep-alive"),
+    HeaderName::from_static("proxy-connection"),
+    axum::http::header::TRANSFER_ENCODING,
+    UPGRADE,
+];
+
+/// Take over an HTTP/1.1 request asking for `Upgrade: h2c`, RFC 7540
+/// section 3.2.
+///
+/// Returns the pending upgrade and the bytes to read before the upgraded
+/// connection: the connection preface, the client's `HTTP2-Settings` and
+/// the request itself as stream 1. Answer the request with
+/// [`switching_protocols`] and serve HTTP/2 on the upgraded connection
+/// wrapped in [`Rewind`].
+///
+/// Requests with a body are left alone, the body would have to be read
+/// before switching, they are answered over HTTP/1.1.
+pub fn upgrade<B: hyper::body::Body>(req: &mut Request<B>) -> Option<(OnUpgrade, Bytes)> {
+    let has_token = |name: &HeaderName, token: &str| {
+        req.headers()
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|item| item.trim().eq_ignore_ascii_case(token))
+    };
+    let upgrade = req.version() == Version::HTTP_11
+        && has_token(&UPGRADE, "h2c")
+        && has_token(&CONNECTION, "upgrade")
+        && has_token(&CONNECTION, "http2-settings")
+        && req.body().is_end_stream();
+    if !upgrade {
+        return None;
+    }
+    let mut settings = req.headers().get_all(&HTTP2_SETTINGS).iter();
+    let settings = match (settings.next(), settings.next()) {
+        (Some(settings), None) => decode_base64url(settings.as_bytes())?,
+        _ => return None,
+    };
+    if settings.len() % 6 != 0 {
+        return None;
+    }
+
+    let mut preface = PREFACE.to_vec();
+    frame(&mut preface, SETTINGS, 0, 0, &settings);
+    let block = header_block(req);
+    let mut chunks = block.chunks(MAX_FRAME_SIZE).peekable();
+    let mut kind = HEADERS;
+    let mut flags = END_STREAM;
+    while let Some(chunk) = chunks.next() {
+        if chunks.peek().is_none() {
+            flags |= END_HEADERS;
+        }
+        frame(&mut preface, kind, flags, 1, chunk);
+        (kind, flags) = (CONTINUATION, 0);
+    }
+    Some((hyper::upgrade::on(req), preface.into()))
+}
+
+/// `101 Switching Protocols` accepting the upgrade to h2c.
+pub fn switching_protocols() -> Response<Body> {
+    let mut res = Response::new(Body::empty());
+    *res.status_mut() = StatusCode::SWITCHING_PROTOCOLS;
+    res.headers_mut()
+        .insert(CONNECTION, HeaderValue::from_static("upgrade"));
+    res.headers_mut()
+        .insert(UPGRADE, HeaderValue::from_static("h2c"));
+    res
+}
+
+/// HPACK block of the request, as literals without indexing so no dynamic
+/// table is shared with the server's decoder.
+fn header_block<B>(req: &Request<B>) -> Vec<u8> {
+    let mut block = vec![];
+    let path = match req.uri().path_and_query() {
+        Some(path) => path.as_str(),
+        None => "/",
+    };
+    let authority = req
+        .headers()
+        .get(HOST)
+        .map(HeaderValue::as_bytes)
+        .or_else(|| {
+            req.uri()
+                .authority()
+                .map(|authority| authority.as_str().as_bytes())
+        });
+    literal(&mut block, b":method", req.method().as_str().as_bytes());
+    literal(&mut block, b":scheme", b"http");
+    literal(&mut block, b":path", path.as_bytes());
+    if let Some(authority) = authority {
+        literal(&mut block, b":authority", authority);
+    }
+    for (name, value) in req.headers() {
+        let hop_by_hop = CONNECTION_HEADERS.contains(name)
+            || name == HTTP2_SETTINGS
+            || name == HOST
+            || (name == TE && value != "trailers");
+        if !hop_by_hop {
+            literal(&mut block, name.as_str().as_bytes(), value.as_bytes());
+        }
+    }
+    block
+}
+
+/// Literal header field without indexing, with a new name and no Huffman
+/// coding, RFC 7541 section 6.2.2.
+fn literal(block: &mut Vec<u8>, name: &[u8], value: &[u8]) {
+    block.push(0);
+    for string in [name, value] {
+        integer(block, string.len(), 7);
+        block.extend_from_slice(string);
+    }
+}
+
+/// Integer with an N-bit prefix, RFC 7541 section 5.1. The flags of the
+/// first byte are zero.
+fn integer(block: &mut Vec<u8>, mut value: usize, prefix: u32) {
+    let max = (1 << prefix) - 1;
+    if value < max {
+        block.push(value as u8);
+        return;
+    }
+    block.push(max as u8);
+    value -= max;
+    while value >= 0x80 {
+        block.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    block.push(value as u8);
+}
+
+fn frame(buf: &mut Vec<u8>, kind: u8, flags: u8, stream: u32, payload: &[u8]) {
+    buf.extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+    buf.extend_from_slice(&[kind, flags]);
+    buf.extend_from_slice(&stream.to_be_bytes());
+    buf.extend_from_slice(payload);
+}
+
+/// Decode the base64url of `HTTP2-Settings`, padding is tolerated.
+fn decode_base64url(input: &[u8]) -> Option<Vec<u8>> {
+    let input = input
+        .strip_suffix(b"==")
+        .or(input.strip_suffix(b"="))
+        .unwrap_or(input);
+    let mut out = Vec::with_capacity(input.len() * 3 / 4);
+    let (mut bits, mut acc) = (0, 0u32);
+    for &byte in input {
+        let value = match byte {
+            b'A'..=b'Z' => byte - b'A',
+            b'a'..=b'z' => byte - b'a' + 26,
+            b'0'..=b'9' => byte - b'0' + 52,
+            b'-' => 62,
+            b'_' => 63,
+            _ => return None,
+        };
+        acc = (acc << 6) | u32::from(value);
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+/// Upgraded connection reading `prefix` first, in place of the connection
+/// preface the client sends after the `101`.
+pub struct Rewind<T> {
+    inner: T,
+    prefix: Bytes,
+    /// Bytes of the client's preface skipped so far.
+    skipped: usize,
+}
+
+impl<T> Rewind<T> {
+    pub fn new(inner: T, prefix: Bytes) -> Self {
+        Self {
+            inner,
+            prefix,
+            skipped: 0,
+        }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Rewind<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.prefix.is_empty() {
+            let len = this.prefix.len().min(buf.remaining());
+            buf.put_slice(&this.prefix.split_to(len));
+            return Poll::Ready(Ok(()));
+        }
+        while this.skipped < PREFACE.len() {
+            let expected = &PREFACE[this.skipped..];
+            let mut skip = [0; PREFACE.len()];
+            let mut skip = ReadBuf::new(&mut skip[..expected.len()]);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut skip))?;
+            let read = skip.filled();
+            if read.is_empty() {
+                return Poll::Ready(Ok(()));
+            }
+            if read != &expected[..read.len()] {
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "invalid HTTP/2 connection preface after h2c upgrade",
+                )));
+            }
+            this.skipped += read.len();
+        }
+        Pin::new(&mut this.inner).poll_read(cx, buf)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Rewind<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hpack_integer() {
+        // Examples of RFC 7541 appendix C.1.
+        let mut block = vec![];
+        integer(&mut block, 10, 5);
+        assert_eq!(block, [10]);
+        let mut block = vec![];
+        integer(&mut block, 1337, 5);
+        assert_eq!(block, [31, 154, 10]);
+    }
+
+    #[test]
+    fn base64url() {
+        assert_eq!(
+            decode_base64url(b"AAMAAABkAAQAoAAAAAIAAAAA").unwrap().len(),
+            18
+        );
+        assert_eq!(decode_base64url(b"_-8").unwrap(), [0xff, 0xef]);
+        assert_eq!(decode_base64url(b"_-8=").unwrap(), [0xff, 0xef]);
+        assert!(decode_base64url(b"a+b/").is_none());
+    }
+
+    #[test]
+    fn upgrade_only_bodyless_requests() {
+        let request = |body: &'static str| {
+            Request::get("/")
+                .header(HOST, "localhost")
+                .header(CONNECTION, "Upgrade, HTTP2-Settings")
+                .header(UPGRADE, "h2c")
+                .header(&HTTP2_SETTINGS, "AAMAAABkAAQAoAAAAAIAAAAA")
+                .body(http_body_util::Full::new(Bytes::from(body)))
+                .unwrap()
+        };
+        let (_, preface) = upgrade(&mut request("")).unwrap();
+        assert!(preface.starts_with(PREFACE));
+        // SETTINGS with the 18 bytes of `HTTP2-Settings`.
+        assert_eq!(
+            preface[PREFACE.len()..][..9],
+            [0, 0, 18, SETTINGS, 0, 0, 0, 0, 0]
+        );
+        let headers = &preface[PREFACE.len() + 9 + 18..];
+        assert_eq!(
+            headers[3..9],
+            [HEADERS, END_STREAM | END_HEADERS, 0, 0, 0, 1]
+        );
+
+        assert!(upgrade(&mut request("body")).is_none());
+    }
+}
@@ -11,7 +11,7 @@ use jsonwebtoken::{
 use rand::distr::{Alphanumeric, SampleString};
 use serde::{Deserialize, Serialize};
 
-use crate::error::AppError;
+use crate::{config::config, error::AppError, utils::readiness::CheckResult};
 
 pub struct Keys {
     pub encoding: EncodingKey,
@@ -27,9 +27,13 @@ impl Keys {
     }
 }
 
-pub static KEYS: LazyLock<Keys> = LazyLock::new(|| {
-    let secret = Alphanumeric.sample_string(&mut rand::rng(), 32);
-    Keys::new(secret.as_bytes())
+/// Keys from `jwt.secret`, or a random secret when it is not set.
+pub static KEYS: LazyLock<Keys> = LazyLock::new(|| match &config().jwt.secret {
+    Some(secret) => Keys::new(secret.expose().as_bytes()),
+    None => {
+        let secret = Alphanumeric.sample_string(&mut rand::rng(), 32);
+        Keys::new(secret.as_bytes())
+    }
 });
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -70,6 +74,20 @@ pub fn decode_jwt(token: &str) -> Result<TokenData<Claims>, jsonwebtoken::errors
     decode::<Claims>(token, &KEYS.decoding, &Validation::default())
 }
 
+/// Readiness check of the keys the user routes sign tokens with, a
+/// round trip of a short-lived token.
+pub async fn check_keys() -> CheckResult {
+    let now = chrono::Utc::now().timestamp() as usize;
+    let claims = Claims {
+        exp: now + 60,
+        iat: now,
+        sub: "readiness".to_string(),
+    };
+    let token = encode_jwt(&claims).map_err(|err| format!("failed to sign: {err}"))?;
+    decode_jwt(&token).map_err(|err| format!("failed to verify: {err}"))?;
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use std::time::{SystemTime, UNIX_EPOCH};
@@ -99,4 +117,9 @@ mod tests {
         assert_eq!(token_data.header.alg, Algorithm::HS256);
         assert_eq!(token_data.claims.sub, sub);
     }
+
+    #[tokio::test]
+    async fn keys_are_ready() {
+        assert_eq!(check_keys().await, Ok(()));
+    }
 }
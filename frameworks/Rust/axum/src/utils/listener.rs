@@ -0,0 +1,662 @@
+use std::{
+    fmt, io,
+    net::SocketAddr,
+    path::PathBuf,
+    pin::Pin,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::{Context as TaskContext, Poll},
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context};
+use listenfd::ListenFd;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    time::{sleep, timeout},
+};
+use tokio_rustls::server::TlsStream;
+use tracing::{debug, error, info, warn};
+
+use super::{
+    proxy_protocol::read_header,
+    tls::{ClientCert, Tls},
+};
+use crate::{config::ServerConfig, middlewares::client_ip::TrustedProxies};
+
+/// How long a peer may take to send the PROXY protocol header and finish
+/// the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Set once the sockets are handed to a new process, which keeps serving
+/// the Unix socket files.
+static KEEP_SOCKET_FILES: AtomicBool = AtomicBool::new(false);
+
+/// Address to listen on, `host:port` or `unix:<path>`, e.g. `[::]:4000` or
+/// `unix:/run/phthonus.sock`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BindAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl fmt::Display for BindAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => addr.fmt(f),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl FromStr for BindAddr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Some(path) = s.strip_prefix("unix:") {
+            return Ok(Self::Unix(PathBuf::from(path)));
+        }
+        s.parse()
+            .map(Self::Tcp)
+            .map_err(|_| anyhow!("invalid bind address `{s}`, expect `host:port` or `unix:<path>`"))
+    }
+}
+
+impl Serialize for BindAddr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for BindAddr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+/// Address of the connection peer, taken from the PROXY protocol header
+/// when it is enabled.
+///
+/// This is usually a load balancer, use
+/// [`ClientIp`](crate::middlewares::client_ip::ClientIp) to identify clients.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerAddr {
+    Tcp(SocketAddr),
+    /// Peers of Unix sockets are local processes without an address.
+    Unix,
+}
+
+impl fmt::Display for PeerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => addr.fmt(f),
+            Self::Unix => f.write_str("unix"),
+        }
+    }
+}
+
+/// Connection of a request, read with `ConnectInfo<ConnectionInfo>`.
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    pub peer: PeerAddr,
+    /// Verified certificate of the client under mutual TLS.
+    pub client_cert: Option<ClientCert>,
+}
+
+impl ConnectionInfo {
+    pub fn new(stream: &AppStream, peer: PeerAddr) -> Self {
+        let client_cert = match stream {
+            AppStream::Tls(tls) => tls
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|chain| ClientCert::new(chain.to_vec())),
+            _ => None,
+        };
+        Self { peer, client_cert }
+    }
+}
+
+/// Connection accepted by [`AppListener`].
+pub enum AppStream {
+    Tcp(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixStream),
+}
+
+macro_rules! delegate {
+    ($self:ident, $stream:ident => $call:expr) => {
+        match $self.get_mut() {
+            AppStream::Tcp($stream) => $call,
+            AppStream::Tls($stream) => $call,
+            #[cfg(unix)]
+            AppStream::Unix($stream) => $call,
+        }
+    };
+}
+
+impl AsyncRead for AppStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        delegate!(self, stream => Pin::new(stream).poll_read(cx, buf))
+    }
+}
+
+impl AsyncWrite for AppStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        delegate!(self, stream => Pin::new(stream).poll_write(cx, buf))
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        delegate!(self, stream => Pin::new(stream).poll_write_vectored(cx, bufs))
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            Self::Tcp(stream) => stream.is_write_vectored(),
+            Self::Tls(stream) => stream.is_write_vectored(),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.is_write_vectored(),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        delegate!(self, stream => Pin::new(stream).poll_flush(cx))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        delegate!(self, stream => Pin::new(stream).poll_shutdown(cx))
+    }
+}
+
+/// A bound socket, accepted from in the background.
+enum BoundSocket {
+    Tcp(TcpListener, Option<Arc<Tls>>),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener),
+}
+
+impl BoundSocket {
+    #[cfg(unix)]
+    fn try_clone_fd(&self) -> io::Result<std::os::fd::OwnedFd> {
+        use std::os::fd::AsFd;
+
+        match self {
+            Self::Tcp(listener, _) => listener.as_fd().try_clone_to_owned(),
+            Self::Unix(listener) => listener.as_fd().try_clone_to_owned(),
+        }
+    }
+
+    async fn accept(&self) -> io::Result<(AppStream, PeerAddr)> {
+        match self {
+            Self::Tcp(listener, _) => {
+                let (stream, addr) = TcpListener::accept(listener).await?;
+                Ok((AppStream::Tcp(stream), PeerAddr::Tcp(addr)))
+            }
+            #[cfg(unix)]
+            Self::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((AppStream::Unix(stream), PeerAddr::Unix))
+            }
+        }
+    }
+}
+
+/// Listener of the server, accepting from every bound address.
+pub struct AppListener {
+    local_addrs: Vec<BindAddr>,
+    /// Connections accepted in the background, with their PROXY protocol
+    /// header read when it is enabled.
+    rx: mpsc::Receiver<(AppStream, PeerAddr)>,
+    /// Duplicates of the bound sockets, handed to a new process on upgrade.
+    #[cfg(unix)]
+    fds: Vec<std::os::fd::OwnedFd>,
+    /// Removes the Unix socket files once the server stops.
+    _socket_files: Vec<SocketFile>,
+}
+
+impl AppListener {
+    /// Bind every address of `server.bind`, or `server.host` and
+    /// `server.port` when it is empty.
+    ///
+    /// A PROXY protocol header is expected on every connection when
+    /// `server.proxy_protocol` is set. Headers are only accepted from
+    /// `server.trusted_proxies` and Unix sockets, connections from other
+    /// peers are dropped.
+    ///
+    /// TCP addresses serve HTTPS when `tls` is set, its certificates are
+    /// reloaded in the background.
+    ///
+    /// Sockets passed by systemd socket activation, or by the process this
+    /// one upgrades, are served instead of `server.bind`.
+    pub fn bind(config: &ServerConfig, tls: Option<Arc<Tls>>) -> anyhow::Result<Self> {
+        let proxies = config
+            .proxy_protocol
+            .then(|| Arc::new(config.trusted_proxies.clone()));
+        let (tx, rx) = mpsc::channel(128);
+        if let Some(tls) = &tls {
+            let (tls, tx) = (tls.clone(), tx.clone());
+            tokio::spawn(async move {
+                tokio::select! {
+                    _ = tls.watch() => {}
+                    _ = tx.closed() => {}
+                }
+            });
+        }
+        let mut sockets = vec![];
+        let mut socket_files = vec![];
+        let mut listen_fd = ListenFd::from_env();
+        if listen_fd.len() > 0 {
+            info!("serving {} inherited sockets", listen_fd.len());
+            for idx in 0..listen_fd.len() {
+                let socket = inherit(&mut listen_fd, idx, &tls)
+                    .with_context(|| format!("invalid inherited socket {idx}"))?;
+                sockets.push(socket);
+            }
+        } else {
+            for addr in config.bind_addrs() {
+                let socket = match &addr {
+                    BindAddr::Tcp(addr) => {
+                        let listener = bind_tcp(*addr, config.ipv6_only)
+                            .with_context(|| format!("failed to bind {addr}"))?;
+                        let local_addr = BindAddr::Tcp(listener.local_addr()?);
+                        (local_addr, BoundSocket::Tcp(listener, tls.clone()))
+                    }
+                    #[cfg(unix)]
+                    BindAddr::Unix(path) => {
+                        let (listener, file) = unix::bind(path, config)
+                            .with_context(|| format!("failed to bind {addr}"))?;
+                        socket_files.push(file);
+                        (addr.clone(), BoundSocket::Unix(listener))
+                    }
+                    #[cfg(not(unix))]
+                    BindAddr::Unix(_) => bail!("Unix sockets are not supported, remove {addr}"),
+                };
+                sockets.push(socket);
+            }
+        }
+
+        let mut local_addrs = vec![];
+        #[cfg(unix)]
+        let mut fds = vec![];
+        for (addr, socket) in sockets {
+            #[cfg(unix)]
+            fds.push(socket.try_clone_fd()?);
+            local_addrs.push(addr);
+            tokio::spawn(accept(socket, proxies.clone(), tx.clone()));
+        }
+        Ok(Self {
+            local_addrs,
+            rx,
+            #[cfg(unix)]
+            fds,
+            _socket_files: socket_files,
+        })
+    }
+
+    /// Bound addresses, with the actual port of addresses bound to port 0.
+    pub fn local_addrs(&self) -> &[BindAddr] {
+        &self.local_addrs
+    }
+
+    /// Duplicates of the bound sockets, for handing them to a new process.
+    #[cfg(unix)]
+    pub fn try_clone_fds(&self) -> io::Result<Vec<std::os::fd::OwnedFd>> {
+        self.fds.iter().map(|fd| fd.try_clone()).collect()
+    }
+
+    /// The next connection, ready to serve HTTP on.
+    pub async fn accept(&mut self) -> (AppStream, PeerAddr) {
+        match self.rx.recv().await {
+            Some(conn) => conn,
+            // The acceptors only stop once the receiver is dropped.
+            None => std::future::pending().await,
+        }
+    }
+}
+
+/// Take the inherited socket at `idx`, TCP sockets serve HTTPS when `tls`
+/// is set.
+///
+/// Unix socket files of inherited sockets are left in place, they belong to
+/// systemd or the process that bound them.
+fn inherit(
+    listen_fd: &mut ListenFd,
+    idx: usize,
+    tls: &Option<Arc<Tls>>,
+) -> anyhow::Result<(BindAddr, BoundSocket)> {
+    if let Ok(Some(listener)) = listen_fd.take_tcp_listener(idx) {
+        listener.set_nonblocking(true)?;
+        let addr = BindAddr::Tcp(listener.local_addr()?);
+        return Ok((
+            addr,
+            BoundSocket::Tcp(TcpListener::from_std(listener)?, tls.clone()),
+        ));
+    }
+    #[cfg(unix)]
+    if let Some(listener) = listen_fd.take_unix_listener(idx)? {
+        listener.set_nonblocking(true)?;
+        let path = listener.local_addr()?.as_pathname().map(PathBuf::from);
+        let addr = BindAddr::Unix(path.unwrap_or_default());
+        return Ok((
+            addr,
+            BoundSocket::Unix(tokio::net::UnixListener::from_std(listener)?),
+        ));
+    }
+    bail!("not a TCP or Unix socket")
+}
+
+/// Keep the Unix socket files when the server stops, the sockets were
+/// handed to a new process.
+#[cfg(unix)]
+pub fn keep_socket_files() {
+    KEEP_SOCKET_FILES.store(true, Ordering::SeqCst);
+}
+
+/// Bind a TCP socket, IPv6 sockets also accept IPv4 unless `ipv6_only`.
+fn bind_tcp(addr: SocketAddr, ipv6_only: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(ipv6_only)?;
+    }
+    // Same as tokio, allow rebinding while old connections are in TIME_WAIT.
+    #[cfg(not(windows))]
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Accept connections of `socket`, their PROXY protocol header is read
+/// when `proxies` is set and TLS is handshaked concurrently, so a slow peer
+/// can not block the others.
+async fn accept(
+    socket: BoundSocket,
+    proxies: Option<Arc<TrustedProxies>>,
+    tx: mpsc::Sender<(AppStream, PeerAddr)>,
+) {
+    let tls = match &socket {
+        BoundSocket::Tcp(_, tls) => tls.clone(),
+        #[cfg(unix)]
+        BoundSocket::Unix(_) => None,
+    };
+    loop {
+        let (stream, peer) = tokio::select! {
+            conn = socket.accept() => match conn {
+                Ok(conn) => conn,
+                Err(err) => {
+                    handle_accept_error(err).await;
+                    continue;
+                }
+            },
+            _ = tx.closed() => return,
+        };
+        if proxies.is_none() && tls.is_none() {
+            tx.send((stream, peer)).await.ok();
+            continue;
+        }
+        if let Some(proxies) = &proxies {
+            let trusted = match peer {
+                PeerAddr::Tcp(addr) => proxies.contains(addr.ip()),
+                PeerAddr::Unix => true,
+            };
+            if !trusted {
+                warn!("reject connection from untrusted proxy {peer}");
+                continue;
+            }
+        }
+        let (tx, tls, proxy_protocol) = (tx.clone(), tls.clone(), proxies.is_some());
+        tokio::spawn(async move {
+            match timeout(
+                HANDSHAKE_TIMEOUT,
+                handshake(stream, peer, proxy_protocol, tls),
+            )
+            .await
+            {
+                Ok(Ok(conn)) => {
+                    tx.send(conn).await.ok();
+                }
+                Ok(Err(err)) => debug!("handshake with {peer} failed: {err:#}"),
+                Err(_) => debug!("handshake with {peer} timed out"),
+            }
+        });
+    }
+}
+
+/// Read the PROXY protocol header, then handshake TLS.
+async fn handshake(
+    mut stream: AppStream,
+    mut peer: PeerAddr,
+    proxy_protocol: bool,
+    tls: Option<Arc<Tls>>,
+) -> anyhow::Result<(AppStream, PeerAddr)> {
+    if proxy_protocol {
+        let source = read_header(&mut stream)
+            .await
+            .context("invalid PROXY protocol header")?;
+        peer = source.map_or(peer, PeerAddr::Tcp);
+    }
+    let stream = match (stream, tls) {
+        (AppStream::Tcp(stream), Some(tls)) => {
+            let stream = tls
+                .acceptor()
+                .accept(stream)
+                .await
+                .context("invalid TLS handshake")?;
+            AppStream::Tls(Box::new(stream))
+        }
+        (stream, _) => stream,
+    };
+    Ok((stream, peer))
+}
+
+/// Same as axum, connection errors only affect a single connection, others
+/// like running out of file descriptors are retried after a while.
+async fn handle_accept_error(err: io::Error) {
+    if matches!(
+        err.kind(),
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+    ) {
+        return;
+    }
+    error!("accept error: {err}");
+    sleep(Duration::from_secs(1)).await;
+}
+
+/// A Unix socket file, removed on drop.
+struct SocketFile(PathBuf);
+
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        if KEEP_SOCKET_FILES.load(Ordering::SeqCst) {
+            return;
+        }
+        if let Err(err) = std::fs::remove_file(&self.0) {
+            warn!("failed to remove socket {}: {err}", self.0.display());
+        }
+    }
+}
+
+#[cfg(unix)]
+mod unix {
+    use std::{
+        fs::{self, DirBuilder, Permissions},
+        io,
+        os::unix::fs::{chown, DirBuilderExt, FileTypeExt, PermissionsExt},
+        path::{Path, PathBuf},
+        process,
+    };
+
+    use anyhow::{bail, Context};
+    use tokio::net::UnixListener;
+    use tracing::{info, warn};
+
+    use super::SocketFile;
+    use crate::config::ServerConfig;
+
+    /// Bind a Unix socket at `path` with the permissions and owner of
+    /// `server.unix_*`, replacing a stale socket left by a crashed process.
+    pub fn bind(path: &Path, config: &ServerConfig) -> anyhow::Result<(UnixListener, SocketFile)> {
+        remove_stale(path)?;
+        let mode = config.unix_mode()?;
+        if mode.is_none() && config.unix_uid.is_none() && config.unix_gid.is_none() {
+            let listener = UnixListener::bind(path)?;
+            return Ok((listener, SocketFile(path.to_path_buf())));
+        }
+        // Bound in a directory only this user can enter and moved into place
+        // once restricted, so no one can connect with the default mode.
+        let dir = PrivateDir::create(path)?;
+        let staged = dir.0.join("s");
+        let listener = UnixListener::bind(&staged)?;
+        if let Some(mode) = mode {
+            fs::set_permissions(&staged, Permissions::from_mode(mode))
+                .context("failed to set socket permissions")?;
+        }
+        if config.unix_uid.is_some() || config.unix_gid.is_some() {
+            chown(&staged, config.unix_uid, config.unix_gid)
+                .context("failed to set socket owner")?;
+        }
+        fs::rename(&staged, path).context("failed to move socket into place")?;
+        Ok((listener, SocketFile(path.to_path_buf())))
+    }
+
+    /// A directory of mode 700 next to a socket, removed on drop.
+    struct PrivateDir(PathBuf);
+
+    impl PrivateDir {
+        fn create(socket: &Path) -> anyhow::Result<Self> {
+            let parent = match socket.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => parent,
+                _ => Path::new("."),
+            };
+            // Short, the path of a socket is limited to about 100 bytes.
+            let dir = parent.join(format!(".phthonus-{}", process::id()));
+            DirBuilder::new()
+                .mode(0o700)
+                .create(&dir)
+                .with_context(|| format!("failed to create {}", dir.display()))?;
+            Ok(Self(dir))
+        }
+    }
+
+    impl Drop for PrivateDir {
+        fn drop(&mut self) {
+            if let Err(err) = fs::remove_dir_all(&self.0) {
+                warn!("failed to remove {}: {err}", self.0.display());
+            }
+        }
+    }
+
+    /// Remove the socket at `path` unless another process is listening on
+    /// it.
+    fn remove_stale(path: &Path) -> anyhow::Result<()> {
+        let Ok(metadata) = fs::symlink_metadata(path) else {
+            return Ok(());
+        };
+        if !metadata.file_type().is_socket() {
+            bail!("{} exists and is not a socket", path.display());
+        }
+        match std::os::unix::net::UnixStream::connect(path) {
+            Ok(_) => bail!("{} is in use by another process", path.display()),
+            Err(err) if err.kind() == io::ErrorKind::ConnectionRefused => {
+                fs::remove_file(path).context("failed to remove stale socket")?;
+                info!("removed stale socket {}", path.display());
+                Ok(())
+            }
+            Err(err) => Err(err).context("failed to check existing socket"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[test]
+    fn parse_bind_addr() {
+        assert_eq!(
+            "[::]:4000".parse::<BindAddr>().unwrap(),
+            BindAddr::Tcp("[::]:4000".parse().unwrap())
+        );
+        let unix = "unix:/run/phthonus.sock".parse::<BindAddr>().unwrap();
+        assert_eq!(unix, BindAddr::Unix("/run/phthonus.sock".into()));
+        assert_eq!(unix.to_string(), "unix:/run/phthonus.sock");
+        assert!("localhost".parse::<BindAddr>().is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn accept_from_every_address() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir().join(format!("phthonus-{}.sock", std::process::id()));
+        // A socket left behind by a crashed process.
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        let config = ServerConfig {
+            bind: vec!["127.0.0.1:0".parse().unwrap(), BindAddr::Unix(path.clone())],
+            unix_mode: Some("600".to_string()),
+            ..Default::default()
+        };
+        let mut listener = AppListener::bind(&config, None).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let staging = std::env::temp_dir().join(format!(".phthonus-{}", std::process::id()));
+        assert!(!staging.exists(), "staging directory is removed");
+
+        let BindAddr::Tcp(tcp) = listener.local_addrs()[0] else {
+            panic!("expect tcp address");
+        };
+        let mut client = TcpStream::connect(tcp).await.unwrap();
+        let (mut stream, peer) = listener.accept().await;
+        assert_eq!(peer, PeerAddr::Tcp(client.local_addr().unwrap()));
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        let _client = tokio::net::UnixStream::connect(&path).await.unwrap();
+        let (_, peer) = listener.accept().await;
+        assert_eq!(peer, PeerAddr::Unix);
+
+        assert!(
+            AppListener::bind(
+                &ServerConfig {
+                    bind: vec![BindAddr::Unix(path.clone())],
+                    ..Default::default()
+                },
+                None
+            )
+            .is_err(),
+            "socket in use"
+        );
+        drop(listener);
+        assert!(!path.exists(), "socket is removed");
+    }
+}
@@ -0,0 +1,87 @@
+use std::sync::OnceLock;
+
+use anyhow::{anyhow, Context};
+use tracing::{info, warn};
+use tracing_subscriber::{reload, EnvFilter, Registry};
+
+use crate::{
+    config::Config,
+    error::{AppError, AppResult},
+};
+
+/// Default filter when `log.filter` is not set.
+pub const DEFAULT_LOG_FILTER: &str = "info";
+
+type FilterHandle = reload::Handle<EnvFilter, Registry>;
+
+/// Handle of the application log filter, set once by [`reloadable_filter`].
+static HANDLE: OnceLock<FilterHandle> = OnceLock::new();
+
+/// Build the filter from the `log.filter` directives.
+pub fn env_filter(config: &Config) -> anyhow::Result<EnvFilter> {
+    EnvFilter::try_new(&config.log.filter)
+        .with_context(|| format!("invalid log.filter `{}`", config.log.filter))
+}
+
+/// Wrap the filter in a reloadable layer and keep its handle, so the level
+/// can be changed at runtime by [`set_log_filter`].
+pub fn reloadable_filter(filter: EnvFilter) -> reload::Layer<EnvFilter, Registry> {
+    let (layer, handle) = reload::Layer::new(filter);
+    HANDLE.set(handle).ok();
+    layer
+}
+
+fn handle() -> anyhow::Result<&'static FilterHandle> {
+    HANDLE
+        .get()
+        .ok_or_else(|| anyhow!("log filter is not reloadable"))
+}
+
+/// Current filter directives of the application log.
+pub fn log_filter() -> anyhow::Result<String> {
+    handle()?
+        .with_current(|filter| filter.to_string())
+        .context("failed to read log filter")
+}
+
+/// Replace the filter directives of the application log.
+pub fn set_log_filter(directives: &str) -> AppResult<()> {
+    let filter = EnvFilter::try_new(directives).map_err(|err| {
+        AppError::InvalidParameter(format!("invalid log filter `{directives}`: {err}").into())
+    })?;
+    handle()?
+        .reload(filter)
+        .context("failed to reload log filter")?;
+    info!("log filter changed to `{directives}`");
+    Ok(())
+}
+
+/// Reload the config each time the process receives `SIGHUP` and apply its
+/// log filter. `.env` is read again, still below the environment.
+#[cfg(unix)]
+pub async fn reload_on_sighup(config: &'static Config) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(err) => {
+            warn!("failed to install SIGHUP handler: {err}");
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        let reloaded = match config.reload() {
+            Ok(reloaded) => reloaded,
+            Err(err) => {
+                warn!("reload config on SIGHUP failed: {err:#}");
+                continue;
+            }
+        };
+        if let Err(err) = set_log_filter(&reloaded.log.filter) {
+            warn!("reload log filter on SIGHUP failed: {err}");
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub async fn reload_on_sighup(_config: &'static Config) {}
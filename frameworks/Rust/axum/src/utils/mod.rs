@@ -1,21 +1,62 @@
 use tokio::signal;
-use tracing_subscriber::{fmt, prelude::*, registry, EnvFilter};
+use tracing_subscriber::{fmt, prelude::*, registry};
 
+use crate::{
+    config::Config,
+    middlewares::access_log::{access_log_layer, exclude_access_log},
+};
+use log_level::{env_filter, reloadable_filter};
+use redact::Redacted;
+
+pub mod admin;
+pub mod duration;
+pub mod etag;
+pub mod h2c;
 pub mod jwt;
+pub mod listener;
+pub mod log_level;
+#[cfg(feature = "otel")]
+pub mod otel;
 pub mod password;
+pub mod process;
+pub mod proxy_protocol;
+pub mod readiness;
+pub mod redact;
+pub mod server;
+pub mod shutdown;
+pub mod size;
+pub mod tls;
+#[cfg(unix)]
+pub mod upgrade;
 pub mod validator;
 
 /// Initializes the logger for tracing.
-pub fn init_logger() {
+///
+/// Application logs are written to stdout, access logs are written to
+/// their own sink, see [`access_log_layer`]. Application logs and exported
+/// spans only see fields masked by [`Redacted`].
+///
+/// The filter of application logs is read from `log.filter` and can be
+/// changed at runtime, see [`log_level`]. Spans are exported over OTLP when
+/// `otlp.endpoint` is set, filtered by `otlp.filter`, see [`otel`].
+pub fn init_logger(config: &Config) -> anyhow::Result<()> {
+    let env_layer = reloadable_filter(env_filter(config)?);
+
     let formatting_layer = fmt::layer()
         // .pretty()
         .with_thread_ids(false)
         .with_target(false)
-        .with_writer(std::io::stdout);
-
-    let env_layer = EnvFilter::try_from_env("axum").unwrap_or_else(|_| "info".into());
+        .with_writer(std::io::stdout)
+        .with_filter(exclude_access_log())
+        .with_filter(env_layer);
 
-    registry().with(env_layer).with(formatting_layer).init();
+    let registry = registry()
+        .with(Redacted(formatting_layer))
+        .with(access_log_layer(&config.log)?);
+    #[cfg(feature = "otel")]
+    let registry = registry.with(otel::otel_layer(config)?.map(Redacted));
+    registry.init();
+    Ok(())
 }
 
 /// Asynchronously waits for a shutdown signal and executes a callback function when a signal is received.
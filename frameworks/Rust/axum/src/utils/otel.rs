@@ -0,0 +1,313 @@
+use std::sync::OnceLock;
+
+use anyhow::Context as _;
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
+use opentelemetry::{
+    global,
+    propagation::{Extractor, Injector},
+    trace::TracerProvider,
+    Context, KeyValue,
+};
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{
+    propagation::TraceContextPropagator,
+    trace::{Sampler, SdkTracer, SdkTracerProvider},
+    Resource,
+};
+use tracing::{warn, Span, Subscriber};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{registry::LookupSpan, EnvFilter, Layer};
+
+use crate::{
+    config::{Config, OtlpProtocol},
+    consts::{NAME, VERSION},
+    utils::shutdown::register_hook,
+};
+
+/// Tracer provider of the OTLP exporter, kept for flushing on shutdown.
+static PROVIDER: OnceLock<SdkTracerProvider> = OnceLock::new();
+
+/// OTLP export settings.
+#[derive(Debug, Clone)]
+pub struct OtelConfig {
+    /// Collector address, e.g. `http://localhost:4317` for gRPC or
+    /// `http://localhost:4318` for HTTP.
+    pub endpoint: String,
+    pub protocol: OtlpProtocol,
+    /// Ratio of root traces to sample, from `0.0` to `1.0`.
+    pub sample_ratio: f64,
+    /// Filter directives of exported spans and events.
+    pub filter: String,
+}
+
+impl OtelConfig {
+    /// Settings of the `otlp` section, the filter falls back to
+    /// `log.filter`. Returns `None` when no endpoint is set.
+    pub fn from_config(config: &Config) -> Option<Self> {
+        let otlp = &config.otlp;
+        let endpoint = otlp
+            .endpoint
+            .clone()
+            .filter(|endpoint| !endpoint.is_empty())?;
+        Some(Self {
+            endpoint,
+            protocol: otlp.protocol,
+            sample_ratio: otlp.sample_ratio,
+            filter: otlp
+                .filter
+                .clone()
+                .unwrap_or_else(|| config.log.filter.clone()),
+        })
+    }
+}
+
+/// Build a tracer provider exporting spans to the collector in batches.
+pub fn tracer_provider(config: &OtelConfig) -> anyhow::Result<SdkTracerProvider> {
+    let exporter = match config.protocol {
+        OtlpProtocol::Grpc => SpanExporter::builder()
+            .with_tonic()
+            .with_endpoint(&config.endpoint)
+            .build(),
+        OtlpProtocol::Http => SpanExporter::builder()
+            .with_http()
+            .with_endpoint(format!(
+                "{}/v1/traces",
+                config.endpoint.trim_end_matches('/')
+            ))
+            .build(),
+    }
+    .context("failed to build OTLP exporter")?;
+
+    let resource = Resource::builder()
+        .with_service_name(NAME)
+        .with_attribute(KeyValue::new("service.version", VERSION))
+        .build();
+    let sampler = Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(config.sample_ratio)));
+
+    Ok(SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(resource)
+        .with_sampler(sampler)
+        .build())
+}
+
+/// Build the tracing layer exporting spans over OTLP.
+///
+/// Returns `None` when `otlp.endpoint` is not set. Must be called inside the
+/// Tokio runtime, the gRPC exporter spawns on it.
+pub fn otel_layer<S>(config: &Config) -> anyhow::Result<Option<Box<dyn Layer<S> + Send + Sync>>>
+where
+    S: Subscriber + for<'a> LookupSpan<'a> + Send + Sync,
+{
+    let Some(config) = OtelConfig::from_config(config) else {
+        return Ok(None);
+    };
+    let filter = EnvFilter::try_new(&config.filter)
+        .with_context(|| format!("invalid otlp.filter `{}`", config.filter))?;
+    let provider = tracer_provider(&config)?;
+    let tracer = provider.tracer(NAME);
+    PROVIDER.set(provider).ok();
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    register_hook("tracer", || async {
+        tokio::task::spawn_blocking(shutdown_tracer).await?;
+        Ok(())
+    });
+
+    Ok(Some(export_layer(tracer, filter)))
+}
+
+/// Layer exporting the spans and events `filter` enables to `tracer`.
+fn export_layer<S>(tracer: SdkTracer, filter: EnvFilter) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: Subscriber + for<'a> LookupSpan<'a> + Send + Sync,
+{
+    tracing_opentelemetry::layer()
+        .with_tracer(tracer)
+        .with_filter(filter)
+        .boxed()
+}
+
+/// Flush pending spans and stop the exporter.
+fn shutdown_tracer() {
+    if let Some(provider) = PROVIDER.get() {
+        if let Err(err) = provider.shutdown() {
+            warn!("failed to shutdown tracer provider: {err}");
+        }
+    }
+}
+
+/// Use the W3C `traceparent` of the incoming request as the parent of `span`.
+pub fn set_remote_parent(span: &Span, headers: &HeaderMap) {
+    if PROVIDER.get().is_none() {
+        return;
+    }
+    let cx =
+        global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers)));
+    span.set_parent(cx).ok();
+}
+
+/// Write the W3C `traceparent` of `span` into the outgoing headers.
+pub fn inject_context(span: &Span, headers: &mut HeaderMap) {
+    if PROVIDER.get().is_none() {
+        return;
+    }
+    let cx: Context = span.context();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&cx, &mut HeaderInjector(headers))
+    });
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
+
+struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(key.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            self.0.insert(name, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use axum::{body::Bytes, routing::post, Router};
+    use opentelemetry::propagation::TextMapPropagator;
+    use opentelemetry_proto::tonic::collector::trace::v1::ExportTraceServiceRequest;
+    use prost::Message;
+    use tokio::net::TcpListener;
+    use tracing::{debug, info, info_span};
+    use tracing_subscriber::prelude::*;
+
+    use super::*;
+
+    /// Spawn an in-process OTLP/HTTP collector that keeps every request.
+    async fn collector() -> (String, Arc<Mutex<Vec<ExportTraceServiceRequest>>>) {
+        let received = Arc::new(Mutex::new(vec![]));
+        let store = received.clone();
+        let app = Router::new().route(
+            "/v1/traces",
+            post(move |body: Bytes| async move {
+                let req = ExportTraceServiceRequest::decode(body).expect("valid OTLP payload");
+                store.lock().unwrap().push(req);
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (format!("http://{addr}"), received)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn export_spans_over_http() {
+        let (endpoint, received) = collector().await;
+        let config = OtelConfig {
+            endpoint,
+            protocol: OtlpProtocol::Http,
+            sample_ratio: 1.0,
+            filter: "info".into(),
+        };
+        let provider = tracer_provider(&config).unwrap();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer(NAME)));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "traceparent",
+            HeaderValue::from_static("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"),
+        );
+        tracing::subscriber::with_default(subscriber, || {
+            let span = info_span!("HTTP");
+            let cx = TraceContextPropagator::new().extract(&HeaderExtractor(&headers));
+            span.set_parent(cx).unwrap();
+            let _entered = span.enter();
+        });
+        tokio::task::spawn_blocking(move || provider.force_flush())
+            .await
+            .unwrap()
+            .unwrap();
+
+        let received = received.lock().unwrap();
+        let resource_spans = &received[0].resource_spans[0];
+        let resource = resource_spans.resource.as_ref().unwrap();
+        assert!(resource
+            .attributes
+            .iter()
+            .any(|attr| attr.key == "service.name" && format!("{:?}", attr.value).contains(NAME)));
+        let span = &resource_spans.scope_spans[0].spans[0];
+        assert_eq!(span.name, "HTTP");
+        assert_eq!(
+            hex(&span.trace_id),
+            "4bf92f3577b34da6a3ce929d0e0e4736",
+            "trace id should come from traceparent"
+        );
+        assert_eq!(hex(&span.parent_span_id), "00f067aa0ba902b7");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn export_only_enabled_events() {
+        let (endpoint, received) = collector().await;
+        let config = Config::from_toml(&format!(
+            "[log]\nfilter = \"debug\"\n[otlp]\nendpoint = \"{endpoint}\"\nprotocol = \"http\"\nfilter = \"info\""
+        ))
+        .unwrap();
+        let config = OtelConfig::from_config(&config).unwrap();
+        let provider = tracer_provider(&config).unwrap();
+        let filter = EnvFilter::new(&config.filter);
+        let subscriber =
+            tracing_subscriber::registry().with(export_layer(provider.tracer(NAME), filter));
+
+        tracing::subscriber::with_default(subscriber, || {
+            let _entered = info_span!("HTTP").entered();
+            debug!("request body");
+            info!("response sent");
+        });
+        tokio::task::spawn_blocking(move || provider.force_flush())
+            .await
+            .unwrap()
+            .unwrap();
+
+        let received = received.lock().unwrap();
+        let span = &received[0].resource_spans[0].scope_spans[0].spans[0];
+        let events = span
+            .events
+            .iter()
+            .map(|event| event.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(events, ["response sent"], "debug events are filtered out");
+    }
+
+    #[test]
+    fn inject_traceparent() {
+        let cx =
+            TraceContextPropagator::new().extract(&HeaderExtractor(&HeaderMap::from_iter([(
+                HeaderName::from_static("traceparent"),
+                HeaderValue::from_static("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"),
+            )])));
+        let mut headers = HeaderMap::new();
+        TraceContextPropagator::new().inject_context(&cx, &mut HeaderInjector(&mut headers));
+        assert_eq!(
+            headers["traceparent"],
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"
+        );
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{b:02x}")).collect()
+    }
+}
@@ -0,0 +1,122 @@
+use std::fs;
+
+use metrics::{counter, gauge};
+
+/// `USER_HZ`, the unit of cpu times in `/proc`, is fixed to 100 on Linux.
+const CLOCK_TICKS: f64 = 100.0;
+
+/// Process stats read from `/proc/self`.
+#[derive(Debug, Default, PartialEq)]
+pub struct ProcessStats {
+    pub cpu_seconds: f64,
+    pub resident_memory_bytes: u64,
+    pub virtual_memory_bytes: u64,
+    pub threads: u64,
+    pub open_fds: u64,
+    pub max_fds: Option<u64>,
+}
+
+impl ProcessStats {
+    /// Read stats of the current process, returns `None` on platforms
+    /// without `/proc`.
+    pub fn read() -> Option<Self> {
+        let stat = fs::read_to_string("/proc/self/stat").ok()?;
+        let status = fs::read_to_string("/proc/self/status").ok()?;
+        let mut stats = Self::parse(&stat, &status)?;
+        // Listing the directory opens one more fd, which is not counted.
+        stats.open_fds = (fs::read_dir("/proc/self/fd").ok()?.count() as u64).saturating_sub(1);
+        stats.max_fds = fs::read_to_string("/proc/self/limits")
+            .ok()
+            .and_then(|limits| parse_max_fds(&limits));
+        Some(stats)
+    }
+
+    fn parse(stat: &str, status: &str) -> Option<Self> {
+        // The command name may contain spaces, fields start after the last `)`.
+        let fields = stat
+            .get(stat.rfind(')')? + 1..)?
+            .split_whitespace()
+            .collect::<Vec<_>>();
+        // utime and stime are the 14th and 15th fields, `fields` starts at the 3rd.
+        let utime = fields.get(11)?.parse::<u64>().ok()?;
+        let stime = fields.get(12)?.parse::<u64>().ok()?;
+
+        let kb = |key: &str| {
+            status
+                .lines()
+                .find_map(|line| line.strip_prefix(key))
+                .and_then(|value| {
+                    value
+                        .trim()
+                        .trim_end_matches("kB")
+                        .trim()
+                        .parse::<u64>()
+                        .ok()
+                })
+                .map(|kb| kb * 1024)
+        };
+        let threads = status
+            .lines()
+            .find_map(|line| line.strip_prefix("Threads:"))
+            .and_then(|value| value.trim().parse().ok())
+            .unwrap_or_default();
+
+        Some(Self {
+            cpu_seconds: (utime + stime) as f64 / CLOCK_TICKS,
+            resident_memory_bytes: kb("VmRSS:").unwrap_or_default(),
+            virtual_memory_bytes: kb("VmSize:").unwrap_or_default(),
+            threads,
+            ..Default::default()
+        })
+    }
+
+    /// Export stats with the standard Prometheus process metric names.
+    pub fn record(&self) {
+        // Counters only take integers, so whole seconds are exported.
+        counter!("process_cpu_seconds_total").absolute(self.cpu_seconds as u64);
+        gauge!("process_resident_memory_bytes").set(self.resident_memory_bytes as f64);
+        gauge!("process_virtual_memory_bytes").set(self.virtual_memory_bytes as f64);
+        gauge!("process_threads").set(self.threads as f64);
+        gauge!("process_open_fds").set(self.open_fds as f64);
+        if let Some(max_fds) = self.max_fds {
+            gauge!("process_max_fds").set(max_fds as f64);
+        }
+    }
+}
+
+fn parse_max_fds(limits: &str) -> Option<u64> {
+    limits
+        .lines()
+        .find(|line| line.starts_with("Max open files"))?
+        .split_whitespace()
+        .nth(3)?
+        .parse()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_proc_works() {
+        let stat =
+            "1234 (php thonus) S 1 1234 1234 0 -1 4194560 1000 0 0 0 250 50 0 0 20 0 8 0 100";
+        let status = "Name:\tphthonus\nVmSize:\t  2048 kB\nVmRSS:\t  1024 kB\nThreads:\t8\n";
+        let stats = ProcessStats::parse(stat, status).unwrap();
+        assert_eq!(
+            stats,
+            ProcessStats {
+                cpu_seconds: 3.0,
+                resident_memory_bytes: 1024 * 1024,
+                virtual_memory_bytes: 2048 * 1024,
+                threads: 8,
+                open_fds: 0,
+                max_fds: None,
+            }
+        );
+
+        let limits = "Limit                     Soft Limit           Hard Limit           Units     \nMax open files            1024                 524288               files     \n";
+        assert_eq!(parse_max_fds(limits), Some(1024));
+    }
+}
@@ -0,0 +1,172 @@
+//! HAProxy PROXY protocol, see
+//! <https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt>.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use anyhow::{anyhow, bail, Context};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Signature of a version 2 header.
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// Longest version 1 header, including the trailing CRLF.
+const V1_MAX_LEN: usize = 107;
+
+/// Read the PROXY protocol header at the beginning of `stream`.
+///
+/// Returns the source address of the original connection, or `None` for
+/// `UNKNOWN` and `LOCAL` headers, which are sent by the proxy itself, e.g.
+/// for health checks. Nothing after the header is consumed.
+pub async fn read_header<R>(stream: &mut R) -> anyhow::Result<Option<SocketAddr>>
+where
+    R: AsyncRead + Unpin,
+{
+    // Both versions are longer than the signature.
+    let mut prefix = [0; 12];
+    stream.read_exact(&mut prefix).await?;
+
+    if prefix == V2_SIGNATURE {
+        let mut header = [0; 4];
+        stream.read_exact(&mut header).await?;
+        let len = u16::from_be_bytes([header[2], header[3]]) as usize;
+        let mut addresses = vec![0; len];
+        stream.read_exact(&mut addresses).await?;
+        return parse_v2(header[0], header[1], &addresses);
+    }
+
+    if !prefix.starts_with(b"PROXY ") {
+        bail!("missing PROXY protocol header");
+    }
+    // Read byte by byte so the request after the line stays in the stream.
+    let mut line = prefix.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LEN {
+            bail!("PROXY protocol header too long");
+        }
+        line.push(stream.read_u8().await?);
+    }
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .context("PROXY protocol header is not ascii")?;
+    parse_v1(line)
+}
+
+/// Parse a version 1 header line without the trailing CRLF, e.g.
+/// `PROXY TCP4 192.0.2.1 192.0.2.2 56324 443`.
+pub fn parse_v1(line: &str) -> anyhow::Result<Option<SocketAddr>> {
+    let mut parts = line.split(' ');
+    if parts.next() != Some("PROXY") {
+        bail!("missing PROXY protocol header");
+    }
+    let protocol = parts.next().unwrap_or_default();
+    if protocol == "UNKNOWN" {
+        return Ok(None);
+    }
+    let [Some(source), Some(_destination), Some(port), Some(_), None] = [
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+    ] else {
+        bail!("malformed PROXY protocol header `{line}`");
+    };
+    let ip = source
+        .parse::<IpAddr>()
+        .with_context(|| format!("invalid source address `{source}`"))?;
+    match (protocol, ip) {
+        ("TCP4", IpAddr::V4(_)) | ("TCP6", IpAddr::V6(_)) => {}
+        _ => bail!("address `{source}` does not match protocol `{protocol}`"),
+    }
+    let port = port
+        .parse::<u16>()
+        .with_context(|| format!("invalid source port `{port}`"))?;
+    Ok(Some(SocketAddr::new(ip, port)))
+}
+
+/// Parse the version/command byte, the family byte and the address block
+/// of a version 2 header.
+pub fn parse_v2(
+    version_command: u8,
+    family: u8,
+    addresses: &[u8],
+) -> anyhow::Result<Option<SocketAddr>> {
+    if version_command >> 4 != 2 {
+        bail!(
+            "unsupported PROXY protocol version {}",
+            version_command >> 4
+        );
+    }
+    match version_command & 0x0f {
+        // LOCAL
+        0x0 => return Ok(None),
+        // PROXY
+        0x1 => {}
+        command => bail!("unknown PROXY protocol command {command}"),
+    }
+    let short = || anyhow!("PROXY protocol address block too short");
+    let addr = match family >> 4 {
+        // AF_INET
+        0x1 => {
+            let block = addresses.get(..12).ok_or_else(short)?;
+            let ip = Ipv4Addr::new(block[0], block[1], block[2], block[3]);
+            SocketAddr::new(ip.into(), u16::from_be_bytes([block[8], block[9]]))
+        }
+        // AF_INET6
+        0x2 => {
+            let block = addresses.get(..36).ok_or_else(short)?;
+            let ip = <[u8; 16]>::try_from(&block[..16]).map(Ipv6Addr::from)?;
+            SocketAddr::new(ip.into(), u16::from_be_bytes([block[32], block[33]]))
+        }
+        // AF_UNSPEC and AF_UNIX carry no usable address.
+        _ => return Ok(None),
+    };
+    Ok(Some(addr))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_v1_header() {
+        assert_eq!(
+            parse_v1("PROXY TCP4 192.0.2.1 192.0.2.2 56324 443").unwrap(),
+            Some("192.0.2.1:56324".parse().unwrap())
+        );
+        assert_eq!(
+            parse_v1("PROXY TCP6 2001:db8::1 2001:db8::2 56324 443").unwrap(),
+            Some("[2001:db8::1]:56324".parse().unwrap())
+        );
+        assert_eq!(parse_v1("PROXY UNKNOWN").unwrap(), None);
+        assert!(parse_v1("PROXY TCP4 2001:db8::1 192.0.2.2 56324 443").is_err());
+        assert!(parse_v1("PROXY TCP4 192.0.2.1 192.0.2.2 56324").is_err());
+    }
+
+    #[tokio::test]
+    async fn read_header_keeps_payload() {
+        let mut v1 = &b"PROXY TCP4 192.0.2.1 192.0.2.2 56324 443\r\nGET / HTTP/1.1\r\n"[..];
+        assert_eq!(
+            read_header(&mut v1).await.unwrap(),
+            Some("192.0.2.1:56324".parse().unwrap())
+        );
+        assert_eq!(v1, b"GET / HTTP/1.1\r\n");
+
+        let mut v2 = V2_SIGNATURE.to_vec();
+        v2.extend([0x21, 0x11, 0, 12]);
+        v2.extend([192, 0, 2, 1, 192, 0, 2, 2, 0xdc, 0x04, 0x01, 0xbb]);
+        v2.extend(b"GET");
+        let mut stream = &v2[..];
+        assert_eq!(
+            read_header(&mut stream).await.unwrap(),
+            Some("192.0.2.1:56324".parse().unwrap())
+        );
+        assert_eq!(stream, b"GET");
+
+        let mut local = V2_SIGNATURE.to_vec();
+        local.extend([0x20, 0x00, 0, 0]);
+        assert_eq!(read_header(&mut &local[..]).await.unwrap(), None);
+
+        assert!(read_header(&mut &b"GET / HTTP/1.1\r\n\r\n"[..])
+            .await
+            .is_err());
+    }
+}
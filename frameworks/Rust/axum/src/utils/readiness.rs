@@ -0,0 +1,65 @@
+use std::{
+    borrow::Cow,
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, LazyLock, RwLock,
+    },
+    time::Duration,
+};
+
+use tokio::time::timeout;
+
+/// Max time a single readiness check may take.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub type CheckResult = Result<(), Cow<'static, str>>;
+type CheckFuture = Pin<Box<dyn Future<Output = CheckResult> + Send>>;
+type Check = Arc<dyn Fn() -> CheckFuture + Send + Sync>;
+type NamedCheck = (Cow<'static, str>, Check);
+
+/// Dependencies checked by `/readyz`.
+static CHECKS: LazyLock<RwLock<Vec<NamedCheck>>> = LazyLock::new(|| RwLock::new(vec![]));
+
+/// Set once the server starts shutting down.
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+/// Register a dependency checked by `/readyz`, such as a database or a
+/// user store.
+pub fn register_check<F, Fut>(name: impl Into<Cow<'static, str>>, check: F)
+where
+    F: Fn() -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = CheckResult> + Send + 'static,
+{
+    let check: Check = Arc::new(move || Box::pin(check()));
+    CHECKS
+        .write()
+        .expect("readiness checks lock poisoned")
+        .push((name.into(), check));
+}
+
+/// Mark the server as shutting down, `/readyz` fails from now on.
+pub fn set_shutting_down() {
+    SHUTTING_DOWN.store(true, Ordering::SeqCst);
+}
+
+pub fn is_shutting_down() -> bool {
+    SHUTTING_DOWN.load(Ordering::SeqCst)
+}
+
+/// Run every registered check, a check that doesn't finish in time fails.
+pub async fn run_checks() -> Vec<(Cow<'static, str>, CheckResult)> {
+    let checks = CHECKS
+        .read()
+        .expect("readiness checks lock poisoned")
+        .clone();
+    let mut results = Vec::with_capacity(checks.len());
+    for (name, check) in checks {
+        let result = timeout(CHECK_TIMEOUT, check())
+            .await
+            .unwrap_or_else(|_| Err("timed out".into()));
+        results.push((name, result));
+    }
+    results
+}
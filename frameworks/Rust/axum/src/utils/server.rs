@@ -0,0 +1,549 @@
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    future::{pending, Future},
+    pin::{pin, Pin},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::ConnectInfo,
+    http::{header::CONNECTION, HeaderValue, Method, Version},
+    Router,
+};
+use hyper::{body::Incoming, server::conn::http1, service::service_fn, Request};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo, TokioTimer},
+    server::conn::auto,
+};
+use tokio::{
+    sync::{watch, Notify},
+    task::JoinSet,
+    time::{sleep, timeout},
+};
+use tower::ServiceExt;
+use tracing::{debug, info, warn};
+
+use super::{
+    h2c::{self, Rewind},
+    listener::{AppListener, AppStream, ConnectionInfo, PeerAddr},
+};
+use crate::config::{HttpConfig, ShutdownConfig};
+
+/// Most aborted requests listed on shutdown.
+const MAX_REPORTED: usize = 10;
+
+/// HTTP server of the connections of [`AppListener`], with the connection
+/// settings of `http` and the drain settings of `shutdown`.
+///
+/// HTTP/1 and HTTP/2 are told apart by the connection preface, so HTTPS
+/// serves whatever ALPN negotiated and plaintext connections serve h2c to
+/// clients with prior knowledge or switch with `Upgrade: h2c`, see
+/// [`h2c::upgrade`]. Other upgrades, e.g. WebSocket, are handed to the
+/// routes.
+pub struct Server {
+    /// Serves HTTP/1 and HTTP/2.
+    auto: auto::Builder<TokioExecutor>,
+    /// Serves plaintext connections when h2c is off.
+    http1: http1::Builder,
+    h2c: bool,
+    max_requests: Option<usize>,
+    max_connection_age: Option<Duration>,
+    shutdown_delay: Duration,
+    shutdown_timeout: Duration,
+    in_flight: Arc<InFlight>,
+}
+
+impl Server {
+    pub fn from_config(config: &HttpConfig, shutdown: &ShutdownConfig) -> Self {
+        let header_read_timeout = config.header_read_timeout.map(|timeout| timeout.0);
+        let mut auto = auto::Builder::new(TokioExecutor::new());
+        auto.http1()
+            .timer(TokioTimer::new())
+            .keep_alive(config.keep_alive)
+            .header_read_timeout(header_read_timeout)
+            .max_headers(config.max_headers)
+            .pipeline_flush(config.pipeline_flush);
+        auto.http2()
+            .timer(TokioTimer::new())
+            .max_concurrent_streams(config.max_concurrent_streams)
+            .keep_alive_interval(config.keep_alive_interval.map(|interval| interval.0))
+            .keep_alive_timeout(config.keep_alive_timeout.0)
+            // WebSocket over HTTP/2, RFC 8441.
+            .enable_connect_protocol();
+        // `auto::Builder::http1_only` is ignored when serving upgrades.
+        let mut http1 = http1::Builder::new();
+        http1
+            .timer(TokioTimer::new())
+            .keep_alive(config.keep_alive)
+            .header_read_timeout(header_read_timeout)
+            .max_headers(config.max_headers)
+            .pipeline_flush(config.pipeline_flush);
+        Self {
+            auto,
+            http1,
+            h2c: config.h2c,
+            max_requests: config.max_requests,
+            max_connection_age: config.max_connection_age.map(|age| age.0),
+            shutdown_delay: shutdown.delay.0,
+            shutdown_timeout: shutdown.timeout.0,
+            in_flight: Arc::default(),
+        }
+    }
+
+    /// Serve `router` until `signal` completes, then drain the open
+    /// connections.
+    ///
+    /// Draining keeps accepting for `shutdown.delay`, so load balancers
+    /// notice the failing `/readyz` first, then stops accepting and closes
+    /// every connection gracefully. Connections still open after
+    /// `shutdown.timeout` are closed forcibly, the requests they abort are
+    /// logged.
+    ///
+    /// Requests carry the `ConnectInfo<ConnectionInfo>` of their
+    /// connection.
+    pub async fn serve(
+        self,
+        mut listener: AppListener,
+        router: Router,
+        signal: impl Future<Output = ()>,
+    ) {
+        let server = Arc::new(self);
+        let (shutdown_tx, shutdown_rx) = watch::channel(());
+        let mut connections = JoinSet::new();
+        let mut draining = pin!(async {
+            signal.await;
+            if !server.shutdown_delay.is_zero() {
+                info!("draining in {:?}", server.shutdown_delay);
+                sleep(server.shutdown_delay).await;
+            }
+        });
+        loop {
+            tokio::select! {
+                (stream, peer) = listener.accept() => {
+                    let info = ConnectionInfo::new(&stream, peer);
+                    connections.spawn(server.clone().serve_connection(
+                        stream,
+                        info,
+                        router.clone(),
+                        shutdown_rx.clone(),
+                    ));
+                }
+                // Reap closed connections.
+                Some(_) = connections.join_next() => {}
+                _ = &mut draining => break,
+            }
+        }
+        drop(listener);
+        shutdown_tx.send_replace(());
+        info!("draining {} connections", connections.len());
+        let drained = timeout(server.shutdown_timeout, async {
+            while connections.join_next().await.is_some() {}
+        })
+        .await;
+        if drained.is_err() {
+            warn!(
+                "{} connections still open after {:?}, closing them",
+                connections.len(),
+                server.shutdown_timeout
+            );
+            server.in_flight.report_aborted();
+            connections.shutdown().await;
+        }
+    }
+
+    /// Serve a connection until the client closes it, or close it
+    /// gracefully on shutdown or once it reaches its limits.
+    ///
+    /// Closing gracefully lets HTTP/1 finish the request in flight with
+    /// `Connection: close`, and sends a GOAWAY to HTTP/2 clients so they
+    /// open new streams elsewhere.
+    async fn serve_connection(
+        self: Arc<Self>,
+        stream: AppStream,
+        info: ConnectionInfo,
+        router: Router,
+        mut shutdown: watch::Receiver<()>,
+    ) {
+        let peer = info.peer;
+        let in_flight = self.in_flight.clone();
+        let tls = matches!(stream, AppStream::Tls(_));
+        let http1_only = !self.h2c && !tls;
+        // The h2c upgrade of the connection, once a request asked for it.
+        let upgrade = Arc::new(Mutex::new(None));
+        let limit_reached = Arc::new(Notify::new());
+        let service = {
+            let (limit_reached, max_requests) = (limit_reached.clone(), self.max_requests);
+            let requests = Arc::new(AtomicUsize::new(0));
+            let (upgrade, h2c) = (upgrade.clone(), self.h2c && !tls);
+            service_fn(move |mut req: Request<Incoming>| {
+                let switching = h2c && {
+                    let mut upgrade = upgrade.lock().expect("h2c upgrade lock poisoned");
+                    *upgrade = h2c::upgrade(&mut req);
+                    upgrade.is_some()
+                };
+                req.extensions_mut().insert(ConnectInfo(info.clone()));
+                // A switching request is counted once it is served as
+                // stream 1.
+                let counted = (!switching).then(|| {
+                    let guard = in_flight.start(&req, peer);
+                    let last = max_requests == Some(requests.fetch_add(1, Ordering::Relaxed) + 1);
+                    if last {
+                        limit_reached.notify_one();
+                    }
+                    (guard, last)
+                });
+                let (router, version) = (router.clone(), req.version());
+                async move {
+                    let Some((guard, last)) = counted else {
+                        return Ok(h2c::switching_protocols());
+                    };
+                    let mut res = router.oneshot(req).await?;
+                    // Fast handlers finish before the connection is told to
+                    // close, so tell HTTP/1 clients here.
+                    if last && version < Version::HTTP_2 {
+                        res.headers_mut()
+                            .insert(CONNECTION, HeaderValue::from_static("close"));
+                    }
+                    drop(guard);
+                    Ok::<_, Infallible>(res)
+                }
+            })
+        };
+        let closing = async {
+            let max_age = async {
+                match self.max_connection_age {
+                    Some(age) => sleep(age).await,
+                    None => pending().await,
+                }
+            };
+            tokio::select! {
+                _ = shutdown.changed() => {}
+                _ = limit_reached.notified() => {}
+                _ = max_age => {}
+            }
+        };
+
+        let mut closing = pin!(closing);
+        // `closing` can't be polled again once it completed.
+        let closed = AtomicBool::new(false);
+        let closing_once = async {
+            closing.as_mut().await;
+            closed.store(true, Ordering::Relaxed);
+        };
+
+        let io = TokioIo::new(stream);
+        let result = if http1_only {
+            let conn = self
+                .http1
+                .serve_connection(io, service.clone())
+                .with_upgrades();
+            drive(conn, closing_once, |conn| conn.graceful_shutdown())
+                .await
+                .map_err(Into::into)
+        } else {
+            let conn = self
+                .auto
+                .serve_connection_with_upgrades(io, service.clone());
+            drive(conn, closing_once, |conn| conn.graceful_shutdown()).await
+        };
+        if let Err(err) = result {
+            debug!("connection from {peer} failed: {err}");
+            return;
+        }
+
+        let upgrade = upgrade.lock().expect("h2c upgrade lock poisoned").take();
+        let Some((upgrade, preface)) = upgrade.filter(|_| !closed.load(Ordering::Relaxed)) else {
+            return;
+        };
+        let upgraded = match upgrade.await {
+            Ok(upgraded) => upgraded,
+            Err(err) => {
+                debug!("h2c upgrade from {peer} failed: {err}");
+                return;
+            }
+        };
+        let io = TokioIo::new(Rewind::new(TokioIo::new(upgraded), preface));
+        let conn = self.auto.serve_connection(io, service);
+        if let Err(err) = drive(conn, closing, |conn| conn.graceful_shutdown()).await {
+            debug!("h2c connection from {peer} failed: {err}");
+        }
+    }
+}
+
+/// Drive `conn` to completion, shutting it down gracefully once `closing`
+/// completes.
+async fn drive<C: Future>(
+    conn: C,
+    closing: impl Future<Output = ()>,
+    graceful_shutdown: impl FnOnce(Pin<&mut C>),
+) -> C::Output {
+    let mut conn = pin!(conn);
+    tokio::select! {
+        result = conn.as_mut() => result,
+        _ = closing => {
+            graceful_shutdown(conn.as_mut());
+            conn.await
+        }
+    }
+}
+
+/// Requests being served, listed when they are aborted at the shutdown
+/// deadline.
+#[derive(Default)]
+struct InFlight {
+    next_id: AtomicU64,
+    requests: Mutex<HashMap<u64, InFlightRequest>>,
+}
+
+struct InFlightRequest {
+    method: Method,
+    /// Without the query, it may carry secrets.
+    path: String,
+    peer: PeerAddr,
+    started: Instant,
+}
+
+impl InFlight {
+    /// Track `req` until the guard is dropped.
+    fn start(self: &Arc<Self>, req: &Request<Incoming>, peer: PeerAddr) -> InFlightGuard {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let request = InFlightRequest {
+            method: req.method().clone(),
+            path: req.uri().path().to_string(),
+            peer,
+            started: Instant::now(),
+        };
+        self.lock().insert(id, request);
+        InFlightGuard(self.clone(), id)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<u64, InFlightRequest>> {
+        self.requests
+            .lock()
+            .expect("in flight requests lock poisoned")
+    }
+
+    /// Log the requests in flight, the oldest first.
+    fn report_aborted(&self) {
+        let requests = self.lock();
+        let mut aborted: Vec<_> = requests.values().collect();
+        aborted.sort_by_key(|request| request.started);
+        warn!("aborting {} requests in flight", aborted.len());
+        for request in aborted.iter().take(MAX_REPORTED) {
+            warn!(
+                "aborted {} {} from {} after {:?}",
+                request.method,
+                request.path,
+                request.peer,
+                request.started.elapsed()
+            );
+        }
+        if aborted.len() > MAX_REPORTED {
+            warn!("and {} more", aborted.len() - MAX_REPORTED);
+        }
+    }
+}
+
+struct InFlightGuard(Arc<InFlight>, u64);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.lock().remove(&self.1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, routing::get};
+    use http_body_util::BodyExt;
+    use hyper::client::conn;
+    use tokio::{net::TcpStream, sync::oneshot, task::JoinHandle};
+
+    use super::*;
+    use crate::{
+        config::ServerConfig,
+        utils::listener::{BindAddr, PeerAddr},
+    };
+
+    struct Running {
+        addr: std::net::SocketAddr,
+        stop: oneshot::Sender<()>,
+        serving: JoinHandle<()>,
+    }
+
+    /// Serve a route answering the peer address and a route that never
+    /// answers in time.
+    fn start(http: HttpConfig, shutdown: ShutdownConfig) -> Running {
+        let listener = AppListener::bind(
+            &ServerConfig {
+                bind: vec!["127.0.0.1:0".parse().unwrap()],
+                ..Default::default()
+            },
+            None,
+        )
+        .unwrap();
+        let BindAddr::Tcp(addr) = listener.local_addrs()[0] else {
+            panic!("expect tcp address");
+        };
+        let router = Router::new()
+            .route(
+                "/",
+                get(
+                    |ConnectInfo(info): ConnectInfo<ConnectionInfo>| async move {
+                        info.peer.to_string()
+                    },
+                ),
+            )
+            .route("/slow", get(|| sleep(Duration::from_secs(60))));
+        let (stop, rx) = oneshot::channel();
+        let serving =
+            tokio::spawn(
+                Server::from_config(&http, &shutdown).serve(listener, router, async {
+                    rx.await.ok();
+                }),
+            );
+        Running {
+            addr,
+            stop,
+            serving,
+        }
+    }
+
+    fn get_root() -> Request<Body> {
+        Request::get("/").body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn h2c_prior_knowledge() {
+        let server = start(HttpConfig::default(), ShutdownConfig::default());
+        let stream = TcpStream::connect(server.addr).await.unwrap();
+        let local = stream.local_addr().unwrap();
+        let (mut sender, conn) = conn::http2::handshake(TokioExecutor::new(), TokioIo::new(stream))
+            .await
+            .unwrap();
+        tokio::spawn(conn);
+        let res = sender.send_request(get_root()).await.unwrap();
+        assert_eq!(res.version(), Version::HTTP_2);
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, PeerAddr::Tcp(local).to_string());
+
+        let server = start(
+            HttpConfig {
+                h2c: false,
+                ..Default::default()
+            },
+            ShutdownConfig::default(),
+        );
+        let stream = TcpStream::connect(server.addr).await.unwrap();
+        let (mut sender, conn) = conn::http2::handshake(TokioExecutor::new(), TokioIo::new(stream))
+            .await
+            .unwrap();
+        tokio::spawn(conn);
+        assert!(sender.send_request(get_root()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn h2c_upgrade() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let server = start(HttpConfig::default(), ShutdownConfig::default());
+        let mut stream = TcpStream::connect(server.addr).await.unwrap();
+        let local = stream.local_addr().unwrap();
+        stream
+            .write_all(
+                b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade, HTTP2-Settings\r\n\
+                  Upgrade: h2c\r\nHTTP2-Settings: AAMAAABkAAQAoAAAAAIAAAAA\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let mut head = vec![];
+        while !head.ends_with(b"\r\n\r\n") {
+            head.push(stream.read_u8().await.unwrap());
+        }
+        assert!(head.starts_with(b"HTTP/1.1 101 Switching Protocols\r\n"));
+        // Connection preface and an empty SETTINGS frame.
+        stream
+            .write_all(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n\0\0\0\x04\0\0\0\0\0")
+            .await
+            .unwrap();
+
+        // The upgraded request is answered on stream 1.
+        let mut body = vec![];
+        loop {
+            let mut header = [0; 9];
+            stream.read_exact(&mut header).await.unwrap();
+            let len = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+            let mut payload = vec![0; len];
+            stream.read_exact(&mut payload).await.unwrap();
+            let (kind, flags, id) = (header[3], header[4], &header[5..]);
+            if kind == 0x0 && id == [0, 0, 0, 1] {
+                body.extend_from_slice(&payload);
+                if flags & 0x1 != 0 {
+                    break;
+                }
+            }
+        }
+        assert_eq!(body, PeerAddr::Tcp(local).to_string().as_bytes());
+    }
+
+    #[tokio::test]
+    async fn close_after_max_requests() {
+        let server = start(
+            HttpConfig {
+                max_requests: Some(2),
+                ..Default::default()
+            },
+            ShutdownConfig::default(),
+        );
+        let stream = TcpStream::connect(server.addr).await.unwrap();
+        let (mut sender, conn) = conn::http1::handshake(TokioIo::new(stream)).await.unwrap();
+        let conn = tokio::spawn(conn);
+
+        let res = sender.send_request(get_root()).await.unwrap();
+        assert!(res.headers().get(CONNECTION).is_none());
+        res.into_body().collect().await.unwrap();
+        let res = sender.send_request(get_root()).await.unwrap();
+        assert_eq!(res.headers()[CONNECTION], "close");
+        res.into_body().collect().await.unwrap();
+        conn.await.unwrap().unwrap();
+
+        // Idle connections are closed on shutdown.
+        let stream = TcpStream::connect(server.addr).await.unwrap();
+        let (mut sender, conn) = conn::http1::handshake(TokioIo::new(stream)).await.unwrap();
+        let conn = tokio::spawn(conn);
+        let res = sender.send_request(get_root()).await.unwrap();
+        res.into_body().collect().await.unwrap();
+        server.stop.send(()).unwrap();
+        conn.await.unwrap().unwrap();
+        server.serving.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn abort_at_shutdown_deadline() {
+        let server = start(
+            HttpConfig::default(),
+            ShutdownConfig {
+                timeout: Duration::from_millis(100).into(),
+                ..Default::default()
+            },
+        );
+        let stream = TcpStream::connect(server.addr).await.unwrap();
+        let (mut sender, conn) = conn::http1::handshake(TokioIo::new(stream)).await.unwrap();
+        tokio::spawn(conn);
+        let slow =
+            tokio::spawn(sender.send_request(Request::get("/slow").body(Body::empty()).unwrap()));
+        sleep(Duration::from_millis(50)).await;
+
+        server.stop.send(()).unwrap();
+        timeout(Duration::from_secs(5), server.serving)
+            .await
+            .expect("stop at the deadline")
+            .unwrap();
+        assert!(slow.await.unwrap().is_err(), "request is aborted");
+    }
+}
@@ -0,0 +1,78 @@
+use std::{
+    borrow::Cow,
+    future::Future,
+    pin::Pin,
+    sync::{LazyLock, Mutex},
+    time::Duration,
+};
+
+use tokio::time::timeout;
+use tracing::{info, warn};
+
+type HookFuture = Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send>>;
+type Hook = Box<dyn FnOnce() -> HookFuture + Send>;
+type NamedHook = (Cow<'static, str>, Hook);
+
+/// Hooks run once the server stopped serving.
+static HOOKS: LazyLock<Mutex<Vec<NamedHook>>> = LazyLock::new(|| Mutex::new(vec![]));
+
+/// Register a hook run on shutdown, after the last connection is closed,
+/// e.g. to flush buffered logs or close a database pool.
+#[cfg_attr(not(feature = "otel"), allow(dead_code))]
+pub fn register_hook<F, Fut>(name: impl Into<Cow<'static, str>>, hook: F)
+where
+    F: FnOnce() -> Fut + Send + 'static,
+    Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+{
+    let hook: Hook = Box::new(move || Box::pin(hook()));
+    HOOKS
+        .lock()
+        .expect("shutdown hooks lock poisoned")
+        .push((name.into(), hook));
+}
+
+/// Run the registered hooks in the reverse order of registration, so
+/// subsystems are stopped before the ones they depend on. A hook that
+/// doesn't finish within `hook_timeout` is abandoned.
+pub async fn run_hooks(hook_timeout: Duration) {
+    let hooks = std::mem::take(&mut *HOOKS.lock().expect("shutdown hooks lock poisoned"));
+    for (name, hook) in hooks.into_iter().rev() {
+        match timeout(hook_timeout, hook()).await {
+            Ok(Ok(())) => info!("shutdown hook {name} finished"),
+            Ok(Err(err)) => warn!("shutdown hook {name} failed: {err:#}"),
+            Err(_) => warn!("shutdown hook {name} timed out"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use anyhow::bail;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn run_hooks_in_reverse() {
+        let ran = Arc::new(Mutex::new(vec![]));
+        for name in ["db", "slow", "failing", "cache"] {
+            let ran = ran.clone();
+            register_hook(name, move || async move {
+                match name {
+                    "slow" => tokio::time::sleep(Duration::from_secs(10)).await,
+                    "failing" => bail!("connection reset"),
+                    _ => {}
+                }
+                ran.lock().unwrap().push(name);
+                Ok(())
+            });
+        }
+        run_hooks(Duration::from_millis(50)).await;
+        assert_eq!(*ran.lock().unwrap(), ["cache", "db"]);
+
+        // Hooks only run once.
+        run_hooks(Duration::from_millis(50)).await;
+        assert_eq!(ran.lock().unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,118 @@
+use std::{fmt, str::FromStr};
+
+use anyhow::{anyhow, bail};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+/// Parse a human readable byte size such as `512`, `64k`, `2mb` or `1GiB`.
+///
+/// Units are binary and case insensitive, a bare number is bytes.
+pub fn parse_size(input: &str) -> anyhow::Result<usize> {
+    let input = input.trim();
+    if input.is_empty() {
+        bail!("empty size");
+    }
+    let split = input
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(input.len());
+    let (value, unit) = input.split_at(split);
+    let value = value
+        .parse::<usize>()
+        .map_err(|_| anyhow!("invalid size `{input}`"))?;
+    let scale = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" | "kib" => 1 << 10,
+        "m" | "mb" | "mib" => 1 << 20,
+        "g" | "gb" | "gib" => 1 << 30,
+        _ => bail!("invalid size unit in `{input}`, expect one of b, k, m, g"),
+    };
+    value
+        .checked_mul(scale)
+        .ok_or_else(|| anyhow!("size `{input}` is too large"))
+}
+
+/// A byte size written as `512`, `64k` or `2mb` in config files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteSize(pub usize);
+
+impl FromStr for ByteSize {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_size(s).map(Self)
+    }
+}
+
+/// Formatted in the largest unit that divides the size.
+impl fmt::Display for ByteSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let units = [(1 << 30, "gb"), (1 << 20, "mb"), (1 << 10, "kb")];
+        match units
+            .iter()
+            .find(|(scale, _)| self.0 > 0 && self.0.is_multiple_of(*scale))
+        {
+            Some((scale, unit)) => write!(f, "{}{unit}", self.0 / scale),
+            None => write!(f, "{}", self.0),
+        }
+    }
+}
+
+impl Serialize for ByteSize {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for ByteSize {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl de::Visitor<'_> for Visitor {
+            type Value = ByteSize;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("a size such as `64k`, `2mb` or a number of bytes")
+            }
+
+            fn visit_u64<E: de::Error>(self, value: u64) -> Result<Self::Value, E> {
+                usize::try_from(value)
+                    .map(ByteSize)
+                    .map_err(|_| E::invalid_value(de::Unexpected::Unsigned(value), &self))
+            }
+
+            fn visit_i64<E: de::Error>(self, value: i64) -> Result<Self::Value, E> {
+                let value = u64::try_from(value)
+                    .map_err(|_| E::invalid_value(de::Unexpected::Signed(value), &self))?;
+                self.visit_u64(value)
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<Self::Value, E> {
+                value.parse().map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_any(Visitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_size_works() {
+        assert_eq!(parse_size("512").unwrap(), 512);
+        assert_eq!(parse_size("64k").unwrap(), 64 * 1024);
+        assert_eq!(parse_size("2MB").unwrap(), 2 * 1024 * 1024);
+        assert_eq!(parse_size("1GiB").unwrap(), 1024 * 1024 * 1024);
+        assert!(parse_size("1t").is_err());
+        assert!(parse_size("k").is_err());
+        assert!(parse_size("").is_err());
+    }
+
+    #[test]
+    fn byte_size_round_trip() {
+        for text in ["512", "64kb", "2mb", "1gb", "1025"] {
+            assert_eq!(text.parse::<ByteSize>().unwrap().to_string(), text);
+        }
+    }
+}
@@ -0,0 +1,339 @@
+use std::{
+    fs,
+    sync::{Arc, RwLock},
+    time::SystemTime,
+};
+
+use anyhow::{bail, Context};
+use axum::{
+    extract::{ConnectInfo, FromRequestParts},
+    http::request::Parts,
+};
+use rustls::{
+    crypto::ring::default_provider,
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    server::WebPkiClientVerifier,
+    RootCertStore, ServerConfig,
+};
+use tokio::time::interval;
+use tokio_rustls::TlsAcceptor;
+use tracing::{error, info};
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+use super::listener::ConnectionInfo;
+use crate::{config::TlsConfig, error::AppError};
+
+/// TLS settings of the server, swapped when the files change on disk.
+///
+/// Only new handshakes use the swapped settings, established connections
+/// are kept.
+pub struct Tls {
+    config: TlsConfig,
+    server: RwLock<Arc<ServerConfig>>,
+}
+
+impl Tls {
+    /// Load the files of `tls`, returns `None` when `tls.cert` is not set.
+    pub fn load(config: &TlsConfig) -> anyhow::Result<Option<Arc<Self>>> {
+        if config.cert.is_none() {
+            return Ok(None);
+        }
+        let server = server_config(config)?;
+        Ok(Some(Arc::new(Self {
+            config: config.clone(),
+            server: RwLock::new(Arc::new(server)),
+        })))
+    }
+
+    /// Acceptor of new connections with the current certificates.
+    pub fn acceptor(&self) -> TlsAcceptor {
+        TlsAcceptor::from(self.server.read().expect("lock poisoned").clone())
+    }
+
+    /// Load the files again, the current settings are kept on error.
+    pub fn reload(&self) -> anyhow::Result<()> {
+        let server = server_config(&self.config)?;
+        *self.server.write().expect("lock poisoned") = Arc::new(server);
+        Ok(())
+    }
+
+    /// Reload once the modification time of a file changes, checked every
+    /// `tls.reload_interval`.
+    pub async fn watch(self: Arc<Self>) {
+        let Some(period) = self.config.reload_interval else {
+            return;
+        };
+        let mut modified = self.modified();
+        let mut interval = interval(period.0);
+        // The first tick completes immediately.
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let current = self.modified();
+            if current == modified {
+                continue;
+            }
+            modified = current;
+            // A half written pair fails and is retried on the next change.
+            match self.reload() {
+                Ok(()) => info!("reloaded TLS certificates"),
+                Err(err) => error!("failed to reload TLS certificates: {err:#}"),
+            }
+        }
+    }
+
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        self.config
+            .files()
+            .map(|file| fs::metadata(file).and_then(|meta| meta.modified()).ok())
+            .collect()
+    }
+}
+
+/// Build the rustls settings, offering HTTP/2 and HTTP/1.1 over ALPN.
+fn server_config(config: &TlsConfig) -> anyhow::Result<ServerConfig> {
+    let (Some(cert), Some(key)) = (&config.cert, &config.key) else {
+        bail!("tls.cert and tls.key must be set together");
+    };
+    let certs = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("invalid tls.cert {}", cert.display()))?;
+    if certs.is_empty() {
+        bail!("invalid tls.cert {}, no certificate found", cert.display());
+    }
+    let key = PrivateKeyDer::from_pem_file(key)
+        .with_context(|| format!("invalid tls.key {}", key.display()))?;
+
+    let provider = Arc::new(default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+    let builder = match &config.client_ca {
+        None => builder.with_no_client_auth(),
+        Some(ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in CertificateDer::pem_file_iter(ca)
+                .with_context(|| format!("invalid tls.client_ca {}", ca.display()))?
+            {
+                roots
+                    .add(cert.with_context(|| format!("invalid tls.client_ca {}", ca.display()))?)
+                    .context("invalid tls.client_ca")?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+            let verifier = if config.client_auth_optional {
+                verifier.allow_unauthenticated()
+            } else {
+                verifier
+            };
+            builder.with_client_cert_verifier(verifier.build().context("invalid tls.client_ca")?)
+        }
+    };
+    let mut server = builder
+        .with_single_cert(certs, key)
+        .context("tls.key does not match tls.cert")?;
+    server.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(server)
+}
+
+/// Certificate chain presented by the client under mutual TLS, leaf first.
+///
+/// Requests without a verified client certificate are rejected with 401.
+#[derive(Debug, Clone)]
+pub struct ClientCert(Arc<Vec<CertificateDer<'static>>>);
+
+impl ClientCert {
+    pub fn new(chain: Vec<CertificateDer<'static>>) -> Option<Self> {
+        (!chain.is_empty()).then(|| Self(Arc::new(chain)))
+    }
+
+    /// Common name of the leaf certificate subject.
+    pub fn common_name(&self) -> Option<String> {
+        let (_, cert) = X509Certificate::from_der(&self.0[0]).ok()?;
+        let name = cert.subject().iter_common_name().next()?;
+        name.as_str().ok().map(ToString::to_string)
+    }
+}
+
+impl<S> FromRequestParts<S> for ClientCert
+where
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts
+            .extensions
+            .get::<ConnectInfo<ConnectionInfo>>()
+            .and_then(|ConnectInfo(info)| info.client_cert.clone())
+            .ok_or_else(|| AppError::Unauthorized("Client certificate is required".into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use rcgen::{BasicConstraints, CertificateParams, CertifiedIssuer, DnType, IsCa, KeyPair};
+    use rustls::{pki_types::ServerName, ClientConfig};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+    use tokio_rustls::TlsConnector;
+
+    use super::*;
+
+    struct Pki {
+        dir: PathBuf,
+        ca: CertifiedIssuer<'static, KeyPair>,
+    }
+
+    impl Pki {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("phthonus-{name}-{}", std::process::id()));
+            fs::create_dir_all(&dir).unwrap();
+            let mut params = CertificateParams::new(vec![]).unwrap();
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            params.distinguished_name.push(DnType::CommonName, "ca");
+            let ca = CertifiedIssuer::self_signed(params, KeyPair::generate().unwrap()).unwrap();
+            fs::write(dir.join("ca.pem"), ca.pem()).unwrap();
+            Self { dir, ca }
+        }
+
+        /// Issue a certificate, written to `<name>.pem` and `<name>.key`.
+        fn issue(&self, name: &str, san: &str) -> (PathBuf, PathBuf) {
+            let mut params = CertificateParams::new(vec![san.to_string()]).unwrap();
+            params.distinguished_name.push(DnType::CommonName, name);
+            let key = KeyPair::generate().unwrap();
+            let cert = params.signed_by(&key, &self.ca).unwrap();
+            let (cert_file, key_file) = (
+                self.dir.join(format!("{name}.pem")),
+                self.dir.join(format!("{name}.key")),
+            );
+            fs::write(&cert_file, cert.pem()).unwrap();
+            fs::write(&key_file, key.serialize_pem()).unwrap();
+            (cert_file, key_file)
+        }
+
+        fn ca_file(&self) -> PathBuf {
+            self.dir.join("ca.pem")
+        }
+    }
+
+    /// Connect to `addr`, returns the certificate served and the negotiated
+    /// ALPN protocol.
+    async fn connect(
+        addr: std::net::SocketAddr,
+        ca: &Path,
+        client: Option<(PathBuf, PathBuf)>,
+    ) -> anyhow::Result<(Vec<u8>, Option<Vec<u8>>)> {
+        let mut roots = RootCertStore::empty();
+        for cert in CertificateDer::pem_file_iter(ca)? {
+            roots.add(cert?)?;
+        }
+        let builder = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots);
+        let mut config = match client {
+            Some((cert, key)) => builder.with_client_auth_cert(
+                CertificateDer::pem_file_iter(cert)?.collect::<Result<_, _>>()?,
+                PrivateKeyDer::from_pem_file(key)?,
+            )?,
+            None => builder.with_no_client_auth(),
+        };
+        config.alpn_protocols = vec![b"h2".to_vec()];
+        let stream = TcpStream::connect(addr).await?;
+        let mut stream = TlsConnector::from(Arc::new(config))
+            .connect(ServerName::try_from("localhost")?, stream)
+            .await?;
+        // Client certificates are verified after the client handshake
+        // completes, a round trip surfaces the rejection.
+        stream.write_all(b"ping").await?;
+        let mut buf = [0; 4];
+        stream.read_exact(&mut buf).await?;
+        let (_, session) = stream.get_ref();
+        Ok((
+            session.peer_certificates().unwrap()[0].to_vec(),
+            session.alpn_protocol().map(<[u8]>::to_vec),
+        ))
+    }
+
+    /// Serve TLS echo connections with the current settings of `tls`.
+    async fn serve(tls: Arc<Tls>) -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let acceptor = tls.acceptor();
+                tokio::spawn(async move {
+                    let Ok(mut stream) = acceptor.accept(stream).await else {
+                        return;
+                    };
+                    let mut buf = [0; 4];
+                    if stream.read_exact(&mut buf).await.is_ok() {
+                        stream.write_all(&buf).await.ok();
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn reload_certificates() {
+        let pki = Pki::new("reload");
+        let (cert, key) = pki.issue("server", "localhost");
+        let config = TlsConfig {
+            cert: Some(cert),
+            key: Some(key),
+            ..Default::default()
+        };
+        let tls = Tls::load(&config).unwrap().unwrap();
+        let addr = serve(tls.clone()).await;
+
+        let (first, alpn) = connect(addr, &pki.ca_file(), None).await.unwrap();
+        assert_eq!(alpn.as_deref(), Some(&b"h2"[..]));
+
+        pki.issue("server", "localhost");
+        tls.reload().unwrap();
+        let (second, _) = connect(addr, &pki.ca_file(), None).await.unwrap();
+        assert_ne!(first, second, "new handshakes use the new certificate");
+
+        fs::write(config.key.as_ref().unwrap(), "garbage").unwrap();
+        assert!(tls.reload().is_err());
+        let (third, _) = connect(addr, &pki.ca_file(), None).await.unwrap();
+        assert_eq!(second, third, "invalid files keep the current certificate");
+    }
+
+    #[tokio::test]
+    async fn mutual_tls() {
+        let pki = Pki::new("mtls");
+        let (cert, key) = pki.issue("server", "localhost");
+        let client = pki.issue("client", "client");
+        let config = TlsConfig {
+            cert: Some(cert),
+            key: Some(key),
+            client_ca: Some(pki.ca_file()),
+            ..Default::default()
+        };
+        let addr = serve(Tls::load(&config).unwrap().unwrap()).await;
+        assert!(connect(addr, &pki.ca_file(), Some(client.clone()))
+            .await
+            .is_ok());
+        assert!(connect(addr, &pki.ca_file(), None).await.is_err());
+
+        let optional = TlsConfig {
+            client_auth_optional: true,
+            ..config
+        };
+        let addr = serve(Tls::load(&optional).unwrap().unwrap()).await;
+        assert!(connect(addr, &pki.ca_file(), None).await.is_ok());
+
+        let chain = CertificateDer::pem_file_iter(&client.0)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let client_cert = ClientCert::new(chain).unwrap();
+        assert_eq!(client_cert.common_name().as_deref(), Some("client"));
+    }
+}
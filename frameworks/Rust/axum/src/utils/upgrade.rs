@@ -0,0 +1,170 @@
+use std::{
+    env,
+    fs::File,
+    io::{self, Write},
+    os::fd::{FromRawFd, OwnedFd, RawFd},
+    path::PathBuf,
+    process,
+    time::Duration,
+};
+
+use anyhow::{bail, Context};
+use command_fds::{CommandFdExt, FdMapping};
+use sd_notify::NotifyState;
+use tokio::{
+    io::AsyncReadExt,
+    net::unix::pipe,
+    process::Command,
+    signal::unix::{signal, SignalKind},
+    time::timeout,
+};
+use tracing::{error, info, warn};
+
+use super::listener::keep_socket_files;
+
+/// Descriptor the new process writes to once it is ready to serve.
+const READY_FD: &str = "PHTHONUS_READY_FD";
+
+/// How long the new process may take to get ready.
+const READY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// First descriptor of inherited sockets, same as systemd.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Start a new process of the current executable on `SIGUSR2`, handing it
+/// the listening `sockets`, returns once it is ready so this process can
+/// drain.
+///
+/// The new process serves the inherited sockets as if they were passed by
+/// systemd, so no connection is refused in between. It reads the config
+/// again, except for the addresses. A failed upgrade keeps this process
+/// serving.
+///
+/// Under systemd the new process becomes the main process, which requires
+/// `Type=notify` and `NotifyAccess=all`.
+pub async fn upgrade_on_sigusr2(sockets: Vec<OwnedFd>) {
+    let mut upgrade =
+        signal(SignalKind::user_defined2()).expect("failed to install signal handler");
+    loop {
+        upgrade.recv().await;
+        info!("upgrading, starting a new process");
+        let command = match current_exe() {
+            Ok(exe) => {
+                let mut command = Command::new(exe);
+                command.args(env::args_os().skip(1));
+                command
+            }
+            Err(err) => {
+                error!("upgrade failed: {err:#}");
+                continue;
+            }
+        };
+        match spawn_ready(command, &sockets).await {
+            Ok(pid) => {
+                info!("new process {pid} is ready, draining");
+                keep_socket_files();
+                return;
+            }
+            Err(err) => error!("upgrade failed: {err:#}"),
+        }
+    }
+}
+
+/// The executable to start, the new binary when a deploy replaced it.
+fn current_exe() -> anyhow::Result<PathBuf> {
+    let exe = env::current_exe().context("failed to find the executable")?;
+    // Linux marks the path of a replaced executable.
+    if let Some(path) = exe.to_str().and_then(|exe| exe.strip_suffix(" (deleted)")) {
+        return Ok(PathBuf::from(path));
+    }
+    Ok(exe)
+}
+
+/// Spawn `command` with `sockets` from descriptor 3 on, and wait until it
+/// reports ready, returns its pid.
+async fn spawn_ready(mut command: Command, sockets: &[OwnedFd]) -> anyhow::Result<u32> {
+    let (reader, writer) = io::pipe().context("failed to create pipe")?;
+    let mut mappings = sockets
+        .iter()
+        .zip(LISTEN_FDS_START..)
+        .map(|(socket, child_fd)| {
+            Ok(FdMapping {
+                parent_fd: socket.try_clone()?,
+                child_fd,
+            })
+        })
+        .collect::<io::Result<Vec<_>>>()
+        .context("failed to duplicate sockets")?;
+    let ready_fd = LISTEN_FDS_START + sockets.len() as RawFd;
+    mappings.push(FdMapping {
+        parent_fd: writer.into(),
+        child_fd: ready_fd,
+    });
+    command
+        .env("LISTEN_FDS", sockets.len().to_string())
+        .env_remove("LISTEN_PID")
+        .env_remove("LISTEN_FDNAMES")
+        .env(READY_FD, ready_fd.to_string())
+        .fd_mappings(mappings)?;
+    let mut child = command.spawn().context("failed to spawn")?;
+    // Close our end of the pipe, so we read EOF once the child exits.
+    drop(command);
+    let pid = child.id().unwrap_or_default();
+
+    let mut reader = pipe::Receiver::from_owned_fd(reader.into())?;
+    let mut ready = [0; 1];
+    match timeout(READY_TIMEOUT, reader.read(&mut ready)).await {
+        Ok(Ok(1)) => Ok(pid),
+        Ok(Ok(_)) => {
+            let status = child.wait().await?;
+            bail!("new process exited before it was ready, {status}")
+        }
+        Ok(Err(err)) => Err(err).context("failed to wait for the new process"),
+        Err(_) => {
+            child.kill().await.ok();
+            bail!("new process was not ready within {READY_TIMEOUT:?}")
+        }
+    }
+}
+
+/// Tell the process that started this one by an upgrade, and systemd, that
+/// the server is ready.
+pub fn notify_ready() {
+    if let Some(fd) = env::var(READY_FD).ok().and_then(|fd| fd.parse().ok()) {
+        // SAFETY: the descriptor was opened by the parent for this purpose
+        // and is not used elsewhere.
+        let mut ready = unsafe { File::from_raw_fd(fd) };
+        if let Err(err) = ready.write_all(b"1") {
+            warn!("failed to notify the old process: {err}");
+        }
+    }
+    let states = [NotifyState::MainPid(process::id()), NotifyState::Ready];
+    if let Err(err) = sd_notify::notify(false, &states) {
+        warn!("failed to notify systemd: {err}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shell(script: &str) -> Command {
+        let mut command = Command::new("sh");
+        command.args(["-c", script]);
+        command
+    }
+
+    #[tokio::test]
+    async fn wait_until_ready() {
+        let socket = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let sockets = [OwnedFd::from(socket)];
+
+        // The socket is passed as fd 3 and the ready pipe after it.
+        let script =
+            r#"[ "$LISTEN_FDS" = 1 ] && [ -S /dev/fd/3 ] && printf 1 >&"$PHTHONUS_READY_FD""#;
+        assert!(spawn_ready(shell(script), &sockets).await.unwrap() > 0);
+
+        let err = spawn_ready(shell("exit 3"), &sockets).await.unwrap_err();
+        assert!(err.to_string().contains("exited"), "{err:#}");
+    }
+}
@@ -13,6 +13,7 @@ use validator::Validate;
 
 use crate::error::AppError;
 
+#[expect(dead_code, reason = "no route takes a form body yet")]
 #[derive(Debug, Clone, Copy, Default)]
 pub struct ValidatedForm<T>(pub T);
 
@@ -49,6 +50,8 @@ where
     }
 }
 
-pub static EMAIL_REGEX: LazyLock<Regex> = LazyLock::new(|| {
-    Regex::new(r"^[a-zA-Z0-9_.+-]+@[a-zA-Z0-9-]+\.[a-zA-Z0-9-.]+$").expect("Regex is valid")
-});
+/// Pattern of email addresses, also documented in the OpenAPI schemas.
+pub const EMAIL_PATTERN: &str = r"^[a-zA-Z0-9_.+-]+@[a-zA-Z0-9-]+\.[a-zA-Z0-9-.]+$";
+
+pub static EMAIL_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(EMAIL_PATTERN).expect("Regex is valid"));